@@ -8,10 +8,12 @@ use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use sha1::{Digest, Sha1};
 
+use pack;
 use parse;
+use repo::Repository;
 use types::{GitError, GitResult};
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum ObjectType {
     Blob,
     Tree,
@@ -19,27 +21,113 @@ pub enum ObjectType {
     Tag,
 }
 
+impl ObjectType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ObjectType::Blob => "blob",
+            ObjectType::Commit => "commit",
+            ObjectType::Tree => "tree",
+            ObjectType::Tag => "tag",
+        }
+    }
+}
+
 pub struct Object {
     pub kind: ObjectType,
     pub data: Vec<u8>,
 }
 
 // The directory that contains an object
-fn dir_for_hash(obj_hash: &str) -> PathBuf {
-    let mut path = PathBuf::from(".git/objects");
+fn dir_for_hash(repo: &Repository, obj_hash: &str) -> PathBuf {
+    let mut path = repo.path("objects");
     path.push(&obj_hash[..2]);
     path
 }
 
 // The full filename for an object
-fn path_for_hash(obj_hash: &str) -> PathBuf {
-    let mut path = dir_for_hash(obj_hash);
+pub fn path_for_hash(repo: &Repository, obj_hash: &str) -> PathBuf {
+    let mut path = dir_for_hash(repo, obj_hash);
     path.push(&obj_hash[2..]);
     path
 }
 
-pub fn read_obj(hash: &str) -> GitResult<Object> {
-    let f = fs::File::open(path_for_hash(hash))?;
+// Expand an abbreviated object name of at least 4 hex characters
+// It's an error if no object, or more than one object, has that prefix
+pub fn resolve_prefix(repo: &Repository, prefix: &str) -> GitResult<String> {
+    let prefix = prefix.to_lowercase();
+    if prefix.len() < 4 || prefix.len() > 40
+            || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(GitError::from("Invalid object name"));
+    }
+    if prefix.len() == 40 {
+        return Ok(prefix);
+    }
+
+    let mut candidates: Vec<String> = Vec::new();
+
+    // Loose objects are all in the directory for their first byte
+    let dir = dir_for_hash(repo, &prefix);
+    match fs::read_dir(&dir) {
+        Ok(entries) => for entry in entries {
+            if let Ok(name) = entry?.file_name().into_string() {
+                let hash = format!("{}{}", &prefix[..2], name);
+                if hash.len() == 40 && hash.starts_with(&prefix) {
+                    candidates.push(hash);
+                }
+            }
+        },
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => (),
+            _ => return Err(GitError::from(err)),
+        },
+    }
+
+    for hash in pack::find_prefix(repo, &prefix)? {
+        let hex = parse::bytes_to_hex(&hash);
+        if !candidates.contains(&hex) {
+            candidates.push(hex);
+        }
+    }
+
+    match candidates.len() {
+        0 => Err(GitError::from("Object not found")),
+        1 => Ok(candidates.remove(0)),
+        _ => {
+            candidates.sort();
+            let mut msg = format!("short SHA1 {} is ambiguous\nThe candidates are:", prefix);
+            for hash in candidates.iter() {
+                let kind = match read_obj(repo, hash) {
+                    Ok(obj) => obj.kind.name(),
+                    Err(_) => "unknown",
+                };
+                msg.push_str(&format!("\n  {} {}", hash, kind));
+            }
+            Err(GitError::from(msg))
+        },
+    }
+}
+
+pub fn read_obj(repo: &Repository, hash: &str) -> GitResult<Object> {
+    let hash_bytes = parse::hex_to_hash(hash)?;
+    if let Some(obj) = read_loose(repo, hash)? {
+        return Ok(obj);
+    }
+    // Fall back to the packs if there's no loose object
+    match pack::read_packed(repo, &hash_bytes)? {
+        Some(obj) => Ok(obj),
+        None => Err(GitError::from("Object not found")),
+    }
+}
+
+// Read an object from the loose object directories only
+pub fn read_loose(repo: &Repository, hash: &str) -> GitResult<Option<Object>> {
+    let f = match fs::File::open(path_for_hash(repo, hash)) {
+        Ok(f) => f,
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => return Ok(None),
+            _ => return Err(GitError::from(err)),
+        },
+    };
     let mut decoder = ZlibDecoder::new(f);
     let type_str = parse::read_until(&mut decoder, b' ')?;
 
@@ -60,32 +148,32 @@ pub fn read_obj(hash: &str) -> GitResult<Object> {
     let mut data = vec![0; expected_size];
     decoder.read_exact(&mut data)?;
 
-    Ok(Object{
+    Ok(Some(Object{
         kind: kind,
         data: data,
-    })
+    }))
 }
 
 impl Object {
-    pub fn write(self) -> GitResult<Digest> {
-        let kind_name = match self.kind {
-            ObjectType::Blob => "blob",
-            ObjectType::Commit => "commit",
-            ObjectType::Tree => "tree",
-            ObjectType::Tag => "tag",
-        };
-
-        let header = format!("{0} {1}\0", kind_name, self.data.len()).into_bytes();
+    fn header(&self) -> Vec<u8> {
+        format!("{0} {1}\0", self.kind.name(), self.data.len()).into_bytes()
+    }
 
-        // Compute object SHA1
+    // The object's name, without writing it anywhere
+    pub fn hash(&self) -> Digest {
         let mut m = Sha1::new();
-        m.update(&header);
+        m.update(&self.header());
         m.update(&self.data);
-        let digest = m.digest();
+        m.digest()
+    }
+
+    pub fn write(self, repo: &Repository) -> GitResult<Digest> {
+        let header = self.header();
+        let digest = self.hash();
         let name = digest.to_string();
 
         // Create containing directory
-        match fs::create_dir(dir_for_hash(&name)) {
+        match fs::create_dir(dir_for_hash(repo, &name)) {
             Ok(_) => (),
             Err(err) => match err.kind() {
                 io::ErrorKind::AlreadyExists => (),
@@ -94,7 +182,7 @@ impl Object {
         }
 
         // Actually create the file
-        let file_path = path_for_hash(&name);
+        let file_path = path_for_hash(repo, &name);
         match fs::OpenOptions::new().write(true).create_new(true).open(file_path) {
             Ok(f) => {
                 // Write object
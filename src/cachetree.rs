@@ -0,0 +1,120 @@
+// The index's TREE extension, which remembers the tree written for each
+// directory so write_tree doesn't have to rewrite trees that haven't changed
+//
+// Each directory is stored as its name, a NUL, the number of index entries
+// under it (or -1 if it has been invalidated) and the number of
+// subdirectories in ASCII, a newline, then the tree hash if it's valid,
+// followed by its subdirectories the same way.
+
+use types::{GitError, GitResult};
+
+#[derive(Default)]
+pub struct CacheTree {
+    // How many index entries the tree covers and its hash, or None if
+    // something under this directory has changed since it was written
+    pub valid: Option<(usize, [u8; 20])>,
+    // Git keeps these ordered by name length, then by name
+    pub children: Vec<(Vec<u8>, CacheTree)>,
+}
+
+fn read_number(data: &[u8], pos: &mut usize, end: u8) -> GitResult<i64> {
+    let start = *pos;
+    while *pos < data.len() && data[*pos] != end {
+        *pos += 1;
+    }
+    if *pos >= data.len() {
+        return Err(GitError::from("Corrupted TREE extension"));
+    }
+    *pos += 1;
+    let text = String::from_utf8_lossy(&data[start..*pos - 1]).into_owned();
+    match text.parse::<i64>() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(GitError::from("Corrupted TREE extension")),
+    }
+}
+
+fn read_node(data: &[u8], pos: &mut usize) -> GitResult<(Vec<u8>, CacheTree)> {
+    let start = *pos;
+    while *pos < data.len() && data[*pos] != 0 {
+        *pos += 1;
+    }
+    if *pos >= data.len() {
+        return Err(GitError::from("Corrupted TREE extension"));
+    }
+    let name = data[start..*pos].to_vec();
+    *pos += 1;
+
+    let entry_count = read_number(data, pos, b' ')?;
+    let num_children = read_number(data, pos, b'\n')?;
+    let valid = if entry_count >= 0 {
+        if *pos + 20 > data.len() {
+            return Err(GitError::from("Corrupted TREE extension"));
+        }
+        let mut hash = [0; 20];
+        hash.copy_from_slice(&data[*pos..*pos + 20]);
+        *pos += 20;
+        Some((entry_count as usize, hash))
+    } else {
+        None
+    };
+
+    let mut children = Vec::new();
+    for _ in 0..num_children {
+        children.push(read_node(data, pos)?);
+    }
+    Ok((name, CacheTree { valid, children }))
+}
+
+pub fn parse(data: &[u8]) -> GitResult<CacheTree> {
+    let mut pos = 0;
+    let (_, root) = read_node(data, &mut pos)?;
+    Ok(root)
+}
+
+impl CacheTree {
+    fn write_node(&self, name: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(name);
+        out.push(0);
+        let count = self.valid.map_or(-1, |(count, _)| count as i64);
+        out.extend_from_slice(format!("{} {}\n", count, self.children.len()).as_bytes());
+        if let Some((_, ref hash)) = self.valid {
+            out.extend_from_slice(hash);
+        }
+        for (child_name, child) in self.children.iter() {
+            child.write_node(child_name, out);
+        }
+    }
+
+    // The extension's contents, starting from the root
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_node(b"", &mut out);
+        out
+    }
+
+    // The subdirectory with a name, added if there isn't one yet
+    pub fn child_mut(&mut self, name: &[u8]) -> &mut CacheTree {
+        let key = |n: &[u8]| (n.len(), n.to_vec());
+        let ndx = match self.children.binary_search_by(|c| key(&c.0).cmp(&key(name))) {
+            Ok(ndx) => ndx,
+            Err(ndx) => {
+                self.children.insert(ndx, (name.to_vec(), CacheTree::default()));
+                ndx
+            },
+        };
+        &mut self.children[ndx].1
+    }
+
+    // Forget the trees containing a path, since that path has changed
+    pub fn invalidate(&mut self, path: &[u8]) {
+        self.valid = None;
+        let mut parts = path.splitn(2, |&c| c == b'/');
+        let (dir, rest) = match (parts.next(), parts.next()) {
+            (Some(dir), Some(rest)) => (dir, rest),
+            _ => return,
+        };
+        if let Some(&mut (_, ref mut child)) = self.children.iter_mut().find(|c| c.0 == dir) {
+            child.invalidate(rest);
+        }
+    }
+}
@@ -0,0 +1,604 @@
+// The checkout, switch and restore commands: move HEAD to another branch or
+// commit and bring the index and work tree along with it, or copy some
+// paths from a commit or the index
+//
+// Like git's two-way merge, switching only touches paths that differ between
+// the old and new commits, so uncommitted changes to anything else are
+// carried over. Changes that would be lost are refused unless forced.
+
+use cache::{read_obj, ObjectType};
+use commit;
+use config;
+use ident;
+use ignore::Ignores;
+use index::{self, Index};
+use parse;
+use pathspec::{self, Pathspec};
+use refs;
+use repo::Repository;
+use revision;
+use revwalk;
+use status;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use tree::{self, EntryMode};
+use types::{GitError, GitResult};
+
+pub struct RestoreOptions {
+    // A commit or tree to restore from. Without one, the work tree is
+    // restored from the index, and the index from HEAD.
+    pub source: Option<String>,
+    pub staged: bool,
+    pub worktree: bool,
+    // Leave paths the source doesn't have alone, rather than removing them
+    pub overlay: bool,
+    // Say how many files were written, as checkout does without "--"
+    pub report: bool,
+}
+
+pub struct CheckoutOptions {
+    // Throw away local changes instead of refusing to switch
+    pub force: bool,
+    // Detach HEAD even when given a branch
+    pub detach: bool,
+    // Whether this is switch, which only detaches HEAD when asked to
+    pub switch: bool,
+}
+
+// What happens to a path
+enum Action {
+    Keep,
+    Write(EntryMode, [u8; 20]),
+    Remove,
+}
+
+// What checkout will do, or the paths that stop it
+struct Plan {
+    actions: Vec<(Vec<u8>, Action)>,
+    // Tracked files with changes that would be lost
+    modified: Vec<Vec<u8>>,
+    // Untracked files, and directories holding them, in the way of new files
+    untracked: Vec<Vec<u8>>,
+    untracked_dirs: Vec<Vec<u8>>,
+}
+
+type Files = BTreeMap<Vec<u8>, (EntryMode, [u8; 20])>;
+
+fn commit_files(repo: &Repository, commit: Option<&str>) -> GitResult<Files> {
+    match commit {
+        Some(hash) => tree::read_recursive(repo, &revision::peel(repo, hash, ObjectType::Tree)?),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+// Where a tracked path lives in the work tree, refusing names that would
+// reach outside it or into .git
+fn work_path(work_tree: &Path, name: &[u8]) -> GitResult<PathBuf> {
+    if !tree::verify_path(name) {
+        return Err(GitError::from(format!("invalid path '{}'", String::from_utf8_lossy(name))));
+    }
+    Ok(work_tree.join(OsStr::from_bytes(name)))
+}
+
+// The first leading directory of a path that is really a symlink
+fn symlinked_dir<'a>(work_tree: &Path, name: &'a [u8]) -> Option<&'a [u8]> {
+    name.iter().enumerate()
+        .filter(|&(_, &c)| c == b'/')
+        .map(|(ndx, _)| &name[..ndx])
+        .find(|dir| match fs::symlink_metadata(work_tree.join(OsStr::from_bytes(dir))) {
+            Ok(meta) => meta.file_type().is_symlink(),
+            Err(_) => false,
+        })
+}
+
+fn beyond_symlink(name: &[u8]) -> GitError {
+    GitError::from(format!("'{}' is beyond a symbolic link", String::from_utf8_lossy(name)))
+}
+
+// Check every path about to be written or removed before touching any of
+// them. A symlink on the way is only allowed if it's about to be removed.
+fn check_writable(work_tree: &Path, writes: &[&[u8]], removes: &[&[u8]]) -> GitResult<()> {
+    for name in writes.iter().chain(removes.iter()) {
+        work_path(work_tree, name)?;
+        match symlinked_dir(work_tree, name) {
+            Some(dir) if !removes.contains(&dir) => return Err(beyond_symlink(name)),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// Like work_path, for a path about to be written or removed, which mustn't
+// go through a symlink to somewhere else
+fn writable_path(work_tree: &Path, name: &[u8]) -> GitResult<PathBuf> {
+    let path = work_path(work_tree, name)?;
+    match symlinked_dir(work_tree, name) {
+        Some(_) => Err(beyond_symlink(name)),
+        None => Ok(path),
+    }
+}
+
+// The abbreviated hash and subject of a commit, as in "HEAD is now at"
+fn describe(repo: &Repository, hash: &str) -> GitResult<String> {
+    let commit = commit::from_object(&read_obj(repo, hash)?)?;
+    let subject = commit.message.lines().next().unwrap_or("");
+    Ok(format!("{} {}", &hash[..7], subject))
+}
+
+// Whether a tracked file's contents or mode differ from its index entry. A
+// missing file has nothing to lose.
+fn is_modified(repo: &Repository, ndx: &Index, name: &[u8]) -> GitResult<bool> {
+    let entry = match ndx.get(name) {
+        Some(entry) => entry,
+        None => return Ok(false),
+    };
+    let path = work_path(repo.work_tree()?, name)?;
+    match fs::symlink_metadata(&path) {
+        Ok(ref meta) if ndx.is_unchanged(entry, meta) => Ok(false),
+        Ok(ref meta) => Ok(index::mode_from_metadata(meta) != Some(entry.mode)
+                           || status::hash_file(&path, meta)? != entry.hash),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound
+            || err.kind() == io::ErrorKind::NotADirectory => Ok(false),
+        Err(err) => Err(GitError::from(err)),
+    }
+}
+
+// Whether something untracked is in the way of a new file. A directory is
+// only in the way if it holds untracked files, since tracked ones will have
+// been removed by the time the file is written.
+fn is_untracked(repo: &Repository, ndx: &Index, ignores: &mut Ignores, name: &[u8])
+        -> GitResult<bool> {
+    let path = work_path(repo.work_tree()?, name)?;
+    let is_dir = match fs::symlink_metadata(&path) {
+        Ok(meta) => meta.is_dir(),
+        Err(_) => return Ok(false),
+    };
+    // Ignored files are expendable
+    if ignores.is_ignored(name, is_dir)? {
+        return Ok(false);
+    }
+    if !is_dir {
+        return Ok(!ndx.contains(name));
+    }
+    for entry in fs::read_dir(&path)? {
+        let mut child = name.to_vec();
+        child.push(b'/');
+        child.extend_from_slice(entry?.file_name().as_bytes());
+        if is_untracked(repo, ndx, ignores, &child)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Decide what to do with each path, and which paths make it impossible
+fn plan(repo: &Repository, ndx: &Index, old_files: &Files, new_files: &Files, force: bool)
+        -> GitResult<Plan> {
+    let cfg = config::load(Some(repo))?;
+    let mut ignores = Ignores::new(repo, &cfg)?;
+    let mut names: BTreeSet<&Vec<u8>> = old_files.keys().collect();
+    names.extend(new_files.keys());
+    names.extend(ndx.entries.keys().map(|(name, _)| name));
+
+    let mut plan = Plan {
+        actions: Vec::new(),
+        modified: Vec::new(),
+        untracked: Vec::new(),
+        untracked_dirs: Vec::new(),
+    };
+    for name in names {
+        let old = old_files.get(name);
+        let new = new_files.get(name);
+        let staged = ndx.get(name).map(|e| (e.mode, e.hash));
+        let action = if force {
+            match new {
+                Some(_) if staged.as_ref() == new && !is_modified(repo, ndx, name)?
+                        && !ndx.stages(name).any(|(k, _)| k.1 != 0) => Action::Keep,
+                Some(&(mode, hash)) => Action::Write(mode, hash),
+                None => Action::Remove,
+            }
+        } else if old == new || (staged.is_some() && staged.as_ref() == new) {
+            // Nothing changes here, so local changes carry over
+            Action::Keep
+        } else if staged.as_ref() != old || is_modified(repo, ndx, name)? {
+            plan.modified.push(name.clone());
+            continue;
+        } else if staged.is_none() && is_untracked(repo, ndx, &mut ignores, name)? {
+            if work_path(repo.work_tree()?, name)?.is_dir() {
+                plan.untracked_dirs.push(name.clone());
+            } else {
+                plan.untracked.push(name.clone());
+            }
+            continue;
+        } else {
+            match new {
+                Some(&(mode, hash)) => Action::Write(mode, hash),
+                None => Action::Remove,
+            }
+        };
+        plan.actions.push((name.clone(), action));
+    }
+    Ok(plan)
+}
+
+// Remove a file, and any directories that leaves empty
+fn remove_file(work_tree: &Path, name: &[u8]) -> GitResult<()> {
+    let path = writable_path(work_tree, name)?;
+    match fs::remove_file(&path) {
+        Ok(_) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound
+            || err.kind() == io::ErrorKind::NotADirectory => return Ok(()),
+        Err(err) => return Err(GitError::from(err)),
+    }
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d == work_tree || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+    Ok(())
+}
+
+// Write a blob into the work tree, replacing whatever was there
+fn write_file(repo: &Repository, name: &[u8], mode: EntryMode, hash: &[u8; 20])
+        -> GitResult<fs::Metadata> {
+    let path = writable_path(repo.work_tree()?, name)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    match fs::symlink_metadata(&path) {
+        Ok(ref meta) if meta.is_dir() => fs::remove_dir(&path)?,
+        Ok(_) => fs::remove_file(&path)?,
+        Err(_) => (),
+    }
+
+    let obj = read_obj(repo, &parse::bytes_to_hex(hash))?;
+    match mode {
+        EntryMode::Symlink => symlink(OsStr::from_bytes(&obj.data), &path)?,
+        _ => {
+            // The umask decides the rest of the permissions
+            let perms = if mode == EntryMode::ExecutableFile { 0o777 } else { 0o666 };
+            let mut f = fs::OpenOptions::new().write(true).create_new(true).mode(perms)
+                .open(&path)?;
+            f.write_all(&obj.data)?;
+        },
+    }
+    Ok(fs::symlink_metadata(&path)?)
+}
+
+// Whether a work tree file is known to have some contents and mode, because
+// its index entry does and the file hasn't changed since. Like git, anything
+// else is rewritten, even if it happens to match.
+fn worktree_matches(repo: &Repository, ndx: &Index, name: &[u8], mode: EntryMode,
+                    hash: &[u8; 20]) -> GitResult<bool> {
+    let path = work_path(repo.work_tree()?, name)?;
+    match (ndx.get(name), fs::symlink_metadata(&path)) {
+        (Some(entry), Ok(ref meta)) => {
+            Ok(entry.mode == mode && entry.hash == *hash && ndx.is_unchanged(entry, meta))
+        },
+        _ => Ok(false),
+    }
+}
+
+// Copy the paths matching some pathspecs from a commit, tree or the index
+// into the index, the work tree or both
+pub fn restore(repo: &Repository, specs: &[Pathspec], opts: &RestoreOptions) -> GitResult<()> {
+    let mut ndx = index::read(repo)?;
+    let tree = match opts.source {
+        Some(ref rev) => {
+            Some(revision::peel(repo, &revision::resolve(repo, rev)?, ObjectType::Tree)?)
+        },
+        None if opts.staged => match refs::read_ref(repo, "HEAD") {
+            Ok(head) => Some(revision::peel(repo, &head, ObjectType::Tree)?),
+            Err(GitError::IoError(ref err)) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        },
+        None => None,
+    };
+    let from_index = opts.source.is_none() && !opts.staged;
+    let files = match tree {
+        Some(ref tree) => tree::read_recursive(repo, tree)?,
+        None => BTreeMap::new(),
+    };
+
+    let mut names: BTreeSet<&Vec<u8>> = files.keys().collect();
+    names.extend(ndx.entries.keys().map(|(name, _)| name));
+    let mut matched = vec![false; specs.len()];
+    let mut unmerged = Vec::new();
+    let mut actions = Vec::new();
+    for name in names {
+        if !pathspec::match_any(specs, &mut matched, name) {
+            continue;
+        }
+        let source = if from_index {
+            match ndx.get(name) {
+                Some(entry) => Some((entry.mode, entry.hash)),
+                None => {
+                    unmerged.push(name.clone());
+                    continue;
+                },
+            }
+        } else {
+            files.get(name).cloned()
+        };
+        if source.is_some() || !opts.overlay {
+            actions.push((name.clone(), source));
+        }
+    }
+
+    let mut errors = String::new();
+    for (spec, _) in specs.iter().zip(matched.iter()).filter(|&(_, &m)| !m) {
+        errors.push_str(&format!("error: pathspec '{}' did not match any file(s) known to git\n",
+                                 spec.original));
+    }
+    for name in unmerged.iter() {
+        errors.push_str(&format!("error: path '{}' is unmerged\n",
+                                 String::from_utf8_lossy(name)));
+    }
+    if !errors.is_empty() {
+        return Err(GitError::Aborted(errors));
+    }
+
+    let work_tree = repo.work_tree()?;
+    if opts.worktree {
+        let writes: Vec<&[u8]> = actions.iter()
+            .filter(|&(_, source)| source.is_some())
+            .map(|(name, _)| name.as_slice())
+            .collect();
+        let removes: Vec<&[u8]> = actions.iter()
+            .filter(|&(_, source)| source.is_none())
+            .map(|(name, _)| name.as_slice())
+            .collect();
+        check_writable(work_tree, &writes, &removes)?;
+    }
+    let mut written = 0;
+    for (name, source) in actions {
+        let (mode, hash) = match source {
+            Some(source) => source,
+            None => {
+                if opts.worktree {
+                    remove_file(work_tree, &name)?;
+                }
+                if opts.staged {
+                    ndx.remove(&name);
+                }
+                continue;
+            },
+        };
+        let meta = if opts.worktree && !worktree_matches(repo, &ndx, &name, mode, &hash)? {
+            written += 1;
+            Some(write_file(repo, &name, mode, &hash)?)
+        } else {
+            None
+        };
+        // The index entry changes when it's restored, and its stat data
+        // when the file it describes was rewritten
+        match meta {
+            Some(ref meta) if opts.staged || from_index => ndx.add_entry(name, mode, hash, meta),
+            None if opts.staged && opts.worktree => {
+                let meta = fs::symlink_metadata(work_path(work_tree, &name)?)?;
+                ndx.add_entry(name, mode, hash, &meta);
+            },
+            _ if opts.staged => ndx.set_entry(name, mode, hash),
+            _ => (),
+        }
+    }
+    ndx.write(repo)?;
+
+    if opts.report {
+        let from = match tree {
+            Some(ref tree) if !from_index => tree[..7].to_string(),
+            _ => String::from("the index"),
+        };
+        eprintln!("Updated {} path{} from {}", written, if written == 1 { "" } else { "s" }, from);
+    }
+    Ok(())
+}
+
+// Warn about commits that only a detached HEAD could reach, or say where
+// HEAD was
+fn describe_old_head(repo: &Repository, old: &str, new: &str) -> GitResult<()> {
+    let mut starts: Vec<String> = refs::list_refs(repo)?.into_iter()
+        .filter_map(|(_, hash)| revision::peel(repo, &hash, ObjectType::Commit).ok())
+        .collect();
+    starts.push(String::from(new));
+    let reachable = revwalk::ancestors(repo, &starts)?;
+    let lost = revwalk::walk(repo, &[String::from(old)], &reachable,
+                             &revwalk::WalkOptions::new())?;
+    if lost.is_empty() {
+        eprintln!("Previous HEAD position was {}", describe(repo, old)?);
+        return Ok(());
+    }
+
+    // Git lists four of them, or all five rather than "and 1 more"
+    let shown = if lost.len() <= 5 { lost.len() } else { 4 };
+    let mut list = String::new();
+    for (hash, _) in lost[..shown].iter() {
+        list.push_str(&format!("  {}\n", describe(repo, hash)?));
+    }
+    if shown < lost.len() {
+        list.push_str(&format!(" ... and {} more.\n", lost.len() - shown));
+    }
+    let (commits, it) = if lost.len() == 1 { ("commit", "it") } else { ("commits", "them") };
+    eprint!("Warning: you are leaving {} {} behind, not connected to\n\
+             any of your branches:\n\n{}\n", lost.len(), commits, list);
+    if config::load(Some(repo))?.get_bool("advice.detachedhead")?.unwrap_or(true) {
+        eprint!("If you want to keep {} by creating a new branch, this may be a good time\n\
+                 to do so with:\n\n git branch <new-branch-name> {}\n\n", it, &old[..7]);
+    }
+    Ok(())
+}
+
+fn print_detach_advice(name: &str) {
+    eprint!("Note: switching to '{}'.\n\n\
+             You are in 'detached HEAD' state. You can look around, make experimental\n\
+             changes and commit them, and you can discard any commits you make in this\n\
+             state without impacting any branches by switching back to a branch.\n\n\
+             If you want to create a new branch to retain commits you create, you may\n\
+             do so (now or later) by using -c with the switch command. Example:\n\n  \
+             git switch -c <new-branch-name>\n\n\
+             Or undo this operation with:\n\n  \
+             git switch -\n\n\
+             Turn off this advice by setting config variable advice.detachedHead to false\n\n",
+           name);
+}
+
+pub fn checkout(repo: &Repository, name: &str, opts: &CheckoutOptions) -> GitResult<()> {
+    let work_tree = repo.work_tree()?;
+    // "-" is the branch we were on before
+    let name = if name == "-" { revision::previous_branch(repo, 1)? } else { String::from(name) };
+
+    // A branch name means switching to that branch, anything else that
+    // names a commit detaches HEAD
+    let branch_ref = format!("refs/heads/{}", name);
+    let branch = match refs::read_ref(repo, &branch_ref) {
+        Ok(hash) if !opts.detach => Some((branch_ref, hash)),
+        _ => None,
+    };
+    let new = match branch {
+        Some((_, ref hash)) => hash.clone(),
+        None => match revision::resolve(repo, &name) {
+            Ok(hash) if opts.switch && !opts.detach => {
+                revision::peel(repo, &hash, ObjectType::Commit)?;
+                eprintln!("hint: If you want to detach HEAD at the commit, try again with \
+                           the --detach option.");
+                return Err(GitError::from(format!("a branch is expected, got commit '{}'",
+                                                  name)));
+            },
+            Ok(hash) => revision::peel(repo, &hash, ObjectType::Commit)?,
+            Err(_) => return Err(GitError::from(format!("invalid reference: {}", name))),
+        },
+    };
+
+    let old_branch = refs::symbolic_target(repo, "HEAD")?;
+    let old = match refs::read_ref(repo, "HEAD") {
+        Ok(hash) => Some(hash),
+        // Nothing has been committed on this branch yet
+        Err(GitError::IoError(ref err)) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+
+    let mut ndx = index::read(repo)?;
+    if !opts.force && ndx.has_conflicts() {
+        let unmerged: BTreeSet<&Vec<u8>> = ndx.entries.keys()
+            .filter(|&&(_, stage)| stage != 0)
+            .map(|(name, _)| name)
+            .collect();
+        for name in unmerged {
+            println!("{}: needs merge", String::from_utf8_lossy(name));
+        }
+        return Err(GitError::Aborted(String::from(
+            "error: you need to resolve your current index first\n")));
+    }
+
+    let old_files = commit_files(repo, old.as_deref())?;
+    let new_files = commit_files(repo, Some(&new))?;
+    let plan = plan(repo, &ndx, &old_files, &new_files, opts.force)?;
+    if !plan.modified.is_empty() || !plan.untracked.is_empty()
+            || !plan.untracked_dirs.is_empty() {
+        let mut msg = String::new();
+        if !plan.modified.is_empty() {
+            msg.push_str("error: Your local changes to the following files would be overwritten \
+                          by checkout:\n");
+            for name in plan.modified.iter() {
+                msg.push_str(&format!("\t{}\n", String::from_utf8_lossy(name)));
+            }
+            msg.push_str("Please commit your changes or stash them before you switch branches.\n");
+        }
+        if !plan.untracked_dirs.is_empty() {
+            msg.push_str("error: Updating the following directories would lose untracked files \
+                          in them:\n");
+            for name in plan.untracked_dirs.iter() {
+                msg.push_str(&format!("\t{}\n", String::from_utf8_lossy(name)));
+            }
+            msg.push('\n');
+        }
+        if !plan.untracked.is_empty() {
+            msg.push_str("error: The following untracked working tree files would be overwritten \
+                          by checkout:\n");
+            for name in plan.untracked.iter() {
+                msg.push_str(&format!("\t{}\n", String::from_utf8_lossy(name)));
+            }
+            msg.push_str("Please move or remove them before you switch branches.\n");
+        }
+        msg.push_str("Aborting\n");
+        return Err(GitError::Aborted(msg));
+    }
+
+    let mut writes = Vec::new();
+    let mut removes = Vec::new();
+    for (name, action) in plan.actions.iter() {
+        match *action {
+            Action::Keep => (),
+            Action::Write(..) => writes.push(name.as_slice()),
+            Action::Remove => removes.push(name.as_slice()),
+        }
+    }
+    check_writable(work_tree, &writes, &removes)?;
+
+    // Clear the way before writing anything, since a file may be replaced
+    // by a directory of the same name
+    for (name, action) in plan.actions.iter() {
+        if let Action::Remove = *action {
+            remove_file(work_tree, name)?;
+            ndx.remove(name);
+        }
+    }
+    for (name, action) in plan.actions.iter() {
+        if let Action::Write(mode, ref hash) = *action {
+            let meta = write_file(repo, name, mode, hash)?;
+            ndx.add_entry(name.clone(), mode, *hash, &meta);
+        }
+    }
+    ndx.write(repo)?;
+
+    // Show what was carried over
+    if !opts.force {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        for (code, name) in status::local_changes(repo, &ndx, Some(&new))? {
+            writeln!(out, "{}\t{}", code, status::quote_path(&name, false))?;
+        }
+    }
+
+    let old_desc = match (old_branch.as_ref(), old.as_ref()) {
+        (Some(branch), _) => String::from(status::short_ref_name(branch)),
+        (None, Some(hash)) => hash.clone(),
+        (None, None) => String::from("(invalid)"),
+    };
+    let cfg = config::load(Some(repo))?;
+    let (committer, date) = ident::identity(ident::Role::Committer, &cfg)?;
+    let message = format!("checkout: moving from {} to {}", old_desc, name);
+
+    match (old_branch.as_ref(), old.as_ref()) {
+        (None, Some(old)) if *old != new => describe_old_head(repo, old, &new)?,
+        _ => (),
+    }
+    match branch {
+        Some((ref branch_ref, _)) => {
+            refs::set_head(repo, Some(branch_ref), old.as_deref(), &new,
+                           &committer, &date, &message)?;
+            if old_branch.as_ref() == Some(branch_ref) {
+                eprintln!("Already on '{}'", name);
+            } else {
+                eprintln!("Switched to branch '{}'", name);
+            }
+        },
+        None => {
+            refs::set_head(repo, None, old.as_deref(), &new,
+                           &committer, &date, &message)?;
+            if old_branch.is_some() && !opts.detach
+                    && cfg.get_bool("advice.detachedhead")?.unwrap_or(true) {
+                print_detach_advice(&name);
+            }
+            eprintln!("HEAD is now at {}", describe(repo, &new)?);
+        },
+    }
+    Ok(())
+}
@@ -30,7 +30,7 @@ fn string_from_hex_bytes(data: &[u8]) -> GitResult<String> {
 }
 
 // Parse bytestring of the form "blah" to (author, date)
-fn parse_author_line(mut line: String) -> GitResult<(String, DateTime<FixedOffset>)> {
+pub fn parse_author_line(mut line: String) -> GitResult<(String, DateTime<FixedOffset>)> {
     // Get offset string and date timestamp
     let last_space = match line.rfind(' ') {
         Some(ndx) => ndx,
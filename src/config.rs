@@ -0,0 +1,761 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use repo::Repository;
+use types::{GitError, GitResult};
+use wildmatch::{self, MatchFlags};
+
+// How deeply include.path may nest before we assume a loop
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+pub struct ConfigEntry {
+    // Section and key names are case insensitive, so they're kept lowercase
+    pub section: String,
+    pub subsection: Option<String>,
+    pub key: String,
+    // None for a key with no "=", which means true
+    pub value: Option<String>,
+    // Character offsets of the key, and of the end of the entry's line
+    start: usize,
+    end: usize,
+}
+
+// A section header, and the character offset just past its "]"
+struct SectionHeader {
+    section: String,
+    subsection: Option<String>,
+    end: usize,
+}
+
+pub struct Config {
+    // In the order they were read, later entries take precedence
+    pub entries: Vec<ConfigEntry>,
+}
+
+impl ConfigEntry {
+    // The full "section[.subsection].key" name
+    pub fn name(&self) -> String {
+        match self.subsection {
+            Some(ref sub) => format!("{}.{}.{}", self.section, sub, self.key),
+            None => format!("{}.{}", self.section, self.key),
+        }
+    }
+
+    fn is_named(&self, section: &str, subsection: &Option<String>, key: &str) -> bool {
+        self.section == section && self.subsection == *subsection && self.key == key
+    }
+}
+
+// Split "section.subsection.key" into its parts
+fn split_name(name: &str) -> GitResult<(String, Option<String>, String)> {
+    let first_dot = name.find('.');
+    let last_dot = name.rfind('.');
+    match (first_dot, last_dot) {
+        (Some(first), Some(last)) if first > 0 && last < name.len() - 1 => {
+            let subsection = if first == last {
+                None
+            } else {
+                Some(String::from(&name[first + 1..last]))
+            };
+            let key = &name[last + 1..];
+            if !key.starts_with(|c: char| c.is_ascii_alphabetic())
+                    || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                return Err(GitError::from(format!("invalid key: {}", name)));
+            }
+            Ok((name[..first].to_lowercase(), subsection, key.to_lowercase()))
+        },
+        _ => Err(GitError::from(format!("key does not contain a section: {}", name))),
+    }
+}
+
+// Parse a quoted or unquoted value, up to the end of the line or a comment
+fn parse_value(chars: &[char], pos: &mut usize) -> GitResult<String> {
+    let mut value = String::new();
+    let mut in_quotes = false;
+    // Trailing whitespace outside of quotes is dropped
+    let mut committed_len = 0;
+    while *pos < chars.len() {
+        let c = chars[*pos];
+        *pos += 1;
+        match c {
+            '\n' if !in_quotes => break,
+            '\n' => return Err(GitError::from("Unterminated quoted config value")),
+            ';' | '#' if !in_quotes => {
+                while *pos < chars.len() && chars[*pos] != '\n' {
+                    *pos += 1;
+                }
+            },
+            '"' => {
+                in_quotes = !in_quotes;
+                committed_len = value.len();
+            },
+            '\\' => {
+                let escaped = match chars.get(*pos) {
+                    Some(&e) => e,
+                    None => return Err(GitError::from("Bad config escape")),
+                };
+                *pos += 1;
+                match escaped {
+                    // A backslash at the end of a line continues the value
+                    '\n' => continue,
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'b' => { value.pop(); },
+                    '\\' | '"' => value.push(escaped),
+                    _ => return Err(GitError::from("Bad config escape")),
+                }
+                committed_len = value.len();
+            },
+            c if c.is_whitespace() && !in_quotes => {
+                // Inner whitespace becomes spaces, leading whitespace is dropped
+                if !value.is_empty() {
+                    value.push(' ');
+                }
+            },
+            _ => {
+                value.push(c);
+                committed_len = value.len();
+            },
+        }
+    }
+    if in_quotes {
+        return Err(GitError::from("Unterminated quoted config value"));
+    }
+    value.truncate(committed_len);
+    Ok(value)
+}
+
+// Parse the contents of a config file
+fn parse(contents: &str) -> GitResult<(Vec<ConfigEntry>, Vec<SectionHeader>)> {
+    let chars: Vec<char> = contents.chars().collect();
+    let mut pos = 0;
+    let mut entries = Vec::new();
+    let mut headers = Vec::new();
+    let mut section: Option<(String, Option<String>)> = None;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c == '#' || c == ';' {
+            while pos < chars.len() && chars[pos] != '\n' {
+                pos += 1;
+            }
+        } else if c == '[' {
+            // [section] or [section "subsection"]
+            pos += 1;
+            let mut name = String::new();
+            while pos < chars.len() && (chars[pos].is_alphanumeric()
+                                        || chars[pos] == '-' || chars[pos] == '.') {
+                name.push(chars[pos]);
+                pos += 1;
+            }
+            let mut subsection = None;
+            if pos < chars.len() && chars[pos] == ' ' {
+                while pos < chars.len() && chars[pos] == ' ' {
+                    pos += 1;
+                }
+                if pos >= chars.len() || chars[pos] != '"' {
+                    return Err(GitError::from("Bad config section header"));
+                }
+                pos += 1;
+                let mut sub = String::new();
+                loop {
+                    match chars.get(pos) {
+                        Some(&'"') => break,
+                        Some(&'\\') if pos + 1 < chars.len() => {
+                            sub.push(chars[pos + 1]);
+                            pos += 2;
+                        },
+                        Some(&'\n') | None => return Err(GitError::from("Bad config section header")),
+                        Some(&c) => {
+                            sub.push(c);
+                            pos += 1;
+                        },
+                    }
+                }
+                pos += 1;
+                subsection = Some(sub);
+            }
+            if pos >= chars.len() || chars[pos] != ']' || name.is_empty() {
+                return Err(GitError::from("Bad config section header"));
+            }
+            pos += 1;
+            // The deprecated [section.subsection] syntax, where the
+            // subsection is case insensitive too
+            if subsection.is_none() && name.contains('.') {
+                let dot = name.find('.').unwrap();
+                subsection = Some(name[dot + 1..].to_lowercase());
+                name.truncate(dot);
+            }
+            headers.push(SectionHeader {
+                section: name.to_lowercase(),
+                subsection: subsection.clone(),
+                end: pos,
+            });
+            section = Some((name.to_lowercase(), subsection));
+        } else if c.is_alphabetic() {
+            let (sec, sub) = match section {
+                Some(ref s) => s.clone(),
+                None => return Err(GitError::from("Config key outside of a section")),
+            };
+            let start = pos;
+            let mut key = String::new();
+            while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '-') {
+                key.push(chars[pos]);
+                pos += 1;
+            }
+            while pos < chars.len() && (chars[pos] == ' ' || chars[pos] == '\t') {
+                pos += 1;
+            }
+            let value = if pos < chars.len() && chars[pos] == '=' {
+                pos += 1;
+                Some(parse_value(&chars, &mut pos)?)
+            } else {
+                // Nothing but a comment may follow a key without a value
+                if pos < chars.len() && !chars[pos].is_whitespace()
+                        && chars[pos] != '#' && chars[pos] != ';' {
+                    return Err(GitError::from("Bad config line"));
+                }
+                while pos < chars.len() && chars[pos] != '\n' {
+                    pos += 1;
+                }
+                if pos < chars.len() {
+                    pos += 1;
+                }
+                None
+            };
+            // We stop just past the newline that ends the entry, if there is one
+            entries.push(ConfigEntry {
+                section: sec,
+                subsection: sub,
+                key: key.to_lowercase(),
+                value,
+                start,
+                end: pos,
+            });
+        } else {
+            return Err(GitError::from("Bad config line"));
+        }
+    }
+    Ok((entries, headers))
+}
+
+fn read_file(path: &Path) -> GitResult<Option<String>> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            f.read_to_string(&mut contents)?;
+            Ok(Some(contents))
+        },
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => Ok(None),
+            _ => Err(GitError::from(err)),
+        },
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from)
+}
+
+// Expand a leading "~/" and make relative paths relative to the directory
+// of the file that mentioned them
+fn expand_path(path: &str, relative_to: &Path) -> PathBuf {
+    if let (Some(rest), Some(home)) = (path.strip_prefix("~/"), home_dir()) {
+        return home.join(rest);
+    }
+    match relative_to.parent() {
+        Some(dir) => dir.join(path),
+        None => PathBuf::from(path),
+    }
+}
+
+// Whether an includeIf "gitdir:" condition applies to this repository
+fn gitdir_matches(pattern: &str, ignore_case: bool, config_path: &Path, git_dir: Option<&Path>)
+                  -> bool {
+    let git_dir = match git_dir.map(fs::canonicalize) {
+        Some(Ok(dir)) => dir,
+        _ => return false,
+    };
+    let mut pattern = if let Some(rest) = pattern.strip_prefix("./") {
+        expand_path(rest, config_path).to_string_lossy().into_owned()
+    } else if pattern.starts_with("~/") {
+        expand_path(pattern, config_path).to_string_lossy().into_owned()
+    } else if pattern.starts_with('/') {
+        String::from(pattern)
+    } else {
+        format!("**/{}", pattern)
+    };
+    if pattern.ends_with('/') {
+        pattern.push_str("**");
+    }
+    let flags = MatchFlags { pathname: true, ignore_case };
+    let git_dir = git_dir.to_string_lossy();
+    // A pattern naming the .git directory itself should match too
+    wildmatch::wildmatch(pattern.as_bytes(), git_dir.as_bytes(), &flags)
+        || wildmatch::wildmatch(pattern.as_bytes(), format!("{}/", git_dir).as_bytes(), &flags)
+}
+
+// Read a config file, and any files it includes, onto the end of entries
+fn load_file(path: &Path, entries: &mut Vec<ConfigEntry>, depth: usize, git_dir: Option<&Path>)
+             -> GitResult<()> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(GitError::from("exceeded maximum include depth"));
+    }
+    let contents = match read_file(path)? {
+        Some(contents) => contents,
+        None => return Ok(()),
+    };
+    for entry in parse(&contents)?.0 {
+        let include_path = if entry.key != "path" || entry.value.is_none() {
+            None
+        } else if entry.section == "include" && entry.subsection.is_none() {
+            entry.value.clone()
+        } else if entry.section == "includeif" {
+            let condition = entry.subsection.clone().unwrap_or_default();
+            let applies = if let Some(pattern) = condition.strip_prefix("gitdir:") {
+                gitdir_matches(pattern, false, path, git_dir)
+            } else if let Some(pattern) = condition.strip_prefix("gitdir/i:") {
+                gitdir_matches(pattern, true, path, git_dir)
+            } else {
+                false
+            };
+            if applies { entry.value.clone() } else { None }
+        } else {
+            None
+        };
+        entries.push(entry);
+        if let Some(include) = include_path {
+            load_file(&expand_path(&include, path), entries, depth + 1, git_dir)?;
+        }
+    }
+    Ok(())
+}
+
+// The system-wide config file
+pub fn system_path() -> Option<PathBuf> {
+    if env::var_os("GIT_CONFIG_NOSYSTEM").is_some() {
+        return None;
+    }
+    match env::var_os("GIT_CONFIG_SYSTEM") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(PathBuf::from("/etc/gitconfig")),
+    }
+}
+
+// The global config files, lowest precedence first
+pub fn global_paths() -> Vec<PathBuf> {
+    if let Some(path) = env::var_os("GIT_CONFIG_GLOBAL") {
+        return vec![PathBuf::from(path)];
+    }
+    let mut paths = Vec::new();
+    let home = home_dir();
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(xdg) => paths.push(PathBuf::from(xdg).join("git/config")),
+        None => if let Some(ref h) = home {
+            paths.push(h.join(".config/git/config"));
+        },
+    }
+    if let Some(ref h) = home {
+        paths.push(h.join(".gitconfig"));
+    }
+    paths
+}
+
+// The file "config --global" writes to
+pub fn global_write_path() -> GitResult<PathBuf> {
+    match global_paths().pop() {
+        Some(path) => Ok(path),
+        None => Err(GitError::from("$HOME not set")),
+    }
+}
+
+pub fn local_path(repo: &Repository) -> PathBuf {
+    repo.path("config")
+}
+
+// Read a single config file, following its includes
+pub fn load_path(path: &Path) -> GitResult<Config> {
+    let mut entries = Vec::new();
+    load_file(path, &mut entries, 0, None)?;
+    Ok(Config { entries })
+}
+
+// Read the system, global, repository and worktree config, in that order.
+// Outside a repository only the first two are read.
+pub fn load(repo: Option<&Repository>) -> GitResult<Config> {
+    let mut paths: Vec<PathBuf> = system_path().into_iter().collect();
+    paths.extend(global_paths());
+    paths.extend(repo.map(local_path));
+
+    let git_dir = repo.map(|r| r.git_dir.as_path());
+    let mut config = Config { entries: Vec::new() };
+    for path in paths {
+        load_file(&path, &mut config.entries, 0, git_dir)?;
+    }
+    match repo {
+        Some(repo) if config.get_bool("extensions.worktreeconfig")?.unwrap_or(false) => {
+            load_file(&repo.path("config.worktree"), &mut config.entries, 0, git_dir)?;
+        },
+        _ => (),
+    }
+    Ok(config)
+}
+
+// Interpret a config value as a boolean
+pub fn parse_bool(value: Option<&str>) -> GitResult<bool> {
+    let value = match value {
+        Some(v) => v.to_lowercase(),
+        // A key with no value at all is true
+        None => return Ok(true),
+    };
+    match value.as_str() {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" | "" => Ok(false),
+        _ => match parse_int(&value) {
+            Ok(n) => Ok(n != 0),
+            Err(_) => Err(GitError::from(format!("bad boolean config value '{}'", value))),
+        },
+    }
+}
+
+// Interpret a config value as an integer, with an optional k, m or g suffix
+pub fn parse_int(value: &str) -> GitResult<i64> {
+    let value = value.trim();
+    let (digits, factor) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&value[..value.len() - 1], 1024),
+        Some('m') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('g') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    match digits.parse::<i64>().ok().and_then(|n| n.checked_mul(factor)) {
+        Some(n) => Ok(n),
+        None => Err(GitError::from(format!("bad numeric config value '{}'", value))),
+    }
+}
+
+impl Config {
+    fn matching<'a>(&'a self, name: &str) -> GitResult<Vec<&'a ConfigEntry>> {
+        let (section, subsection, key) = split_name(name)?;
+        Ok(self.entries.iter()
+           .filter(|e| e.is_named(&section, &subsection, &key))
+           .collect())
+    }
+
+    // The last value set for a "section[.subsection].key" name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match self.matching(name) {
+            Ok(entries) => entries.last()
+                .map(|e| e.value.as_ref().map_or("true", |v| v.as_str())),
+            Err(_) => None,
+        }
+    }
+
+    // Every value of a multi-valued key, in order
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        match self.matching(name) {
+            Ok(entries) => entries.iter()
+                .map(|e| e.value.as_ref().map_or("true", |v| v.as_str()))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn get_bool(&self, name: &str) -> GitResult<Option<bool>> {
+        match self.matching(name)?.last() {
+            Some(entry) => Ok(Some(parse_bool(entry.value.as_deref())?)),
+            None => Ok(None),
+        }
+    }
+
+    // A path value, with a leading "~/" expanded to the home directory
+    pub fn get_path(&self, name: &str) -> Option<PathBuf> {
+        self.get(name).map(|value| {
+            match (value.starts_with("~/"), home_dir()) {
+                (true, Some(home)) => home.join(&value[2..]),
+                _ => PathBuf::from(value),
+            }
+        })
+    }
+
+    pub fn get_int(&self, name: &str) -> GitResult<Option<i64>> {
+        match self.get(name) {
+            Some(value) => Ok(Some(parse_int(value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// Quote and escape a value so it reads back the same
+fn format_value(value: &str) -> String {
+    let needs_quotes = value.starts_with(' ') || value.ends_with(' ')
+        || value.contains(';') || value.contains('#');
+    let mut result = String::new();
+    if needs_quotes {
+        result.push('"');
+    }
+    for c in value.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '"' => result.push_str("\\\""),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            _ => result.push(c),
+        }
+    }
+    if needs_quotes {
+        result.push('"');
+    }
+    result
+}
+
+// Replace a config file through a lock file
+fn write_file(path: &Path, contents: &str) -> GitResult<()> {
+    let lock_path = PathBuf::from(format!("{}.lock", path.display()));
+    let mut lock = match fs::OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+        Ok(f) => f,
+        Err(err) => match err.kind() {
+            io::ErrorKind::AlreadyExists => return Err(GitError::from(format!(
+                "could not lock config file {}: File exists", path.display()))),
+            _ => return Err(GitError::from(err)),
+        },
+    };
+    let result = lock.write_all(contents.as_bytes())
+        .and_then(|_| lock.sync_all())
+        .and_then(|_| fs::rename(&lock_path, path));
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            let _ = fs::remove_file(&lock_path);
+            Err(GitError::from(err))
+        },
+    }
+}
+
+fn read_entries(path: &Path) -> GitResult<(Vec<char>, Vec<ConfigEntry>, Vec<SectionHeader>)> {
+    let contents = read_file(path)?.unwrap_or_default();
+    let (entries, headers) = parse(&contents)?;
+    Ok((contents.chars().collect(), entries, headers))
+}
+
+// Replace an entry with text, which is either a whole line or nothing
+// An entry sharing a line with its section header leaves the header on a
+// line of its own
+fn replace_entry(chars: &[char], entry: &ConfigEntry, text: &str) -> String {
+    let mut start = entry.start;
+    while start > 0 && (chars[start - 1] == ' ' || chars[start - 1] == '\t') {
+        start -= 1;
+    }
+    let mut result: String = chars[..start].iter().collect();
+    if start > 0 && chars[start - 1] != '\n' {
+        result.push('\n');
+    }
+    result.push_str(text);
+    result.extend(chars[entry.end..].iter());
+    result
+}
+
+// Insert a line of text at a character offset, starting a new line first if
+// the offset is partway through one
+fn insert_line(chars: &[char], pos: usize, text: &str) -> String {
+    let mut result: String = chars[..pos].iter().collect();
+    if pos > 0 && chars[pos - 1] != '\n' {
+        result.push('\n');
+    }
+    result.push_str(text);
+    result.extend(chars[pos..].iter());
+    result
+}
+
+// Set a value in one config file, keeping everything else as it was
+pub fn set_value(path: &Path, name: &str, value: &str) -> GitResult<()> {
+    let (section, subsection, key) = split_name(name)?;
+    let (chars, entries, headers) = read_entries(path)?;
+    let existing: Vec<&ConfigEntry> = entries.iter()
+        .filter(|e| e.is_named(&section, &subsection, &key))
+        .collect();
+    if existing.len() > 1 {
+        return Err(GitError::from(format!(
+            "cannot overwrite multiple values with a single value for {}", name)));
+    }
+
+    // Keep the key's original spelling when replacing it
+    let new_line = format!("\t{} = {}\n", &name[name.rfind('.').unwrap() + 1..],
+                           format_value(value));
+    let contents = match existing.first() {
+        Some(entry) => replace_entry(&chars, entry, &new_line),
+        None => {
+            // Add to the end of the last matching section, or make a new one
+            let last_in_section = headers.iter()
+                .filter(|h| h.section == section && h.subsection == subsection)
+                .map(|h| {
+                    let entries_end = entries.iter()
+                        .filter(|e| e.start > h.end)
+                        .take_while(|e| e.section == section && e.subsection == subsection)
+                        .map(|e| e.end)
+                        .last();
+                    // An empty section's entries go after the rest of its
+                    // header's line
+                    entries_end.unwrap_or_else(|| {
+                        match chars[h.end..].iter().position(|&c| c == '\n') {
+                            Some(ndx) => h.end + ndx + 1,
+                            None => chars.len(),
+                        }
+                    })
+                })
+                .next_back();
+            match last_in_section {
+                Some(pos) => insert_line(&chars, pos, &new_line),
+                None => {
+                    let header = match subsection {
+                        Some(ref sub) => format!("[{} \"{}\"]", section,
+                                                 sub.replace('\\', "\\\\").replace('"', "\\\"")),
+                        None => format!("[{}]", section),
+                    };
+                    insert_line(&chars, chars.len(), &format!("{}\n{}", header, new_line))
+                },
+            }
+        },
+    };
+
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir)?,
+        _ => (),
+    }
+    write_file(path, &contents)
+}
+
+// Remove a key from one config file
+pub fn unset_value(path: &Path, name: &str) -> GitResult<()> {
+    let (section, subsection, key) = split_name(name)?;
+    let (chars, entries, _) = read_entries(path)?;
+    let existing: Vec<&ConfigEntry> = entries.iter()
+        .filter(|e| e.is_named(&section, &subsection, &key))
+        .collect();
+    match existing.len() {
+        0 => Err(GitError::from(format!("key {} is not set", name))),
+        1 => write_file(path, &replace_entry(&chars, existing[0], "")),
+        _ => Err(GitError::from(format!("{} has multiple values", name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::TempDir;
+
+    const SAMPLE: &str = "# top comment\n\
+                          [core]\n\
+                          \tbare = false ; trailing\n\
+                          \t# indented comment\n\
+                          \tfilemode = true\n\
+                          [remote \"origin\"]\n\
+                          \turl = https://example.com/repo\n\
+                          [core]\n\
+                          \tautocrlf = input\n";
+
+    // Run an edit on a config file holding contents, and return the result
+    fn edit<F: FnOnce(&Path) -> GitResult<()>>(name: &str, contents: &str, f: F) -> String {
+        let dir = TempDir::new(name);
+        let path = dir.path.join("config");
+        fs::write(&path, contents).unwrap();
+        f(&path).unwrap();
+        fs::read_to_string(&path).unwrap()
+    }
+
+    #[test]
+    fn set_adds_to_the_last_matching_section() {
+        let result = edit("config-set-new", SAMPLE, |p| set_value(p, "core.editor", "vim"));
+        assert_eq!(result, format!("{}\teditor = vim\n", SAMPLE));
+    }
+
+    #[test]
+    fn set_replaces_only_the_value() {
+        let result = edit("config-set-existing", SAMPLE, |p| set_value(p, "core.bare", "true"));
+        assert_eq!(result, SAMPLE.replace("bare = false ; trailing", "bare = true"));
+    }
+
+    #[test]
+    fn set_creates_sections() {
+        let result = edit("config-set-section", SAMPLE, |p| {
+            set_value(p, "remote.upstream.url", "a \"b\"")?;
+            set_value(p, "user.name", " Pad;ded ")
+        });
+        assert_eq!(result, format!("{}[remote \"upstream\"]\n\turl = a \\\"b\\\"\n\
+                                    [user]\n\tname = \" Pad;ded \"\n", SAMPLE));
+        let dir = TempDir::new("config-set-missing");
+        let path = dir.path.join("sub/config");
+        set_value(&path, "core.bare", "true").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[core]\n\tbare = true\n");
+    }
+
+    #[test]
+    fn set_in_empty_section() {
+        let contents = "[alias]\n[core] bare = true\n";
+        let result = edit("config-set-empty", contents, |p| set_value(p, "alias.co", "checkout"));
+        assert_eq!(result, "[alias]\n\tco = checkout\n[core] bare = true\n");
+    }
+
+    #[test]
+    fn set_refuses_multiple_values() {
+        let dir = TempDir::new("config-set-multiple");
+        let path = dir.path.join("config");
+        let contents = "[remote \"origin\"]\n\tfetch = a\n\tfetch = b\n";
+        fs::write(&path, contents).unwrap();
+        assert!(set_value(&path, "remote.origin.fetch", "c").is_err());
+        assert!(unset_value(&path, "remote.origin.fetch").is_err());
+        assert!(unset_value(&path, "remote.origin.url").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+    }
+
+    #[test]
+    fn unset_keeps_comments() {
+        let result = edit("config-unset", SAMPLE, |p| unset_value(p, "core.filemode"));
+        assert_eq!(result, SAMPLE.replace("\tfilemode = true\n", ""));
+        let result = edit("config-unset-trailing", SAMPLE, |p| unset_value(p, "core.bare"));
+        assert_eq!(result, SAMPLE.replace("\tbare = false ; trailing\n", ""));
+    }
+
+    #[test]
+    fn unset_on_header_line() {
+        let contents = "[core] bare = true\n[user]\n\tname = A\n";
+        let result = edit("config-unset-header", contents, |p| unset_value(p, "core.bare"));
+        assert_eq!(result, "[core]\n[user]\n\tname = A\n");
+    }
+
+    #[test]
+    fn values_read_back() {
+        let dir = TempDir::new("config-read");
+        let path = dir.path.join("config");
+        fs::write(&path, "[Core]\n\tBare\n\tFileMode = no\n\
+                          [Section \"Sub\"]\n\tkey = multi \\\n  line\n\
+                          \tquoted = \" a \\\"b\\\" ; c \" # comment\n\
+                          \tsize = 2k\n\tsize = 3m\n").unwrap();
+        let config = load_path(&path).unwrap();
+        assert_eq!(config.get_bool("core.bare").unwrap(), Some(true));
+        assert_eq!(config.get_bool("CORE.filemode").unwrap(), Some(false));
+        assert_eq!(config.get("section.Sub.KEY"), Some("multi   line"));
+        assert_eq!(config.get("section.sub.key"), None);
+        assert_eq!(config.get("section.Sub.quoted"), Some(" a \"b\" ; c "));
+        assert_eq!(config.get_all("section.Sub.size"), vec!["2k", "3m"]);
+        assert_eq!(config.get_int("section.Sub.size").unwrap(), Some(3 * 1024 * 1024));
+        assert!(config.get_bool("section.Sub.key").is_err());
+    }
+
+    #[test]
+    fn set_values_read_back() {
+        let dir = TempDir::new("config-round-trip");
+        let path = dir.path.join("config");
+        let values = [" leading", "trailing ", "semi;colon", "hash#", "back\\slash",
+                      "new\nline", "tab\there", "\"quotes\""];
+        for (i, value) in values.iter().enumerate() {
+            set_value(&path, &format!("test.key{}", i), value).unwrap();
+        }
+        let config = load_path(&path).unwrap();
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(config.get(&format!("test.key{}", i)), Some(*value));
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone};
+use types::{GitError, GitResult};
+
+fn local_now() -> DateTime<FixedOffset> {
+    let now = Local::now();
+    now.with_timezone(now.offset())
+}
+
+// Parse "<n>.<unit>.ago" or "<n> <unit> ago" into how long ago it was
+fn parse_relative(s: &str) -> Option<Duration> {
+    let parts: Vec<&str> = s.split(['.', ' '])
+                            .filter(|p| !p.is_empty()).collect();
+    if parts.len() != 3 || parts[2] != "ago" {
+        return None;
+    }
+    let count = match parts[0].parse::<i64>() {
+        Ok(n) => n,
+        Err(_) => return None,
+    };
+    match parts[1].trim_end_matches('s') {
+        "second" => Some(Duration::seconds(count)),
+        "minute" => Some(Duration::minutes(count)),
+        "hour" => Some(Duration::hours(count)),
+        "day" => Some(Duration::days(count)),
+        "week" => Some(Duration::weeks(count)),
+        "month" => Some(Duration::days(count * 30)),
+        "year" => Some(Duration::days(count * 365)),
+        _ => None,
+    }
+}
+
+// Parse git's internal "<unix timestamp> <offset>" format, optionally
+// with an "@" before the timestamp
+fn parse_raw(s: &str) -> Option<DateTime<FixedOffset>> {
+    let s = s.strip_prefix('@').unwrap_or(s);
+    let mut parts = s.split(' ');
+    let timestamp = match parts.next().map(|t| t.parse::<i64>()) {
+        Some(Ok(t)) => t,
+        _ => return None,
+    };
+    let offset = match parts.next() {
+        Some(tz) => match DateTime::parse_from_str(&format!("0 {}", tz), "%s %z") {
+            Ok(d) => *d.offset(),
+            Err(_) => return None,
+        },
+        None => FixedOffset::east_opt(0).unwrap(),
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    offset.timestamp_opt(timestamp, 0).single()
+}
+
+// Parse a date the way git accepts it in GIT_AUTHOR_DATE and options like
+// log --since: "now", relative dates, unix timestamps with an optional
+// offset, RFC 2822 and ISO 8601 dates (in local time unless an offset is
+// given)
+pub fn parse_date(s: &str) -> GitResult<DateTime<FixedOffset>> {
+    let s = s.trim();
+    if s == "now" {
+        return Ok(local_now());
+    }
+    if let Some(date) = parse_raw(s) {
+        return Ok(date);
+    }
+    if let Ok(date) = DateTime::parse_from_rfc2822(s) {
+        return Ok(date);
+    }
+    if let Some(ago) = parse_relative(s) {
+        return Ok(local_now() - ago);
+    }
+
+    for format in ["%Y-%m-%d %H:%M:%S %z", "%Y-%m-%dT%H:%M:%S%z",
+                   "%Y-%m-%dT%H:%M:%S %z"].iter() {
+        if let Ok(date) = DateTime::parse_from_str(s, format) {
+            return Ok(date);
+        }
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"].iter()
+        .filter_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .next()
+        .or_else(|| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()
+                     .and_then(|d| d.and_hms_opt(0, 0, 0)));
+    match naive.and_then(|n| Local.from_local_datetime(&n).earliest()) {
+        Some(date) => Ok(date.with_timezone(date.offset())),
+        None => Err(GitError::from("Invalid date")),
+    }
+}
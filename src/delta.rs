@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use types::{GitError, GitResult};
+
+// Read one of the little-endian size varints at the start of a delta
+fn read_size(delta: &[u8], pos: &mut usize) -> GitResult<usize> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = match delta.get(*pos) {
+            Some(b) => *b,
+            None => return Err(GitError::from("Truncated delta")),
+        };
+        *pos += 1;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+// Rebuild an object from its base and a delta's copy/insert instructions
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> GitResult<Vec<u8>> {
+    let mut pos = 0;
+    let base_size = read_size(delta, &mut pos)?;
+    if base_size != base.len() {
+        return Err(GitError::from("Delta base size mismatch"));
+    }
+    let result_size = read_size(delta, &mut pos)?;
+    let mut result = Vec::with_capacity(result_size);
+
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        if op & 0x80 != 0 {
+            // Copy from the base, the low bits say which offset and size
+            // bytes are present
+            let mut args = [0usize; 7];
+            for (i, arg) in args.iter_mut().enumerate() {
+                if op & (1 << i) != 0 {
+                    *arg = match delta.get(pos) {
+                        Some(b) => *b as usize,
+                        None => return Err(GitError::from("Truncated delta")),
+                    };
+                    pos += 1;
+                }
+            }
+            let offset = args[0] | args[1] << 8 | args[2] << 16 | args[3] << 24;
+            let mut size = args[4] | args[5] << 8 | args[6] << 16;
+            if size == 0 {
+                size = 0x10000;
+            }
+            if offset + size > base.len() {
+                return Err(GitError::from("Delta copy out of range"));
+            }
+            result.extend_from_slice(&base[offset..offset + size]);
+        } else if op != 0 {
+            // Insert the next op bytes literally
+            let size = op as usize;
+            if pos + size > delta.len() {
+                return Err(GitError::from("Truncated delta"));
+            }
+            result.extend_from_slice(&delta[pos..pos + size]);
+            pos += size;
+        } else {
+            return Err(GitError::from("Invalid delta opcode"));
+        }
+    }
+
+    if result.len() != result_size {
+        return Err(GitError::from("Delta result size mismatch"));
+    }
+    Ok(result)
+}
+
+// Delta index block size, matches shorter than this aren't worth a copy
+const BLOCK_SIZE: usize = 16;
+
+// Largest size a single copy instruction can encode compatibly
+const MAX_COPY_SIZE: usize = 0x10000;
+
+fn write_size(out: &mut Vec<u8>, mut size: usize) {
+    while size >= 0x80 {
+        out.push((size & 0x7f) as u8 | 0x80);
+        size >>= 7;
+    }
+    out.push(size as u8);
+}
+
+fn flush_insert(out: &mut Vec<u8>, pending: &mut Vec<u8>) {
+    for chunk in pending.chunks(0x7f) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+    pending.clear();
+}
+
+fn write_copy(out: &mut Vec<u8>, mut offset: usize, mut size: usize) {
+    while size > 0 {
+        let chunk = if size > MAX_COPY_SIZE { MAX_COPY_SIZE } else { size };
+        let op_pos = out.len();
+        let mut op = 0x80;
+        out.push(0);
+        for i in 0..4 {
+            let byte = (offset >> (i * 8)) & 0xff;
+            if byte != 0 {
+                op |= 1 << i;
+                out.push(byte as u8);
+            }
+        }
+        // A size of 0x10000 is encoded by leaving out every size byte
+        let encoded_size = if chunk == MAX_COPY_SIZE { 0 } else { chunk };
+        for i in 0..3 {
+            let byte = (encoded_size >> (i * 8)) & 0xff;
+            if byte != 0 {
+                op |= 1 << (4 + i);
+                out.push(byte as u8);
+            }
+        }
+        out[op_pos] = op;
+        offset += chunk;
+        size -= chunk;
+    }
+}
+
+// Build a delta which turns base into target
+pub fn create_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_size(&mut out, base.len());
+    write_size(&mut out, target.len());
+
+    // Index every block of the base by its contents
+    let mut blocks: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    let mut pos = 0;
+    while pos + BLOCK_SIZE <= base.len() {
+        blocks.entry(&base[pos..pos + BLOCK_SIZE]).or_default().push(pos);
+        pos += BLOCK_SIZE;
+    }
+
+    let mut pending = Vec::new();
+    let mut pos = 0;
+    while pos < target.len() {
+        // Find the longest match for the block starting here
+        let mut best = (0, 0);
+        if pos + BLOCK_SIZE <= target.len() {
+            if let Some(candidates) = blocks.get(&target[pos..pos + BLOCK_SIZE]) {
+                for &start in candidates {
+                    let mut len = 0;
+                    while start + len < base.len() && pos + len < target.len()
+                            && base[start + len] == target[pos + len] {
+                        len += 1;
+                    }
+                    if len > best.1 {
+                        best = (start, len);
+                    }
+                }
+            }
+        }
+
+        if best.1 < BLOCK_SIZE {
+            pending.push(target[pos]);
+            pos += 1;
+            continue;
+        }
+
+        // Grow the match backwards over bytes we were going to insert
+        let (mut start, mut len) = best;
+        while start > 0 && !pending.is_empty()
+                && base[start - 1] == pending[pending.len() - 1] {
+            start -= 1;
+            len += 1;
+            pending.pop();
+        }
+        flush_insert(&mut out, &mut pending);
+        write_copy(&mut out, start, len);
+        pos += best.1;
+    }
+    flush_insert(&mut out, &mut pending);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bytes that won't repeat by accident, from a simple LCG
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    fn round_trip(base: &[u8], target: &[u8]) -> Vec<u8> {
+        let delta = create_delta(base, target);
+        assert_eq!(apply_delta(base, &delta).unwrap(), target);
+        delta
+    }
+
+    #[test]
+    fn round_trips() {
+        let base = noise(5000, 1);
+        let mut edited = base.clone();
+        edited[100..120].copy_from_slice(&noise(20, 2));
+        edited.splice(3000..3000, noise(300, 3));
+        edited.truncate(4500);
+
+        round_trip(b"", b"");
+        round_trip(b"", b"all new");
+        round_trip(b"nothing in common", b"");
+        round_trip(&base, &base);
+        round_trip(&base, &edited);
+        round_trip(&edited, &base);
+        round_trip(&base, &noise(5000, 4));
+    }
+
+    #[test]
+    fn similar_objects_make_small_deltas() {
+        let base = noise(5000, 1);
+        let mut target = base.clone();
+        target[2500] ^= 0xff;
+        assert!(round_trip(&base, &target).len() < 100);
+        // Matches are extended backwards over data that would be inserted
+        let mut shifted = vec![b'x'];
+        shifted.extend_from_slice(&base);
+        assert!(round_trip(&base, &shifted).len() < 20);
+    }
+
+    #[test]
+    fn large_copies_are_split() {
+        // More than one copy instruction's worth, with a copy of exactly
+        // 0x10000 which leaves out every size byte
+        let base = noise(3 * MAX_COPY_SIZE + 100, 5);
+        let delta = round_trip(&base, &base);
+        // After the two 3-byte sizes, the first copy is from offset 0, so
+        // it's just an opcode
+        assert_eq!(delta[6], 0x80);
+        assert!(delta.len() < 30);
+    }
+
+    #[test]
+    fn apply_known_delta() {
+        // Base size 11, result size 12, copy 5 bytes from offset 6, insert
+        // " big", copy 6 bytes from offset 0 (offset byte left out)
+        let delta = [11, 15, 0x91, 6, 5, 4, b' ', b'b', b'i', b'g', 0x90, 6];
+        assert_eq!(apply_delta(b"hello world", &delta).unwrap(), b"world bighello ");
+    }
+
+    #[test]
+    fn apply_rejects_bad_deltas() {
+        let base = b"hello world";
+        // Wrong base size
+        assert!(apply_delta(base, &[10, 5, 0x90, 5]).is_err());
+        // Result shorter than it claims
+        assert!(apply_delta(base, &[11, 6, 0x90, 5]).is_err());
+        // Copy past the end of the base
+        assert!(apply_delta(base, &[11, 5, 0x91, 8, 5]).is_err());
+        // Insert running past the end of the delta
+        assert!(apply_delta(base, &[11, 5, 5, b'a']).is_err());
+        // Reserved opcode
+        assert!(apply_delta(base, &[11, 0, 0]).is_err());
+        // Truncated size
+        assert!(apply_delta(base, &[0x8b]).is_err());
+    }
+}
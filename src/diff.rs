@@ -0,0 +1,394 @@
+// The diff command: compare the work tree, the index and commits, and print
+// what changed as a unified diff in git's format
+//
+// Without commits, the index is compared with the work tree, or with
+// --cached, a commit (HEAD by default) with the index. One commit is
+// compared with the work tree, and two with each other.
+
+use cache::{read_obj, ObjectType};
+use config;
+use index::{self, Index, IndexEntry};
+use parse;
+use pathspec::Pathspec;
+use refs;
+use repo::Repository;
+use revision;
+use status;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use tree::{self, EntryMode};
+use types::{GitError, GitResult};
+use xdiff::{self, Algorithm, Change};
+
+pub struct DiffOptions {
+    // Zero, one or two commits to compare
+    pub commits: Vec<String>,
+    // Compare with the index rather than the work tree
+    pub cached: bool,
+    // Lines of context around changes, or diff.context's when not given
+    pub context: Option<usize>,
+    // How to find the changes, or diff.algorithm's when not given
+    pub algorithm: Option<Algorithm>,
+}
+
+// One side of a path's diff
+struct Side {
+    mode: EntryMode,
+    hash: [u8; 20],
+    // A work tree file to read, when the contents aren't in a blob
+    path: Option<PathBuf>,
+}
+
+type Files = BTreeMap<Vec<u8>, Side>;
+
+// Git treats anything with a NUL near the start as binary
+const FIRST_FEW_BYTES: usize = 8000;
+// The longest function name shown in hunk headers
+const FUNC_NAME_MAX: usize = 80;
+
+fn tree_files(repo: &Repository, commit: &str) -> GitResult<Files> {
+    let tree = revision::peel(repo, &revision::resolve(repo, commit)?, ObjectType::Tree)?;
+    Ok(tree::read_recursive(repo, &tree)?.into_iter()
+       .map(|(name, (mode, hash))| (name, Side { mode, hash, path: None }))
+       .collect())
+}
+
+// HEAD's files, or none on an unborn branch
+fn head_files(repo: &Repository) -> GitResult<Files> {
+    match refs::read_ref(repo, "HEAD") {
+        Ok(head) => tree_files(repo, &head),
+        Err(GitError::IoError(ref err)) if err.kind() == io::ErrorKind::NotFound => {
+            Ok(BTreeMap::new())
+        },
+        Err(err) => Err(err),
+    }
+}
+
+// The merged index entries. Intent-to-add entries aren't really there yet.
+fn index_files(ndx: &Index) -> Files {
+    ndx.entries.iter()
+        .filter(|&(&(_, stage), entry)| stage == 0 && !entry.intent_to_add)
+        .map(|((name, _), entry)| {
+            (name.clone(), Side { mode: entry.mode, hash: entry.hash, path: None })
+        })
+        .collect()
+}
+
+// What the work tree has for a path, if anything. Files whose stat data
+// matches their index entry aren't read; conflicted paths have no entry to
+// match.
+fn worktree_side(repo: &Repository, ndx: &Index, name: &[u8], entry: Option<&IndexEntry>)
+        -> GitResult<Option<Side>> {
+    match entry {
+        // Sparse checkouts leave these out of the work tree on purpose
+        Some(entry) if entry.skip_worktree => {
+            return Ok(Some(Side { mode: entry.mode, hash: entry.hash, path: None }));
+        },
+        _ => (),
+    }
+    let path = repo.work_tree()?.join(OsStr::from_bytes(name));
+    match fs::symlink_metadata(&path) {
+        Ok(ref meta) => match (index::mode_from_metadata(meta), entry) {
+            (Some(mode), Some(entry)) if !entry.intent_to_add && ndx.is_unchanged(entry, meta) => {
+                Ok(Some(Side { mode, hash: entry.hash, path: None }))
+            },
+            (Some(mode), _) => {
+                let hash = status::hash_file(&path, meta)?;
+                Ok(Some(Side { mode, hash, path: Some(path) }))
+            },
+            // Replaced with a directory
+            (None, _) => Ok(None),
+        },
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound
+            || err.kind() == io::ErrorKind::NotADirectory => Ok(None),
+        Err(err) => Err(GitError::from(err)),
+    }
+}
+
+fn worktree_files(repo: &Repository, ndx: &Index) -> GitResult<Files> {
+    let mut files = BTreeMap::new();
+    for (&(ref name, stage), entry) in ndx.entries.iter() {
+        if files.contains_key(name) {
+            continue;
+        }
+        let entry = if stage == 0 { Some(entry) } else { None };
+        if let Some(side) = worktree_side(repo, ndx, name, entry)? {
+            files.insert(name.clone(), side);
+        }
+    }
+    Ok(files)
+}
+
+fn contents(repo: &Repository, side: &Side) -> GitResult<Vec<u8>> {
+    match side.path {
+        Some(ref path) if side.mode == EntryMode::Symlink => {
+            Ok(path.read_link()?.as_os_str().as_bytes().to_vec())
+        },
+        Some(ref path) => Ok(fs::read(path)?),
+        None => Ok(read_obj(repo, &parse::bytes_to_hex(&side.hash))?.data),
+    }
+}
+
+fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(FIRST_FEW_BYTES)].contains(&0)
+}
+
+// A path as it appears in headers, quoted if it needs to be
+fn header_name(prefix: &str, name: &[u8]) -> String {
+    let mut full = prefix.as_bytes().to_vec();
+    full.extend_from_slice(name);
+    status::quote_path(&full, false)
+}
+
+// The text for a hunk header if a line starts a function, the way git's
+// default funcname pattern finds one: anything starting with a letter, "_"
+// or "$"
+fn func_name(line: &[u8]) -> Option<&[u8]> {
+    match line.first() {
+        Some(&c) if c.is_ascii_alphabetic() || c == b'_' || c == b'$' => {
+            let mut len = line.len().min(FUNC_NAME_MAX);
+            while len > 0 && is_space(line[len - 1]) {
+                len -= 1;
+            }
+            Some(&line[..len])
+        },
+        _ => None,
+    }
+}
+
+// Whitespace as C's isspace sees it
+fn is_space(c: u8) -> bool {
+    c == b' ' || (b'\t'..=b'\r').contains(&c)
+}
+
+fn write_line<W: Write>(out: &mut W, prefix: &[u8], line: &[u8]) -> io::Result<()> {
+    out.write_all(prefix)?;
+    out.write_all(line)?;
+    if !line.ends_with(b"\n") {
+        out.write_all(b"\n\\ No newline at end of file\n")?;
+    }
+    Ok(())
+}
+
+// "start,count" for a hunk header, where an empty range starts at the line
+// before it and a count of one is left out
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+// Print changes as hunks with context lines around them. Changes close
+// enough for their context to touch share a hunk.
+pub fn write_hunks<W: Write>(out: &mut W, lines1: &[&[u8]], lines2: &[&[u8]], changes: &[Change],
+                   context: usize) -> io::Result<()> {
+    let mut func_line: &[u8] = b"";
+    // Where the search for a function line stopped last time
+    let mut func_searched: isize = -1;
+    let mut ndx = 0;
+    while ndx < changes.len() {
+        let first = ndx;
+        while ndx + 1 < changes.len()
+                && changes[ndx + 1].i1 - (changes[ndx].i1 + changes[ndx].chg1) <= 2 * context {
+            ndx += 1;
+        }
+        let (xch, xche) = (changes[first], changes[ndx]);
+        ndx += 1;
+
+        let s1 = xch.i1.saturating_sub(context);
+        let s2 = xch.i2.saturating_sub(context);
+        let lctx = context.min(lines1.len() - (xche.i1 + xche.chg1))
+            .min(lines2.len() - (xche.i2 + xche.chg2));
+        let e1 = xche.i1 + xche.chg1 + lctx;
+        let e2 = xche.i2 + xche.chg2 + lctx;
+
+        // The nearest function line before the hunk, or the last one found
+        let mut l = s1 as isize - 1;
+        while l > func_searched {
+            match func_name(lines1[l as usize]) {
+                Some(name) => {
+                    func_line = name;
+                    break;
+                },
+                None => l -= 1,
+            }
+        }
+        func_searched = s1 as isize - 1;
+
+        write!(out, "@@ -{} +{} @@", hunk_range(s1, e1 - s1), hunk_range(s2, e2 - s2))?;
+        if !func_line.is_empty() {
+            out.write_all(b" ")?;
+            out.write_all(func_line)?;
+        }
+        out.write_all(b"\n")?;
+
+        for line in &lines2[s2..xch.i2] {
+            write_line(out, b" ", line)?;
+        }
+        for (i, change) in changes[first..ndx].iter().enumerate() {
+            if i > 0 {
+                let prev = changes[first + i - 1];
+                for line in &lines2[prev.i2 + prev.chg2..change.i2] {
+                    write_line(out, b" ", line)?;
+                }
+            }
+            for line in &lines1[change.i1..change.i1 + change.chg1] {
+                write_line(out, b"-", line)?;
+            }
+            for line in &lines2[change.i2..change.i2 + change.chg2] {
+                write_line(out, b"+", line)?;
+            }
+        }
+        for line in &lines2[xche.i2 + xche.chg2..e2] {
+            write_line(out, b" ", line)?;
+        }
+    }
+    Ok(())
+}
+
+// Print the diff for one path, from the old side to the new
+fn write_file_diff<W: Write>(out: &mut W, repo: &Repository, name: &[u8], old: Option<&Side>,
+                   new: Option<&Side>, context: usize, algorithm: Algorithm) -> GitResult<()> {
+    let is_link = |side: &Side| side.mode == EntryMode::Symlink;
+    match (old, new) {
+        (Some(old), Some(new)) if old.mode == new.mode && old.hash == new.hash => return Ok(()),
+        // A file that became a symlink or back is shown as a deletion and an
+        // addition
+        (Some(o), Some(n)) if is_link(o) != is_link(n) => {
+            write_file_diff(out, repo, name, old, None, context, algorithm)?;
+            return write_file_diff(out, repo, name, None, new, context, algorithm);
+        },
+        (None, None) => return Ok(()),
+        _ => (),
+    }
+
+    let a_name = header_name("a/", name);
+    let b_name = header_name("b/", name);
+    writeln!(out, "diff --git {} {}", a_name, b_name)?;
+    match (old, new) {
+        (None, Some(new)) => writeln!(out, "new file mode {:06o}", new.mode.bits())?,
+        (Some(old), None) => writeln!(out, "deleted file mode {:06o}", old.mode.bits())?,
+        (Some(old), Some(new)) if old.mode != new.mode => {
+            writeln!(out, "old mode {:06o}", old.mode.bits())?;
+            writeln!(out, "new mode {:06o}", new.mode.bits())?;
+        },
+        _ => (),
+    }
+    let hash_of = |side: Option<&Side>| side.map(|s| s.hash).unwrap_or([0; 20]);
+    let (old_hash, new_hash) = (hash_of(old), hash_of(new));
+    if old_hash == new_hash {
+        // Only the mode changed
+        return Ok(());
+    }
+    let (old_hex, new_hex) = (parse::bytes_to_hex(&old_hash), parse::bytes_to_hex(&new_hash));
+    write!(out, "index {}..{}", &old_hex[..7], &new_hex[..7])?;
+    match (old, new) {
+        (Some(old), Some(new)) if old.mode == new.mode => {
+            write!(out, " {:06o}", old.mode.bits())?;
+        },
+        _ => (),
+    }
+    writeln!(out)?;
+
+    let old_data = match old {
+        Some(side) => contents(repo, side)?,
+        None => Vec::new(),
+    };
+    let new_data = match new {
+        Some(side) => contents(repo, side)?,
+        None => Vec::new(),
+    };
+    let a_name = if old.is_some() { a_name } else { String::from("/dev/null") };
+    let b_name = if new.is_some() { b_name } else { String::from("/dev/null") };
+    if is_binary(&old_data) || is_binary(&new_data) {
+        writeln!(out, "Binary files {} and {} differ", a_name, b_name)?;
+        return Ok(());
+    }
+
+    let lines1 = xdiff::split_lines(&old_data);
+    let lines2 = xdiff::split_lines(&new_data);
+    let changes = xdiff::diff(&lines1, &lines2, algorithm);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    // Names with spaces get a tab after them, so patch can find their end
+    let tab = if name.contains(&b' ') { "\t" } else { "" };
+    writeln!(out, "--- {}{}", a_name, if old.is_some() { tab } else { "" })?;
+    writeln!(out, "+++ {}{}", b_name, if new.is_some() { tab } else { "" })?;
+    write_hunks(out, &lines1, &lines2, &changes, context)?;
+    Ok(())
+}
+
+pub fn diff(repo: &Repository, specs: &[Pathspec], opts: &DiffOptions) -> GitResult<()> {
+    let cfg = config::load(Some(repo))?;
+    let context = match opts.context {
+        Some(context) => context,
+        None => match cfg.get_int("diff.context")? {
+            Some(context) if context >= 0 => context as usize,
+            Some(_) => return Err(GitError::from("bad config variable 'diff.context'")),
+            None => 3,
+        },
+    };
+    let algorithm = match (opts.algorithm, cfg.get("diff.algorithm")) {
+        (Some(algorithm), _) => algorithm,
+        (None, Some(name)) => match Algorithm::from_name(name) {
+            Some(algorithm) => algorithm,
+            None => {
+                return Err(GitError::from(format!("unknown value for config 'diff.algorithm': {}",
+                                                  name)));
+            },
+        },
+        (None, None) => Algorithm::Myers,
+    };
+
+    // Conflicted paths have no single index entry to compare
+    let mut unmerged = BTreeSet::new();
+    let (old, new) = match (opts.commits.len(), opts.cached) {
+        (0, false) | (1, false) | (0, true) | (1, true) => {
+            let ndx = index::read(repo)?;
+            // A commit can still be compared with the work tree
+            if opts.cached || opts.commits.is_empty() {
+                unmerged.extend(ndx.entries.keys()
+                                .filter(|&&(_, stage)| stage != 0)
+                                .map(|(name, _)| name.clone()));
+            }
+            let old = match opts.commits.first() {
+                Some(commit) => tree_files(repo, commit)?,
+                None if opts.cached => head_files(repo)?,
+                None => index_files(&ndx),
+            };
+            let new = if opts.cached {
+                index_files(&ndx)
+            } else {
+                worktree_files(repo, &ndx)?
+            };
+            (old, new)
+        },
+        (2, false) => (tree_files(repo, &opts.commits[0])?, tree_files(repo, &opts.commits[1])?),
+        _ => return Err(GitError::from("Too many commits to diff")),
+    };
+
+    let mut names: BTreeSet<&Vec<u8>> = old.keys().collect();
+    names.extend(new.keys());
+    names.extend(unmerged.iter());
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for name in names {
+        if !specs.is_empty() && !specs.iter().any(|spec| spec.matches(name)) {
+            continue;
+        }
+        if unmerged.contains(name) {
+            writeln!(out, "* Unmerged path {}", status::quote_path(name, false))?;
+            continue;
+        }
+        write_file_diff(&mut out, repo, name, old.get(name), new.get(name), context, algorithm)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use cache::{self, ObjectType, read_obj};
+use commit;
+use index;
+use pack;
+use parse;
+use refs;
+use repo::Repository;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tag;
+use tree::{self, EntryMode};
+use types::{GitError, GitResult};
+
+// Unreachable loose objects younger than this are kept, since another
+// command may be about to reference them
+pub const DEFAULT_PRUNE_GRACE: u64 = 14 * 24 * 60 * 60;
+
+// The old and new values of every entry in every reflog under dir
+fn reflog_hashes(dir: &Path, hashes: &mut Vec<[u8; 20]>) -> GitResult<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => return Ok(()),
+            _ => return Err(GitError::from(err)),
+        },
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            reflog_hashes(&entry.path(), hashes)?;
+            continue;
+        }
+        for line in BufReader::new(File::open(entry.path())?).lines() {
+            let line = line?;
+            // <old> <new> <committer> <timestamp> <tz>\t<message>
+            if line.len() < 81 || !line.is_char_boundary(81) {
+                return Err(GitError::from("Malformed reflog entry"));
+            }
+            for hex in [&line[..40], &line[41..81]].iter() {
+                if *hex != refs::NULL_HASH {
+                    hashes.push(parse::hex_to_hash(hex)?);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Every object reachable from the refs, HEAD, the reflogs and the index, with
+// commits and trees before the blobs they contain
+pub fn reachable_objects(repo: &Repository) -> GitResult<Vec<[u8; 20]>> {
+    let mut roots = Vec::new();
+    for (_, hash) in refs::list_refs(repo)? {
+        roots.push(parse::hex_to_hash(&hash)?);
+    }
+    match refs::read_ref(repo, "HEAD") {
+        Ok(hash) => roots.push(parse::hex_to_hash(&hash)?),
+        // An unborn branch doesn't point at anything yet
+        Err(GitError::IoError(ref err)) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err),
+    }
+    for entry in index::read(repo)?.entries.values() {
+        roots.push(entry.hash);
+    }
+    let mut logged = Vec::new();
+    reflog_hashes(&repo.path("logs"), &mut logged)?;
+    for hash in logged {
+        // Entries can outlive the objects they name, skip those rather than
+        // failing the whole walk
+        if read_obj(repo, &parse::bytes_to_hex(&hash)).is_ok() {
+            roots.push(hash);
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    let mut stack = roots;
+    while let Some(hash) = stack.pop() {
+        if !seen.insert(hash) {
+            continue;
+        }
+        result.push(hash);
+        let obj = read_obj(repo, &parse::bytes_to_hex(&hash))?;
+        match obj.kind {
+            ObjectType::Commit => {
+                let commit = commit::from_object(&obj)?;
+                stack.push(parse::hex_to_hash(&commit.tree)?);
+                for parent in commit.parents.iter() {
+                    stack.push(parse::hex_to_hash(parent)?);
+                }
+            },
+            ObjectType::Tree => {
+                for entry in tree::from_object(&obj)?.entries {
+                    match entry.mode {
+                        EntryMode::Tree => stack.push(entry.hash),
+                        // No need to read blobs, nothing hangs off them
+                        _ => if seen.insert(entry.hash) {
+                            result.push(entry.hash);
+                        },
+                    }
+                }
+            },
+            ObjectType::Tag => stack.push(tag::target(&obj)?),
+            ObjectType::Blob => (),
+        }
+    }
+    Ok(result)
+}
+
+// Every loose object, along with its path on disk
+fn loose_objects(repo: &Repository) -> GitResult<Vec<([u8; 20], PathBuf)>> {
+    let mut objects = Vec::new();
+    for dir in fs::read_dir(repo.path("objects"))? {
+        let dir = dir?;
+        let prefix = match dir.file_name().into_string() {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(dir.path())? {
+            let file = file?;
+            let name = match file.file_name().into_string() {
+                Ok(s) => format!("{}{}", prefix, s),
+                Err(_) => continue,
+            };
+            // Skip anything that isn't an object, like temporary files
+            match parse::hex_to_hash(&name) {
+                Ok(hash) => objects.push((hash, file.path())),
+                Err(_) => continue,
+            }
+        }
+    }
+    Ok(objects)
+}
+
+// Write the objects in a pack that aren't in keep out as loose objects, dated
+// with the pack's mtime so the prune grace period still applies to them
+// Objects already older than cutoff are left out, they'd only be pruned
+fn unpack_unreachable(repo: &Repository, pack: &pack::PackIndex, keep: &HashSet<[u8; 20]>,
+                      cutoff: Option<SystemTime>) -> GitResult<()> {
+    let mtime = fs::metadata(&pack.pack_path)?.modified()?;
+    match cutoff {
+        Some(cutoff) if mtime <= cutoff => return Ok(()),
+        _ => (),
+    }
+    for hash in pack.hashes() {
+        if keep.contains(hash) {
+            continue;
+        }
+        let hex = parse::bytes_to_hex(hash);
+        let path = cache::path_for_hash(repo, &hex);
+        // Leave existing loose copies alone, they have their own age
+        if path.exists() {
+            continue;
+        }
+        match pack::read_packed(repo, hash)? {
+            Some(obj) => { obj.write(repo)?; },
+            None => return Err(GitError::from("Object not found")),
+        }
+        fs::OpenOptions::new().write(true).open(&path)?.set_modified(mtime)?;
+    }
+    Ok(())
+}
+
+// Pack all reachable objects into a single pack, and remove the old packs
+// and any loose objects that are now redundant
+// Unreachable objects are kept loose, and deleted once older than prune_grace
+pub fn gc(repo: &Repository, prune_grace: Option<Duration>) -> GitResult<()> {
+    let reachable = reachable_objects(repo)?;
+    let pack_dir = repo.path("objects/pack");
+
+    let old_packs: Vec<PathBuf> = {
+        let mut paths = Vec::new();
+        match fs::read_dir(&pack_dir) {
+            Ok(dir) => for entry in dir {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "pack") {
+                    paths.push(path);
+                }
+            },
+            Err(err) => match err.kind() {
+                io::ErrorKind::NotFound => fs::create_dir(&pack_dir)?,
+                _ => return Err(GitError::from(err)),
+            },
+        }
+        paths
+    };
+
+    let packed: HashSet<[u8; 20]> = reachable.iter().cloned().collect();
+    let cutoff = prune_grace.map(|grace| SystemTime::now() - grace);
+
+    // Write the new pack before removing anything
+    let mut new_pack = None;
+    if !reachable.is_empty() {
+        let base_name = pack_dir.join("pack");
+        let digest = pack::write_pack(repo, &reachable, &base_name.to_string_lossy(), 10, 50)?;
+        new_pack = Some(pack_dir.join(format!("pack-{}.pack", digest)));
+    }
+
+    for path in old_packs {
+        if Some(&path) == new_pack.as_ref() {
+            continue;
+        }
+        let index = pack::read_index(&path.with_extension("idx"))?;
+        unpack_unreachable(repo, &index, &packed, cutoff)?;
+        fs::remove_file(path.with_extension("idx"))?;
+        fs::remove_file(&path)?;
+    }
+    pack::clear_cache();
+
+    for (hash, path) in loose_objects(repo)? {
+        if packed.contains(&hash) {
+            fs::remove_file(&path)?;
+        } else if let Some(cutoff) = cutoff {
+            if fs::metadata(&path)?.modified()? <= cutoff {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+
+    // Clean up any fan-out directories we emptied
+    for dir in fs::read_dir(repo.path("objects"))? {
+        let dir = dir?;
+        if dir.file_name().len() == 2 && dir.file_type()?.is_dir() {
+            // Fails harmlessly if the directory still has objects in it
+            let _ = fs::remove_dir(dir.path());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use chrono::{self, DateTime, FixedOffset};
+use config::Config;
+use date;
+use std::env;
+use types::{GitError, GitResult};
+
+pub enum Role {
+    Author,
+    Committer,
+}
+
+// Remove characters that would break the "Name <email>" format
+fn sanitize(s: &str) -> String {
+    s.chars().filter(|c| *c != '<' && *c != '>' && *c != '\n').collect::<String>()
+        .trim().to_string()
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok()
+}
+
+// Work out "Name <email>" and the date for the author or committer, from
+// GIT_AUTHOR_* or GIT_COMMITTER_*, then the config
+pub fn identity(role: Role, config: &Config) -> GitResult<(String, DateTime<FixedOffset>)> {
+    let (env_prefix, config_prefix, title) = match role {
+        Role::Author => ("GIT_AUTHOR", "author", "Author"),
+        Role::Committer => ("GIT_COMMITTER", "committer", "Committer"),
+    };
+
+    let name = env_var(&format!("{}_NAME", env_prefix))
+        .or_else(|| config.get(&format!("{}.name", config_prefix)).map(String::from))
+        .or_else(|| config.get("user.name").map(String::from));
+    let email = env_var(&format!("{}_EMAIL", env_prefix))
+        .or_else(|| config.get(&format!("{}.email", config_prefix)).map(String::from))
+        .or_else(|| config.get("user.email").map(String::from))
+        .or_else(|| env_var("EMAIL"));
+
+    let (name, email) = match (name, email) {
+        (Some(ref name), Some(ref email)) if !sanitize(name).is_empty() => {
+            (sanitize(name), sanitize(email))
+        },
+        _ => return Err(GitError::from(format!(
+            "{} identity unknown\n\n\
+             *** Please tell me who you are.\n\n\
+             Run\n\n  \
+             git config --global user.email \"you@example.com\"\n  \
+             git config --global user.name \"Your Name\"\n\n\
+             to set your account's default identity.\n\
+             Omit --global to set the identity only in this repository.", title))),
+    };
+
+    let date = match env_var(&format!("{}_DATE", env_prefix)) {
+        Some(value) => date::parse_date(&value)?,
+        None => {
+            let localtime = chrono::Local::now();
+            localtime.with_timezone(localtime.offset())
+        },
+    };
+
+    Ok((format!("{} <{}>", name, email), date))
+}
@@ -0,0 +1,250 @@
+// Exclude rules from .gitignore files, .git/info/exclude and core.excludesFile
+//
+// Paths are index names, relative to the top of the work tree with "/"
+// separators. Within a file the last matching pattern wins, and a
+// .gitignore beats the ones above it, which beat info/exclude, which beats
+// core.excludesFile.
+
+use config::Config;
+use repo::Repository;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use types::{GitError, GitResult};
+use wildmatch::{self, MatchFlags};
+
+pub struct Pattern {
+    // The line as written, and where it came from, for check-ignore -v
+    pub text: String,
+    pub source: String,
+    pub line: usize,
+    // A "!" pattern re-includes what an earlier one excluded
+    pub negated: bool,
+    glob: Vec<u8>,
+    // The directory of the .gitignore, with a trailing "/", or empty
+    base: Vec<u8>,
+    // A trailing "/" only matches directories
+    dir_only: bool,
+    // Patterns without a "/" match the last path component at any depth
+    basename_only: bool,
+}
+
+impl Pattern {
+    fn matches(&self, name: &[u8], is_dir: bool, ignore_case: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.basename_only {
+            let basename = match name.iter().rposition(|&c| c == b'/') {
+                Some(ndx) => &name[ndx + 1..],
+                None => name,
+            };
+            let flags = MatchFlags { pathname: false, ignore_case };
+            return wildmatch::wildmatch(&self.glob, basename, &flags);
+        }
+        if !name.starts_with(&self.base) {
+            return false;
+        }
+        let flags = MatchFlags { pathname: true, ignore_case };
+        wildmatch::wildmatch(&self.glob, &name[self.base.len()..], &flags)
+    }
+}
+
+// Trailing spaces are dropped unless they're escaped with a backslash
+fn trim_trailing_spaces(line: &[u8]) -> &[u8] {
+    let mut end = 0;
+    let mut ndx = 0;
+    while ndx < line.len() {
+        if line[ndx] == b'\\' && ndx + 1 < line.len() {
+            ndx += 2;
+            end = ndx;
+        } else {
+            ndx += 1;
+            if line[ndx - 1] != b' ' {
+                end = ndx;
+            }
+        }
+    }
+    &line[..end]
+}
+
+fn parse_patterns(contents: &[u8], source: &str, base: &[u8]) -> Vec<Pattern> {
+    let mut patterns = Vec::new();
+    for (ndx, line) in contents.split(|&c| c == b'\n').enumerate() {
+        let line = trim_trailing_spaces(line);
+        if line.is_empty() || line[0] == b'#' {
+            continue;
+        }
+        let negated = line[0] == b'!';
+        let mut glob = if negated { &line[1..] } else { line };
+        let dir_only = glob.ends_with(b"/");
+        if dir_only {
+            glob = &glob[..glob.len() - 1];
+        }
+        let basename_only = !glob.contains(&b'/');
+        if glob.starts_with(b"/") {
+            glob = &glob[1..];
+        }
+        patterns.push(Pattern {
+            text: String::from_utf8_lossy(line).into_owned(),
+            source: String::from(source),
+            line: ndx + 1,
+            negated,
+            glob: glob.to_vec(),
+            base: base.to_vec(),
+            dir_only,
+            basename_only,
+        });
+    }
+    patterns
+}
+
+// The contents of a file, or nothing if it doesn't exist
+fn read_file(path: &Path) -> GitResult<Vec<u8>> {
+    let mut contents = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            f.read_to_end(&mut contents)?;
+            Ok(contents)
+        },
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => Ok(contents),
+            _ => Err(GitError::from(err)),
+        },
+    }
+}
+
+// Where the global excludes file lives when core.excludesFile isn't set
+fn default_excludes_file() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(ref xdg) if !xdg.is_empty() => Some(PathBuf::from(xdg).join("git/ignore")),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/git/ignore")),
+    }
+}
+
+// The parent directory of an index name, or "" at the top
+fn parent(name: &[u8]) -> &[u8] {
+    match name.iter().rposition(|&c| c == b'/') {
+        Some(ndx) => &name[..ndx],
+        None => b"",
+    }
+}
+
+pub struct Ignores {
+    work_tree: PathBuf,
+    ignore_case: bool,
+    patterns: Vec<Pattern>,
+    // Indexes into patterns of each directory's .gitignore, loaded as needed
+    dirs: HashMap<Vec<u8>, Vec<usize>>,
+    // info/exclude, then core.excludesFile
+    fallback: Vec<Vec<usize>>,
+}
+
+impl Ignores {
+    pub fn new(repo: &Repository, cfg: &Config) -> GitResult<Ignores> {
+        let work_tree = repo.work_tree()?.to_path_buf();
+        let mut ignores = Ignores {
+            ignore_case: cfg.get_bool("core.ignorecase")?.unwrap_or(false),
+            patterns: Vec::new(),
+            dirs: HashMap::new(),
+            fallback: Vec::new(),
+            work_tree,
+        };
+
+        let info_exclude = repo.path("info/exclude");
+        let source = match info_exclude.strip_prefix(&ignores.work_tree) {
+            Ok(rel) => rel.to_path_buf(),
+            Err(_) => info_exclude.clone(),
+        };
+        let info = ignores.load_file(&info_exclude, &source.to_string_lossy(), b"")?;
+        ignores.fallback.push(info);
+        if let Some(path) = cfg.get_path("core.excludesfile").or_else(default_excludes_file) {
+            let global = ignores.load_file(&path, &path.to_string_lossy(), b"")?;
+            ignores.fallback.push(global);
+        }
+        Ok(ignores)
+    }
+
+    fn load_file(&mut self, path: &Path, source: &str, base: &[u8]) -> GitResult<Vec<usize>> {
+        let start = self.patterns.len();
+        let patterns = parse_patterns(&read_file(path)?, source, base);
+        self.patterns.extend(patterns);
+        Ok((start..self.patterns.len()).collect())
+    }
+
+    // Read the .gitignore in a directory, if we haven't already
+    fn load_dir(&mut self, dir: &[u8]) -> GitResult<()> {
+        if self.dirs.contains_key(dir) {
+            return Ok(());
+        }
+        let dir_str = String::from_utf8_lossy(dir).into_owned();
+        let (source, base) = if dir.is_empty() {
+            (String::from(".gitignore"), Vec::new())
+        } else {
+            (format!("{}/.gitignore", dir_str), format!("{}/", dir_str).into_bytes())
+        };
+        let path = self.work_tree.join(&source);
+        let ndxs = self.load_file(&path, &source, &base)?;
+        self.dirs.insert(dir.to_vec(), ndxs);
+        Ok(())
+    }
+
+    // The last pattern matching a path, checking the closest .gitignore first
+    fn find(&self, name: &[u8], is_dir: bool) -> Option<usize> {
+        let mut lists = Vec::new();
+        let mut dir = parent(name);
+        loop {
+            if let Some(list) = self.dirs.get(dir) {
+                lists.push(list);
+            }
+            if dir.is_empty() {
+                break;
+            }
+            dir = parent(dir);
+        }
+        lists.extend(self.fallback.iter());
+
+        for list in lists {
+            for &ndx in list.iter().rev() {
+                if self.patterns[ndx].matches(name, is_dir, self.ignore_case) {
+                    return Some(ndx);
+                }
+            }
+        }
+        None
+    }
+
+    // The pattern deciding whether a path is ignored, which may be a
+    // negated one that re-includes it
+    pub fn matching(&mut self, name: &[u8], is_dir: bool) -> GitResult<Option<&Pattern>> {
+        if name.is_empty() {
+            return Ok(None);
+        }
+        // Nothing inside an excluded directory can be re-included, so each
+        // leading directory is checked before the path itself
+        let mut start = 0;
+        let found = loop {
+            let (prefix, prefix_is_dir) = match name[start..].iter().position(|&c| c == b'/') {
+                Some(ndx) => (&name[..start + ndx], true),
+                None => (name, is_dir),
+            };
+            self.load_dir(parent(prefix))?;
+            let found = self.find(prefix, prefix_is_dir);
+            if prefix.len() == name.len() {
+                break found;
+            }
+            match found {
+                Some(ndx) if !self.patterns[ndx].negated => break found,
+                _ => (),
+            }
+            start = prefix.len() + 1;
+        };
+        Ok(found.map(move |ndx| &self.patterns[ndx]))
+    }
+
+    pub fn is_ignored(&mut self, name: &[u8], is_dir: bool) -> GitResult<bool> {
+        Ok(self.matching(name, is_dir)?.is_some_and(|p| !p.negated))
+    }
+}
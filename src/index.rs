@@ -1,21 +1,67 @@
 extern crate byteorder;
 
 use cache::{Object, ObjectType};
+use cachetree::{self, CacheTree};
+use config;
 use parse;
+use repo::Repository;
 use self::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use sha1::{Digest, Sha1};
 use std::collections::BTreeMap;
-use std::fs::{File, Metadata};
-use std::io::{self, Read, Write};
+use std::collections::btree_map::Range;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Cursor, Read, Write};
 use std::os::unix::fs::MetadataExt;
-use std::os::unix::ffi::OsStringExt;
-use std::path::Path;
-use std::time::UNIX_EPOCH;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path};
 use tree::{EntryMode, Tree, TreeEntry};
 use types::{GitError, GitResult};
 
+// The hash of the empty blob
+const EMPTY_BLOB: [u8; 20] = [
+    0xe6, 0x9d, 0xe2, 0x9b, 0xb2, 0xd1, 0xd6, 0x43, 0x4b, 0x8b,
+    0x29, 0xae, 0x77, 0x5a, 0xd8, 0xc2, 0xe4, 0x8c, 0x53, 0x91,
+];
+
+// Index versions we can read and write. Version 3 adds extended flags, and
+// version 4 compresses each name against the one before it.
+const MIN_VERSION: u32 = 2;
+const MAX_VERSION: u32 = 4;
+
+// Bits in an entry's flags, and in the extended flags that follow them
+const FLAG_ASSUME_VALID: u16 = 0x8000;
+const FLAG_EXTENDED: u16 = 0x4000;
+const STAGE_MASK: u16 = 0x3000;
+const STAGE_SHIFT: u16 = 12;
+const FLAG_SKIP_WORKTREE: u16 = 0x4000;
+const FLAG_INTENT_TO_ADD: u16 = 0x2000;
+
+// The mode and hash of stages 1 to 3 of a conflict that has been resolved
+type ResolveUndo = BTreeMap<Vec<u8>, [Option<(u32, [u8; 20])>; 3]>;
+
+// Whatever changes entries should invalidate their paths in the cache tree
 pub struct Index {
-    pub entries: BTreeMap<Vec<u8>, IndexEntry>,
+    // Keyed by name and stage. Stage 0 is a normal entry, while a path with
+    // a merge conflict has stage 1 for the common ancestor, 2 for ours and
+    // 3 for theirs, whichever of them exist.
+    pub entries: BTreeMap<(Vec<u8>, u8), IndexEntry>,
+    // The format to write, which is the one read unless set_version changes it
+    version: u32,
+    // When the index file was last written, as (seconds, nanoseconds)
+    timestamp: Option<(u32, u32)>,
+    // The TREE extension, if there is one
+    cache_tree: Option<CacheTree>,
+    // The REUC extension: the conflicted stages of paths that have since
+    // been resolved, so the conflict could be recreated
+    resolve_undo: ResolveUndo,
+    // Optional extensions we don't understand. They may describe the
+    // entries, so they're only written back until an entry changes.
+    other_extensions: Vec<([u8; 4], Vec<u8>)>,
+    // Whether the index had an end-of-index-entries extension, which we
+    // have to recompute rather than copy
+    end_of_entries: bool,
 }
 
 pub struct IndexEntry {
@@ -30,60 +76,229 @@ pub struct IndexEntry {
     pub gid: u32,
     pub size: u32,
     pub assume_valid: bool,
+    // Set by "add -N": the path will be added, but nothing is staged yet
+    pub intent_to_add: bool,
+    // Set by sparse checkouts for files that aren't in the work tree
+    pub skip_worktree: bool,
     pub hash: [u8; 20],
 }
 
-pub fn read() -> GitResult<Index> {
-    let mut file = match File::open(".git/index") {
+impl IndexEntry {
+    fn has_extended_flags(&self) -> bool {
+        self.intent_to_add || self.skip_worktree
+    }
+}
+
+// Index names always use "/" as the separator, whatever the platform
+pub fn path_to_name(path: &Path) -> GitResult<Vec<u8>> {
+    let mut name = Vec::new();
+    for component in path.components() {
+        let part = match component {
+            Component::Normal(part) => part,
+            _ => return Err(GitError::from(format!("Invalid path '{}'", path.display()))),
+        };
+        let part = match part.to_str() {
+            Some(s) => s,
+            None => return Err(GitError::from("Invalid UTF-8 filename")),
+        };
+        if !name.is_empty() {
+            name.push(b'/');
+        }
+        name.extend_from_slice(part.as_bytes());
+    }
+    Ok(name)
+}
+
+// The version a new index is written in, from GIT_INDEX_VERSION or
+// index.version
+fn default_version(repo: &Repository) -> GitResult<u32> {
+    let version = match env::var("GIT_INDEX_VERSION") {
+        Ok(value) => value.parse::<i64>().ok(),
+        Err(_) => config::load(Some(repo))?.get_int("index.version")?,
+    };
+    match version {
+        Some(v) if v >= MIN_VERSION as i64 && v <= MAX_VERSION as i64 => Ok(v as u32),
+        Some(_) => {
+            eprintln!("warning: index.version set, but the value is invalid. Using version {}",
+                      MIN_VERSION);
+            Ok(MIN_VERSION)
+        },
+        None => Ok(MIN_VERSION),
+    }
+}
+
+// Git's variable-length integers for v4 names, as in pack OFS_DELTA offsets
+fn read_varint<R: Read>(reader: &mut R) -> GitResult<usize> {
+    let mut byte = reader.read_u8()?;
+    let mut value = (byte & 0x7f) as usize;
+    while byte & 0x80 != 0 {
+        byte = reader.read_u8()?;
+        value = ((value + 1) << 7) | (byte & 0x7f) as usize;
+    }
+    Ok(value)
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut value: usize) -> io::Result<()> {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        value -= 1;
+        bytes.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    bytes.reverse();
+    writer.write_all(&bytes)
+}
+
+// How many NULs follow a name in versions 2 and 3, to make the entry a
+// multiple of 8 bytes with at least one NUL
+fn padding_len(name_len: usize, extended: bool) -> usize {
+    let entry_len = 62 + if extended { 2 } else { 0 } + name_len;
+    ((entry_len + 8) & !7) - entry_len
+}
+
+// Each REUC entry is a name, a NUL, the three stages' modes in octal ASCII
+// each followed by a NUL, then the hashes of the stages whose mode isn't 0
+fn parse_resolve_undo(data: &[u8]) -> GitResult<ResolveUndo> {
+    let corrupt = || GitError::from("Corrupted REUC extension");
+    let mut reader = Cursor::new(data);
+    let mut resolve_undo = BTreeMap::new();
+    while (reader.position() as usize) < data.len() {
+        let name = parse::read_until(&mut reader, b'\0')?;
+        let mut modes = [0; 3];
+        for mode in modes.iter_mut() {
+            let text = parse::read_until(&mut reader, b'\0')?;
+            let text = String::from_utf8(text).map_err(|_| corrupt())?;
+            *mode = u32::from_str_radix(&text, 8).map_err(|_| corrupt())?;
+        }
+        let mut stages = [None; 3];
+        for (stage, &mode) in stages.iter_mut().zip(modes.iter()) {
+            if mode != 0 {
+                let mut hash = [0; 20];
+                reader.read_exact(&mut hash)?;
+                *stage = Some((mode, hash));
+            }
+        }
+        resolve_undo.insert(name, stages);
+    }
+    Ok(resolve_undo)
+}
+
+fn serialize_resolve_undo(resolve_undo: &ResolveUndo) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, stages) in resolve_undo.iter() {
+        out.extend_from_slice(name);
+        out.push(0);
+        for stage in stages.iter() {
+            out.extend_from_slice(format!("{:o}", stage.map_or(0, |s| s.0)).as_bytes());
+            out.push(0);
+        }
+        for stage in stages.iter() {
+            if let Some((_, ref hash)) = *stage {
+                out.extend_from_slice(hash);
+            }
+        }
+    }
+    out
+}
+
+pub fn read(repo: &Repository) -> GitResult<Index> {
+    let mut file = match File::open(repo.path("index")) {
         Ok(f) => f,
         Err(err) => match err.kind() {
             // If there is no index file, use an empty index
             io::ErrorKind::NotFound => return Ok(Index {
                 entries: BTreeMap::new(),
+                version: default_version(repo)?,
+                timestamp: None,
+                cache_tree: None,
+                resolve_undo: BTreeMap::new(),
+                other_extensions: Vec::new(),
+                end_of_entries: false,
             }),
             _ => return Err(GitError::from(err)),
         },
     };
 
-    let mut sig = vec![0; 8];
-    file.read_exact(&mut sig)?;
-    if sig != b"DIRC\0\0\0\x02" {
-        return Err(GitError::from("Bad index file signature"));
+    let meta = file.metadata()?;
+    let timestamp = (meta.mtime() as u32, meta.mtime_nsec() as u32);
+
+    // The last 20 bytes are a SHA-1 of everything before them, or all zeros
+    // if whoever wrote the file didn't bother
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    if data.len() < 32 {
+        return Err(GitError::from("index file smaller than expected"));
+    }
+    let (contents, checksum) = data.split_at(data.len() - 20);
+    if checksum != [0; 20] {
+        let mut m = Sha1::new();
+        m.update(contents);
+        if m.digest().bytes() != checksum {
+            return Err(GitError::from("index file corrupt: bad sha1 signature"));
+        }
     }
+    let mut reader = Cursor::new(contents);
 
-    let num_entries = file.read_u32::<BigEndian>()? as usize;
-    let mut entries: BTreeMap<Vec<u8>, IndexEntry> = BTreeMap::new();
+    let mut sig = vec![0; 4];
+    reader.read_exact(&mut sig)?;
+    if sig != b"DIRC" {
+        return Err(GitError::from("Bad index file signature"));
+    }
+    let version = reader.read_u32::<BigEndian>()?;
+    if !(MIN_VERSION..=MAX_VERSION).contains(&version) {
+        return Err(GitError::from(format!("Unsupported index version {}", version)));
+    }
 
-    while entries.len() < num_entries {
-        let ctime = file.read_u32::<BigEndian>()?;
-        let ctime_ns = file.read_u32::<BigEndian>()?;
-        let mtime = file.read_u32::<BigEndian>()?;
-        let mtime_ns = file.read_u32::<BigEndian>()?;
-        let dev = file.read_u32::<BigEndian>()?;
-        let ino = file.read_u32::<BigEndian>()?;
-        let mode = match file.read_u32::<BigEndian>()? {
+    let num_entries = reader.read_u32::<BigEndian>()? as usize;
+    let mut entries: BTreeMap<(Vec<u8>, u8), IndexEntry> = BTreeMap::new();
+    let mut prev_name: Vec<u8> = Vec::new();
+
+    for _ in 0..num_entries {
+        let ctime = reader.read_u32::<BigEndian>()?;
+        let ctime_ns = reader.read_u32::<BigEndian>()?;
+        let mtime = reader.read_u32::<BigEndian>()?;
+        let mtime_ns = reader.read_u32::<BigEndian>()?;
+        let dev = reader.read_u32::<BigEndian>()?;
+        let ino = reader.read_u32::<BigEndian>()?;
+        let mode = match reader.read_u32::<BigEndian>()? {
             0b1000_000_110_100_100 => EntryMode::NormalFile,
             0b1000_000_111_101_101 => EntryMode::ExecutableFile,
             0b1010_000_000_000_000 => EntryMode::Symlink,
             _ => return Err(GitError::from("Bad entry mode in index")),
         };
-        let uid = file.read_u32::<BigEndian>()?;
-        let gid = file.read_u32::<BigEndian>()?;
-        let size = file.read_u32::<BigEndian>()?;
+        let uid = reader.read_u32::<BigEndian>()?;
+        let gid = reader.read_u32::<BigEndian>()?;
+        let size = reader.read_u32::<BigEndian>()?;
 
         let mut hash = [0; 20];
-        file.read_exact(&mut hash)?;
-
-        let flags = file.read_u16::<BigEndian>()?;
-        let assume_valid = flags & 0x8000 != 0;
-        if flags & 0x4000 != 0 {
+        reader.read_exact(&mut hash)?;
+
+        let flags = reader.read_u16::<BigEndian>()?;
+        let assume_valid = flags & FLAG_ASSUME_VALID != 0;
+        let extended = flags & FLAG_EXTENDED != 0;
+        let extended_flags = if !extended {
+            0
+        } else if version >= 3 {
+            reader.read_u16::<BigEndian>()?
+        } else {
             return Err(GitError::from("Extended flag must be 0"));
-        }
-        // TODO: Actually do something with the stage
-        //let stage = (flags & 0b0011000000000000) >> 12;
+        };
+        let stage = ((flags & STAGE_MASK) >> STAGE_SHIFT) as u8;
         let name_length = (flags & 0xfff) as usize;
 
-        let name = parse::read_until(&mut file, b'\0')?;
+        let name = if version >= 4 {
+            // Drop some bytes from the end of the last name, then add a suffix
+            let strip = read_varint(&mut reader)?;
+            if strip > prev_name.len() {
+                return Err(GitError::from("Corrupted entry name"));
+            }
+            let mut name = prev_name[..prev_name.len() - strip].to_vec();
+            name.extend(parse::read_until(&mut reader, b'\0')?);
+            name
+        } else {
+            parse::read_until(&mut reader, b'\0')?
+        };
 
         // Verify name length
         if !((name.len() == name_length)
@@ -91,29 +306,75 @@ pub fn read() -> GitResult<Index> {
             return Err(GitError::from("Corrupted entry name"));
         }
 
-        // Name is padded with NUL bytes until the entry is a multiple of 8 bytes
-        let num_pad = 7 - (name.len() + 6) % 8;
-        let mut padding = vec![0; num_pad];
-        file.read_exact(&mut padding)?;
-        if padding != vec![0; num_pad] {
-            return Err(GitError::from("Found bytes in pad field"));
+        // Before version 4, names are padded with NUL bytes until the entry is
+        // a multiple of 8 bytes
+        if version < 4 {
+            let num_pad = padding_len(name.len(), extended) - 1;
+            let mut padding = vec![0; num_pad];
+            reader.read_exact(&mut padding)?;
+            if padding != vec![0; num_pad] {
+                return Err(GitError::from("Found bytes in pad field"));
+            }
         }
 
         let entry = IndexEntry {
-            ctime: ctime, ctime_ns: ctime_ns, mtime: mtime, mtime_ns: mtime_ns,
-            dev: dev, ino: ino, mode: mode, uid: uid, gid: gid, size: size,
-            assume_valid: assume_valid, hash: hash,
+            ctime, ctime_ns, mtime, mtime_ns, dev, ino, mode, uid, gid, size, assume_valid,
+            intent_to_add: extended_flags & FLAG_INTENT_TO_ADD != 0,
+            skip_worktree: extended_flags & FLAG_SKIP_WORKTREE != 0,
+            hash,
         };
-        entries.insert(name, entry);
+        prev_name = name.clone();
+        entries.insert((name, stage), entry);
     }
 
-    Ok(Index { entries: entries })
+    // Extensions follow the entries, each a signature, a size and the data
+    let mut cache_tree = None;
+    let mut resolve_undo = BTreeMap::new();
+    let mut other_extensions = Vec::new();
+    let mut end_of_entries = false;
+    while (reader.position() as usize) < contents.len() {
+        let mut signature = [0; 4];
+        reader.read_exact(&mut signature)?;
+        let size = reader.read_u32::<BigEndian>()? as usize;
+        let start = reader.position() as usize;
+        if start + size > contents.len() {
+            return Err(GitError::from("Corrupted index extension"));
+        }
+        let data = &contents[start..start + size];
+        reader.set_position((start + size) as u64);
+        match &signature {
+            b"TREE" => cache_tree = Some(cachetree::parse(data)?),
+            b"REUC" => resolve_undo = parse_resolve_undo(data)?,
+            b"EOIE" => end_of_entries = true,
+            // Entry offsets would be wrong once the entries change, and
+            // it's only a hint anyway
+            b"IEOT" => (),
+            // Extensions starting with a capital letter are optional
+            _ if signature[0] >= b'A' && signature[0] <= b'Z' => {
+                other_extensions.push((signature, data.to_vec()));
+            },
+            _ => return Err(GitError::from(format!(
+                "index uses {} extension, which we do not understand",
+                String::from_utf8_lossy(&signature)))),
+        }
+    }
+
+    Ok(Index {
+        entries,
+        version,
+        timestamp: Some(timestamp),
+        cache_tree,
+        resolve_undo,
+        other_extensions,
+        end_of_entries,
+    })
 }
 
 // Helper to track the SHA of the file's contents as we write to it
 struct HashingWriter {
     file: File,
     hash: Sha1,
+    written: usize,
 }
 
 impl HashingWriter {
@@ -124,8 +385,10 @@ impl HashingWriter {
 
 impl Write for HashingWriter {
     fn write(&mut self, data: &[u8]) -> Result<usize, io::Error> {
-        self.hash.update(data);
-        self.file.write(data)
+        let len = self.file.write(data)?;
+        self.hash.update(&data[..len]);
+        self.written += len;
+        Ok(len)
     }
 
     fn flush(&mut self) -> Result<(), io::Error> {
@@ -133,17 +396,165 @@ impl Write for HashingWriter {
     }
 }
 
+impl IndexEntry {
+    // Whether a file's stat data still matches the entry, so its contents
+    // can be assumed unchanged without reading it
+    pub fn stat_matches(&self, meta: &Metadata) -> bool {
+        // A size of 0 marks an entry whose contents must be checked, unless
+        // the file really is empty
+        if self.size == 0 && self.hash != EMPTY_BLOB {
+            return false;
+        }
+        self.mtime == meta.mtime() as u32 && self.mtime_ns == meta.mtime_nsec() as u32
+            && self.ctime == meta.ctime() as u32 && self.ctime_ns == meta.ctime_nsec() as u32
+            && self.ino == meta.ino() as u32 && self.uid == meta.uid()
+            && self.gid == meta.gid() && self.size == meta.size() as u32
+            && Some(self.mode) == mode_from_metadata(meta)
+    }
+}
+
+// The mode a file in the work tree would get in the index
+pub fn mode_from_metadata(meta: &Metadata) -> Option<EntryMode> {
+    let file_type = meta.file_type();
+    if file_type.is_symlink() {
+        Some(EntryMode::Symlink)
+    } else if !file_type.is_file() {
+        None
+    } else if meta.mode() & 0o100 != 0 {
+        Some(EntryMode::ExecutableFile)
+    } else {
+        Some(EntryMode::NormalFile)
+    }
+}
+
 impl Index {
-    // Write out to index file
-    pub fn write(&self) -> GitResult<()> {
-        let file = File::create(".git/index")?;
+    // Whether an entry was written so soon after its file was modified that
+    // the file could have changed again without its mtime changing
+    fn is_racy(&self, entry: &IndexEntry) -> bool {
+        match self.timestamp {
+            Some((sec, nsec)) => {
+                sec < entry.mtime || (sec == entry.mtime && nsec <= entry.mtime_ns)
+            },
+            None => false,
+        }
+    }
+
+    // Whether a file is known to match its entry without reading it
+    pub fn is_unchanged(&self, entry: &IndexEntry, meta: &Metadata) -> bool {
+        entry.stat_matches(meta) && !self.is_racy(entry)
+    }
+
+    // Whether a racy entry's stat data matches a file whose contents have
+    // in fact changed. Such entries are written with a size of 0, because
+    // once the index file is newer than the entry nothing else would show
+    // that the file needs checking.
+    fn is_racily_clean(&self, work_tree: Option<&Path>, name: &[u8], entry: &IndexEntry)
+            -> GitResult<bool> {
+        let path = match work_tree {
+            Some(dir) if self.is_racy(entry) => dir.join(OsStr::from_bytes(name)),
+            _ => return Ok(false),
+        };
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(_) => return Ok(false),
+        };
+        if !entry.stat_matches(&meta) {
+            return Ok(false);
+        }
+        let data = if meta.file_type().is_symlink() {
+            path.read_link()?.into_os_string().into_vec()
+        } else {
+            fs::read(&path)?
+        };
+        Ok(Object { kind: ObjectType::Blob, data }.hash().bytes() != entry.hash)
+    }
+
+    // Write the index in another format from now on, as update-index
+    // --index-version does. Version 3 still becomes 2 when no entry needs
+    // extended flags, as git does.
+    pub fn set_version(&mut self, version: u32) -> GitResult<()> {
+        if !(MIN_VERSION..=MAX_VERSION).contains(&version) {
+            return Err(GitError::from(format!("index-version {} not in range: {}..{}",
+                                              version, MIN_VERSION, MAX_VERSION)));
+        }
+        self.version = version;
+        Ok(())
+    }
+
+    // The normal, unconflicted entry for a path
+    pub fn get(&self, name: &[u8]) -> Option<&IndexEntry> {
+        self.entries.get(&(name.to_vec(), 0))
+    }
+
+    // All the entries for a path, in order of stage
+    pub fn stages(&self, name: &[u8]) -> Range<'_, (Vec<u8>, u8), IndexEntry> {
+        self.entries.range((name.to_vec(), 0)..=(name.to_vec(), 3))
+    }
+
+    // Whether the index has a path at any stage
+    pub fn contains(&self, name: &[u8]) -> bool {
+        self.stages(name).next().is_some()
+    }
+
+    pub fn has_conflicts(&self) -> bool {
+        self.entries.keys().any(|&(_, stage)| stage != 0)
+    }
+
+    // Whether the index has a path, or anything under it if it's a directory
+    pub fn is_tracked(&self, name: &[u8]) -> bool {
+        if self.contains(name) {
+            return true;
+        }
+        let mut prefix = name.to_vec();
+        prefix.push(b'/');
+        self.entries.range((prefix.clone(), 0)..).next()
+            .is_some_and(|(k, _)| k.0.starts_with(&prefix))
+    }
+
+    // Write out to index file, through index.lock so that readers never see
+    // a half-written index and two writers can't clobber each other
+    pub fn write(&self, repo: &Repository) -> GitResult<()> {
+        let path = repo.path("index");
+        let lock_path = repo.path("index.lock");
+        let file = match fs::OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+            Ok(f) => f,
+            Err(err) => match err.kind() {
+                io::ErrorKind::AlreadyExists => return Err(GitError::from(format!(
+                    "Unable to create '{}': File exists. Another git process seems \
+                     to be running in this repository", lock_path.display()))),
+                _ => return Err(GitError::from(err)),
+            },
+        };
         let hash = Sha1::new();
-        let mut w = HashingWriter {file: file, hash: hash};
+        let mut w = HashingWriter {file, hash, written: 0};
+
+        let result = self.write_contents(repo, &mut w)
+            .and_then(|_| Ok(w.file.sync_all()?))
+            .and_then(|_| Ok(fs::rename(&lock_path, &path)?));
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                let _ = fs::remove_file(&lock_path);
+                Err(err)
+            },
+        }
+    }
 
-        w.write_all(b"DIRC\0\0\0\x02")?;
+    fn write_contents(&self, repo: &Repository, w: &mut HashingWriter) -> GitResult<()> {
+        // Version 3 is only needed when some entry has extended flags
+        let version = match self.version {
+            2 | 3 if self.entries.values().any(|e| e.has_extended_flags()) => 3,
+            2 | 3 => 2,
+            version => version,
+        };
+        w.write_all(b"DIRC")?;
+        w.write_u32::<BigEndian>(version)?;
         w.write_u32::<BigEndian>(self.entries.len() as u32)?;
 
-        for (name, entry) in self.entries.iter() {
+        let work_tree = repo.work_tree().ok();
+        let mut prev_name: &[u8] = b"";
+        for (&(ref name, stage), entry) in self.entries.iter() {
+            let size = if self.is_racily_clean(work_tree, name, entry)? { 0 } else { entry.size };
             w.write_u32::<BigEndian>(entry.ctime)?;
             w.write_u32::<BigEndian>(entry.ctime_ns)?;
             w.write_u32::<BigEndian>(entry.mtime)?;
@@ -158,21 +569,76 @@ impl Index {
             })?;
             w.write_u32::<BigEndian>(entry.uid)?;
             w.write_u32::<BigEndian>(entry.gid)?;
-            w.write_u32::<BigEndian>(entry.size)?;
+            w.write_u32::<BigEndian>(size)?;
             w.write_all(&entry.hash)?;
 
-            let flags: u16 = if name.len() <= 0xfff {
+            let mut flags: u16 = if name.len() <= 0xfff {
                 name.len() as u16
             } else {
                 0xfff
             };
+            flags |= (stage as u16) << STAGE_SHIFT;
+            if entry.assume_valid {
+                flags |= FLAG_ASSUME_VALID;
+            }
+            let extended = entry.has_extended_flags();
+            if extended {
+                flags |= FLAG_EXTENDED;
+            }
             w.write_u16::<BigEndian>(flags)?;
+            if extended {
+                let mut extended_flags = 0;
+                if entry.intent_to_add {
+                    extended_flags |= FLAG_INTENT_TO_ADD;
+                }
+                if entry.skip_worktree {
+                    extended_flags |= FLAG_SKIP_WORKTREE;
+                }
+                w.write_u16::<BigEndian>(extended_flags)?;
+            }
 
-            w.write_all(&name)?;
-            // Pad entry size to a multiple of 8 bytes, with NUL's
-            let num_pad = 8 - (name.len() + 6) % 8;
-            let padding = vec![0; num_pad];
-            w.write_all(&padding)?;
+            if version >= 4 {
+                // Only write what differs from the previous name
+                let common = prev_name.iter().zip(name.iter())
+                    .take_while(|&(a, b)| a == b).count();
+                write_varint(w, prev_name.len() - common)?;
+                w.write_all(&name[common..])?;
+                w.write_all(b"\0")?;
+                prev_name = name;
+            } else {
+                w.write_all(name)?;
+                // Pad entry size to a multiple of 8 bytes, with NUL's
+                let padding = vec![0; padding_len(name.len(), extended)];
+                w.write_all(&padding)?;
+            }
+        }
+
+        // The end-of-entries extension records where the others start, and
+        // a hash of their headers
+        let extensions_start = w.written;
+        let mut headers = Sha1::new();
+        let mut extensions: Vec<(&[u8], Vec<u8>)> = Vec::new();
+        if let Some(ref tree) = self.cache_tree {
+            extensions.push((b"TREE", tree.serialize()));
+        }
+        if !self.resolve_undo.is_empty() {
+            extensions.push((b"REUC", serialize_resolve_undo(&self.resolve_undo)));
+        }
+        for (signature, data) in self.other_extensions.iter() {
+            extensions.push((signature, data.clone()));
+        }
+        for (signature, data) in extensions {
+            let mut header = signature.to_vec();
+            header.write_u32::<BigEndian>(data.len() as u32)?;
+            headers.update(&header);
+            w.write_all(&header)?;
+            w.write_all(&data)?;
+        }
+        if self.end_of_entries {
+            w.write_all(b"EOIE")?;
+            w.write_u32::<BigEndian>(24)?;
+            w.write_u32::<BigEndian>(extensions_start as u32)?;
+            w.write_all(&headers.digest().bytes())?;
         }
 
         let digest = w.digest().bytes();
@@ -181,17 +647,22 @@ impl Index {
         Ok(())
     }
 
-    pub fn add(&mut self, path: &Path, meta: &Metadata) -> GitResult<()> {
-        let name: Vec<u8> = match path.as_os_str().to_str() {
-            Some(s) => s.as_bytes().to_vec(),
-            None => return Err(GitError::from("Invalid UTF-8 filename")),
-        };
+    // Add a file, given its path relative to the top of the work tree
+    pub fn add(&mut self, repo: &Repository, path: &Path, meta: &Metadata) -> GitResult<()> {
+        let name = path_to_name(path)?;
+        let full_path = repo.work_tree()?.join(path);
+
+        // Don't bother reading files that haven't changed since they were added
+        match self.get(&name) {
+            Some(entry) if self.is_unchanged(entry, meta) => return Ok(()),
+            _ => (),
+        }
 
         // Figure out file mode and data
         let file_type = meta.file_type();
         let (mode, data) = if file_type.is_file() {
             // Regular file, just read it
-            let mut file = File::open(path)?;
+            let mut file = File::open(&full_path)?;
             let mut contents = Vec::new();
             file.read_to_end(&mut contents)?;
             if meta.mode() & 0b1_000_000 != 0 {
@@ -201,7 +672,7 @@ impl Index {
             }
         } else if file_type.is_symlink() {
             // The data is the symlink destination
-            let dest = path.read_link()?;
+            let dest = full_path.read_link()?;
             (EntryMode::Symlink, dest.into_os_string().into_vec())
         } else {
             return Err(GitError::from("Tried to add a non-file"));
@@ -210,24 +681,26 @@ impl Index {
         // Write the object and get the hash
         let hash: [u8; 20] = {
             let obj = Object { kind: ObjectType::Blob, data: data };
-            obj.write()?.bytes()
+            obj.write(repo)?.bytes()
         };
 
-        // Unpack timestamps
-        let (ctime, ctime_ns) = {
-            let duration = meta.created()?.duration_since(UNIX_EPOCH)?;
-            (duration.as_secs() as u32, duration.subsec_nanos())
-        };
-        let (mtime, mtime_ns) = {
-            let duration = meta.modified()?.duration_since(UNIX_EPOCH)?;
-            (duration.as_secs() as u32, duration.subsec_nanos())
-        };
+        self.add_entry(name, mode, hash, meta);
+        Ok(())
+    }
 
-        self.entries.insert(name, IndexEntry {
-            ctime: ctime,
-            ctime_ns: ctime_ns,
-            mtime: mtime,
-            mtime_ns: mtime_ns,
+    // Record a file whose object is already written, taking its stat data
+    // from the file's metadata
+    pub fn add_entry(&mut self, name: Vec<u8>, mode: EntryMode, hash: [u8; 20], meta: &Metadata) {
+        self.invalidate(&name);
+        self.resolve(&name);
+
+        // Like git, ctime is when the inode last changed, not when the file
+        // was created, and everything is truncated to 32 bits
+        self.entries.insert((name, 0), IndexEntry {
+            ctime: meta.ctime() as u32,
+            ctime_ns: meta.ctime_nsec() as u32,
+            mtime: meta.mtime() as u32,
+            mtime_ns: meta.mtime_nsec() as u32,
             dev: meta.dev() as u32,
             ino: meta.ino() as u32,
             mode: mode,
@@ -235,78 +708,307 @@ impl Index {
             gid: meta.gid() as u32,
             size: meta.size() as u32,
             assume_valid: false,
+            intent_to_add: false,
+            skip_worktree: false,
             hash: hash,
         });
-        Ok(())
     }
 
-    // Create trees
-    pub fn write_tree(&self) -> GitResult<Digest> {
-        // Create a stack of trees, With just the root initially
-        let mut tree_stack: Vec<(Vec<u8>, Tree)> = Vec::new();
-        tree_stack.push((b"root".to_vec(), Tree { entries: Vec::new() }));
+    // Point a path at an object without touching the work tree. The stat
+    // data is left empty, so the file will be compared by content until it's
+    // added again.
+    pub fn set_entry(&mut self, name: Vec<u8>, mode: EntryMode, hash: [u8; 20]) {
+        match self.get(&name) {
+            Some(entry) if entry.mode == mode && entry.hash == hash => return,
+            _ => (),
+        }
+        self.invalidate(&name);
+        self.resolve(&name);
+        self.entries.insert((name, 0), IndexEntry {
+            ctime: 0,
+            ctime_ns: 0,
+            mtime: 0,
+            mtime_ns: 0,
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            assume_valid: false,
+            intent_to_add: false,
+            skip_worktree: false,
+            hash,
+        });
+    }
 
-        for (name, entry) in self.entries.iter() {
-            // TODO: use std::path::Path for this
-            let parts: Vec<&[u8]> = name.split(|c| *c == b'/').collect();
+    // Remove a path at every stage
+    pub fn remove(&mut self, name: &[u8]) {
+        if !self.contains(name) {
+            return;
+        }
+        self.invalidate(name);
+        for stage in 0..4 {
+            self.entries.remove(&(name.to_vec(), stage));
+        }
+    }
 
-            // Figure out if we need to write out some trees from the stack
-            for i in 1..tree_stack.len() {
-                if i >= parts.len() || tree_stack[i].0 != parts[i - 1] {
-                    truncate_tree_stack(&mut tree_stack, i)?;
-                    break;
-                }
+    // Drop a path's conflicted stages, remembering them in case the
+    // resolution needs to be undone
+    fn resolve(&mut self, name: &[u8]) {
+        let mut stages = [None; 3];
+        for stage in 1..4 {
+            if let Some(entry) = self.entries.remove(&(name.to_vec(), stage)) {
+                stages[stage as usize - 1] = Some((entry.mode.bits(), entry.hash));
             }
+        }
+        if stages.iter().any(|s| s.is_some()) {
+            self.resolve_undo.insert(name.to_vec(), stages);
+        }
+    }
 
-            // Append any new trees to the stack
-            for part in parts[(tree_stack.len() - 1)..(parts.len() - 1)].iter() {
-                let new_tree = Tree { entries: Vec::new() };
-                tree_stack.push((Vec::from(*part), new_tree));
-            }
+    // Mark the trees containing a path as needing to be rewritten, and drop
+    // the extensions that might still refer to its old entry
+    pub fn invalidate(&mut self, name: &[u8]) {
+        if let Some(ref mut tree) = self.cache_tree {
+            tree.invalidate(name);
+        }
+        self.other_extensions.clear();
+    }
 
-            let bottom_tree = match tree_stack.last_mut() {
-                Some(&mut (_, ref mut tree)) => tree,
-                None => return Err(GitError::from("Unexpected error")),
-            };
-            bottom_tree.entries.push(TreeEntry {
-                mode: entry.mode.clone(),
-                name: Vec::from(parts[parts.len() - 1]),
-                hash: entry.hash.clone(),
-            });
+    // Create trees, reusing the cached hash of any directory whose entries
+    // haven't changed, and remember the new ones
+    pub fn write_tree(&mut self, repo: &Repository) -> GitResult<String> {
+        if self.has_conflicts() {
+            return Err(GitError::from("Cannot write a tree with unmerged entries"));
         }
+        // Intent-to-add entries aren't part of the tree until they're added
+        let entries: Vec<(&[u8], &IndexEntry)> = self.entries.iter()
+            .filter(|&(_, e)| !e.intent_to_add)
+            .map(|((name, _), e)| (name.as_slice(), e))
+            .collect();
+        let cache = self.cache_tree.get_or_insert_with(CacheTree::default);
+        let hash = build_tree(repo, &entries, 0, cache)?;
+        Ok(parse::bytes_to_hex(&hash))
+    }
+}
+
+// Write the tree for a directory, given the entries under it and the length
+// of the directory's name with its trailing "/"
+fn build_tree(repo: &Repository, entries: &[(&[u8], &IndexEntry)], base_len: usize,
+              cache: &mut CacheTree) -> GitResult<[u8; 20]> {
+    match cache.valid {
+        Some((count, hash)) if count == entries.len() => return Ok(hash),
+        _ => (),
+    }
 
-        // Write out the rest of the trees
-        truncate_tree_stack(&mut tree_stack, 0)
+    let mut tree = Tree { entries: Vec::new() };
+    let mut seen_dirs = Vec::new();
+    let mut i = 0;
+    while i < entries.len() {
+        let rest = &entries[i].0[base_len..];
+        match rest.iter().position(|&c| c == b'/') {
+            None => {
+                tree.entries.push(TreeEntry {
+                    mode: entries[i].1.mode,
+                    name: rest.to_vec(),
+                    hash: entries[i].1.hash,
+                });
+                i += 1;
+            },
+            Some(pos) => {
+                // Everything in this subdirectory is next to each other
+                let dir = &rest[..pos + 1];
+                let end = i + entries[i..].iter()
+                    .take_while(|&&(name, _)| name[base_len..].starts_with(dir))
+                    .count();
+                let name = &rest[..pos];
+                let hash = build_tree(repo, &entries[i..end], base_len + pos + 1,
+                                      cache.child_mut(name))?;
+                tree.entries.push(TreeEntry { mode: EntryMode::Tree, name: name.to_vec(), hash });
+                seen_dirs.push(name.to_vec());
+                i = end;
+            },
+        }
     }
+
+    // Forget directories that no longer exist
+    cache.children.retain(|c| seen_dirs.contains(&c.0));
+    let hash = tree.as_object().write(repo)?.bytes();
+    cache.valid = Some((entries.len(), hash));
+    Ok(hash)
 }
 
-// Remove and write the trees on the stack starting from position at
-// Return the hash of the highest level tree written
-fn truncate_tree_stack(stack: &mut Vec<(Vec<u8>, Tree)>, at: usize)
-        -> GitResult<Digest> {
-    let mut result: Option<Digest> = None;
-    while stack.len() > at {
-        let (name, tree) = match stack.pop() {
-            Some(tup) => tup,
-            // This probably can't happen
-            None => return Err(GitError::from("Unexpected error")),
-        };
-        // Write the tre
-        let digest = tree.as_object().write()?;
-
-        // Add an entry for tree in its parent
-        match stack.last_mut() {
-            Some(&mut (_, ref mut parent)) => parent.entries.push(TreeEntry {
-                mode: EntryMode::Tree,
-                name: name,
-                hash: digest.bytes(),
-            }),
-            None => (),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::TempDir;
+
+    fn entry(n: u32, mode: EntryMode) -> IndexEntry {
+        IndexEntry {
+            ctime: 1_600_000_000 + n, ctime_ns: n, mtime: 1_600_000_100 + n, mtime_ns: 2 * n,
+            dev: 3 * n, ino: 4 * n, mode, uid: 1000, gid: 100, size: 10 * n,
+            assume_valid: false, intent_to_add: false, skip_worktree: false,
+            hash: [n as u8; 20],
+        }
+    }
+
+    // Paths sharing long prefixes, a name too long for the flags' length
+    // field, a conflict, and optionally entries that need extended flags
+    fn sample(extended: bool) -> BTreeMap<(Vec<u8>, u8), IndexEntry> {
+        let mut entries = BTreeMap::new();
+        entries.insert((b"README".to_vec(), 0), entry(1, EntryMode::NormalFile));
+        entries.insert((b"src/bin/main.rs".to_vec(), 0), entry(2, EntryMode::ExecutableFile));
+        entries.insert((b"src/bin/tool.rs".to_vec(), 0), entry(3, EntryMode::NormalFile));
+        entries.insert((b"src/link".to_vec(), 0), entry(4, EntryMode::Symlink));
+        let mut long_name = b"src/".to_vec();
+        long_name.extend(vec![b'x'; 5000]);
+        entries.insert((long_name, 0), entry(5, EntryMode::NormalFile));
+        for stage in 1..4 {
+            entries.insert((b"conflict".to_vec(), stage), entry(5 + stage as u32,
+                                                                EntryMode::NormalFile));
+        }
+        let mut valid = entry(9, EntryMode::NormalFile);
+        valid.assume_valid = true;
+        entries.insert((b"valid".to_vec(), 0), valid);
+        if extended {
+            let mut added = entry(10, EntryMode::NormalFile);
+            added.intent_to_add = true;
+            entries.insert((b"src/added".to_vec(), 0), added);
+            let mut sparse = entry(11, EntryMode::NormalFile);
+            sparse.skip_worktree = true;
+            entries.insert((b"src/sparse".to_vec(), 0), sparse);
+        }
+        entries
+    }
+
+    fn assert_same_entries(a: &BTreeMap<(Vec<u8>, u8), IndexEntry>,
+                           b: &BTreeMap<(Vec<u8>, u8), IndexEntry>) {
+        assert_eq!(a.keys().collect::<Vec<_>>(), b.keys().collect::<Vec<_>>());
+        for (x, y) in a.values().zip(b.values()) {
+            assert_eq!((x.ctime, x.ctime_ns, x.mtime, x.mtime_ns, x.dev, x.ino),
+                       (y.ctime, y.ctime_ns, y.mtime, y.mtime_ns, y.dev, y.ino));
+            assert_eq!((x.mode.bits(), x.uid, x.gid, x.size, x.hash),
+                       (y.mode.bits(), y.uid, y.gid, y.size, y.hash));
+            assert_eq!((x.assume_valid, x.intent_to_add, x.skip_worktree),
+                       (y.assume_valid, y.intent_to_add, y.skip_worktree));
+        }
+    }
+
+    // Write the sample entries in a version, then read them back, returning
+    // the version in the file and its size
+    fn round_trip(name: &str, version: u32, extended: bool) -> (u32, usize) {
+        let dir = TempDir::new(name);
+        let repo = dir.repo();
+        let mut ndx = read(&repo).unwrap();
+        ndx.entries = sample(extended);
+        ndx.set_version(version).unwrap();
+        ndx.write(&repo).unwrap();
+
+        let data = fs::read(repo.path("index")).unwrap();
+        let ndx = read(&repo).unwrap();
+        assert_same_entries(&ndx.entries, &sample(extended));
+        assert_eq!(ndx.version, Cursor::new(&data[4..8]).read_u32::<BigEndian>().unwrap());
+        (ndx.version, data.len())
+    }
+
+    #[test]
+    fn version_2() {
+        assert_eq!(round_trip("index-v2", 2, false).0, 2);
+        // Extended flags need version 3
+        assert_eq!(round_trip("index-v2-extended", 2, true).0, 3);
+    }
+
+    #[test]
+    fn version_3() {
+        assert_eq!(round_trip("index-v3", 3, true).0, 3);
+        // Like git, only write version 3 when something needs it
+        assert_eq!(round_trip("index-v3-plain", 3, false).0, 2);
+    }
+
+    #[test]
+    fn version_4() {
+        let (version, size) = round_trip("index-v4", 4, true);
+        assert_eq!(version, 4);
+        // The long name is only written in full once, with no padding
+        assert!(size < round_trip("index-v4-compare", 3, true).1);
+    }
+
+    #[test]
+    fn unsupported_versions() {
+        let dir = TempDir::new("index-bad-version");
+        let mut ndx = read(&dir.repo()).unwrap();
+        assert!(ndx.set_version(1).is_err());
+        assert!(ndx.set_version(5).is_err());
+        assert_eq!(ndx.version, MIN_VERSION);
+    }
+
+    #[test]
+    fn varints() {
+        for &value in [0, 1, 127, 128, 16511, 16512, 1 << 30].iter() {
+            let mut data = Vec::new();
+            write_varint(&mut data, value).unwrap();
+            assert_eq!(read_varint(&mut Cursor::new(&data)).unwrap(), value);
         }
-        result = Some(digest);
     }
-    match result {
-        Some(digest) => Ok(digest),
-        None => Err(GitError::from("Tried to truncate empty stack")),
+
+    #[test]
+    fn bad_checksum() {
+        let dir = TempDir::new("index-bad-checksum");
+        let repo = dir.repo();
+        let mut ndx = read(&repo).unwrap();
+        ndx.entries = sample(false);
+        ndx.write(&repo).unwrap();
+        let mut data = fs::read(repo.path("index")).unwrap();
+        data[20] ^= 1;
+        fs::write(repo.path("index"), &data).unwrap();
+        assert!(read(&repo).is_err());
+    }
+
+    #[test]
+    fn unknown_extensions() {
+        let dir = TempDir::new("index-extensions");
+        let repo = dir.repo();
+        let mut ndx = read(&repo).unwrap();
+        ndx.entries = sample(false);
+        ndx.write(&repo).unwrap();
+
+        // Add an optional extension before the checksum
+        let mut data = fs::read(repo.path("index")).unwrap();
+        let checksum_start = data.len() - 20;
+        data.truncate(checksum_start);
+        data.extend_from_slice(b"UNTR\0\0\0\x03abc");
+        let checksum = {
+            let mut m = Sha1::new();
+            m.update(&data);
+            m.digest().bytes()
+        };
+        data.extend_from_slice(&checksum);
+        fs::write(repo.path("index"), &data).unwrap();
+        let has_extension = || {
+            let data = fs::read(repo.path("index")).unwrap();
+            data.windows(11).any(|w| w == b"UNTR\0\0\0\x03abc")
+        };
+
+        // It survives a rewrite with no changes, but not a change to an entry
+        read(&repo).unwrap().write(&repo).unwrap();
+        assert!(has_extension());
+        let mut ndx = read(&repo).unwrap();
+        ndx.set_entry(b"README".to_vec(), EntryMode::NormalFile, [1; 20]);
+        ndx.write(&repo).unwrap();
+        assert!(has_extension());
+        ndx = read(&repo).unwrap();
+        ndx.set_entry(b"README".to_vec(), EntryMode::NormalFile, [2; 20]);
+        ndx.write(&repo).unwrap();
+        assert!(!has_extension());
+
+        // Unknown required extensions are an error
+        let mut data = fs::read(repo.path("index")).unwrap();
+        let checksum_start = data.len() - 20;
+        data.truncate(checksum_start);
+        data.extend_from_slice(b"link\0\0\0\0");
+        data.extend_from_slice(&[0; 20]);
+        fs::write(repo.path("index"), &data).unwrap();
+        assert!(read(&repo).is_err());
     }
 }
@@ -4,56 +4,417 @@ extern crate sha1;
 
 use cache::{Object, ObjectType, read_obj};
 use commit::Commit;
+use ignore::Ignores;
 use index::Index;
+use repo::Repository;
 use tree::EntryMode;
 use types::{GitError, GitResult};
-use std::collections::HashSet;
 use std::env;
-use std::ffi::OsString;
 use std::fs::{self, File};
 use std::io::{self, BufRead, BufReader, Read, Write};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command};
+use std::time::Duration;
 
 mod cache;
+mod cachetree;
+mod checkout;
 mod commit;
+mod config;
+mod date;
+mod delta;
+mod diff;
+mod gc;
+mod ident;
+mod ignore;
 mod index;
+mod pack;
 mod parse;
+mod pathspec;
 mod refs;
+mod regex;
+mod repo;
+mod revision;
+mod revwalk;
+mod status;
+mod tag;
+#[cfg(test)]
+mod testutil;
 mod tree;
 mod types;
+mod wildmatch;
+mod xdiff;
 
-fn cat_file(hash: &str) -> GitResult<()> {
-    let obj = read_obj(hash)?;
+fn cat_file(repo: &Repository, name: &str) -> GitResult<()> {
+    let obj = read_obj(repo, &revision::resolve(repo, name)?)?;
     io::stdout().write(&obj.data)?;
     Ok(())
 }
 
-fn hash_object() -> GitResult<()> {
+fn config_cmd(repo: Option<&Repository>, args: &[String]) -> GitResult<()> {
+    let local_path = || match repo {
+        Some(repo) => Ok(config::local_path(repo)),
+        None => Err(GitError::from("--local can only be used inside a git repository")),
+    };
+    let mut file: Option<PathBuf> = None;
+    let mut kind: Option<&str> = None;
+    let mut action: Option<&str> = None;
+    let mut rest: Vec<&String> = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        i += 1;
+        match arg {
+            "--global" => file = Some(config::global_write_path()?),
+            "--system" => file = match config::system_path() {
+                Some(path) => Some(path),
+                None => return Err(GitError::from("system config is disabled")),
+            },
+            "--local" => file = Some(local_path()?),
+            "--worktree" => file = match repo {
+                Some(repo) => Some(repo.path("config.worktree")),
+                None => return Err(GitError::from("--worktree can only be used inside a git \
+                                                   repository")),
+            },
+            "-f" | "--file" => {
+                file = match args.get(i) {
+                    Some(path) => Some(PathBuf::from(path)),
+                    None => return Err(GitError::from("--file requires a path")),
+                };
+                i += 1;
+            },
+            "--bool" | "--int" => kind = Some(arg),
+            "--get" | "--get-all" | "--set" | "--unset" | "--list" => action = Some(arg),
+            "-l" => action = Some("--list"),
+            _ if arg.starts_with('-') => return Err(GitError::from("Unknown config argument")),
+            _ => rest.push(&args[i - 1]),
+        }
+    }
+    let action = match action {
+        Some(action) => action,
+        None => match rest.len() {
+            1 => "--get",
+            2 => "--set",
+            _ => return Err(GitError::from("usage: config [<options>] <name> [<value>]")),
+        },
+    };
+
+    let cfg = match file {
+        Some(ref path) => config::load_path(path)?,
+        None => config::load(repo)?,
+    };
+    let format_value = |value: &str| -> GitResult<String> {
+        match kind {
+            Some("--bool") => Ok(config::parse_bool(Some(value))?.to_string()),
+            Some("--int") => Ok(config::parse_int(value)?.to_string()),
+            _ => Ok(String::from(value)),
+        }
+    };
+
+    match (action, rest.len()) {
+        ("--list", 0) => {
+            for entry in cfg.entries.iter() {
+                match entry.value {
+                    Some(ref value) => println!("{}={}", entry.name(), value),
+                    None => println!("{}", entry.name()),
+                }
+            }
+            Ok(())
+        },
+        ("--get", 1) => match cfg.get(rest[0]) {
+            Some(value) => {
+                println!("{}", format_value(value)?);
+                Ok(())
+            },
+            // Like git, a missing key is a quiet failure
+            None => process::exit(1),
+        },
+        ("--get-all", 1) => {
+            let values = cfg.get_all(rest[0]);
+            if values.is_empty() {
+                process::exit(1);
+            }
+            for value in values {
+                println!("{}", format_value(value)?);
+            }
+            Ok(())
+        },
+        ("--set", 2) => {
+            let value = format_value(rest[1])?;
+            let path = match file {
+                Some(path) => path,
+                None => local_path()?,
+            };
+            config::set_value(&path, rest[0], &value)
+        },
+        ("--unset", 1) => {
+            let path = match file {
+                Some(path) => path,
+                None => local_path()?,
+            };
+            config::unset_value(&path, rest[0])
+        },
+        _ => Err(GitError::from("Wrong number of arguments to config")),
+    }
+}
+
+fn init(args: &[String]) -> GitResult<()> {
+    let mut bare = false;
+    let mut branch = "master";
+    let mut dir = None;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+        if arg == "--bare" {
+            bare = true;
+        } else if arg == "-b" || arg == "--initial-branch" {
+            branch = match args.get(i) {
+                Some(name) => name,
+                None => return Err(GitError::from("-b requires a branch name")),
+            };
+            i += 1;
+        } else if let Some(name) = arg.strip_prefix("--initial-branch=") {
+            branch = name;
+        } else if dir.is_none() && !arg.starts_with('-') {
+            dir = Some(arg);
+        } else {
+            return Err(GitError::from("Unknown init argument"));
+        }
+    }
+    if branch.is_empty() || branch.contains(|c: char| c.is_whitespace() || c == '~'
+                                                    || c == '^' || c == ':') {
+        return Err(GitError::from("Invalid initial branch name"));
+    }
+
+    let work_dir = PathBuf::from(dir.map_or(".", |d| d.as_str()));
+    let git_dir = if bare { work_dir.clone() } else { work_dir.join(".git") };
+    let reinit = git_dir.join("HEAD").is_file();
+
+    for sub in ["objects/info", "objects/pack", "refs/heads", "refs/tags"].iter() {
+        fs::create_dir_all(git_dir.join(sub))?;
+    }
+
+    // Don't clobber anything in an existing repository
+    let skeleton = [
+        ("HEAD", format!("ref: refs/heads/{}\n", branch)),
+        ("config", format!("[core]\n\
+                            \trepositoryformatversion = 0\n\
+                            \tfilemode = true\n\
+                            \tbare = {}\n\
+                            \tlogallrefupdates = true\n", bare)),
+        ("description", String::from("Unnamed repository; edit this file \
+                                      'description' to name the repository.\n")),
+    ];
+    for &(name, ref contents) in skeleton.iter() {
+        match fs::OpenOptions::new().write(true).create_new(true).open(git_dir.join(name)) {
+            Ok(mut f) => f.write_all(contents.as_bytes())?,
+            Err(err) => match err.kind() {
+                io::ErrorKind::AlreadyExists => (),
+                _ => return Err(GitError::from(err)),
+            },
+        }
+    }
+
+    let abs_dir = fs::canonicalize(&git_dir)?;
+    if reinit {
+        println!("Reinitialized existing Git repository in {}/", abs_dir.display());
+    } else {
+        println!("Initialized empty Git repository in {}/", abs_dir.display());
+    }
+    Ok(())
+}
+
+fn hash_object(repo: &Repository) -> GitResult<()> {
     let mut stdin = std::io::stdin();
     let mut data = Vec::new();
     stdin.read_to_end(&mut data)?;
     let obj = Object { kind: ObjectType::Blob, data: data };
-    let hash = obj.write()?;
+    let hash = obj.write(repo)?;
     println!("{}", hash);
     Ok(())
 }
 
-fn show_commit(hash: &str) -> GitResult<()> {
-    let obj = read_obj(hash)?;
-    let commit = commit::from_object(&obj)?;
+fn pack_objects(repo: &Repository, args: &[String]) -> GitResult<()> {
+    let mut window = 10;
+    let mut depth = 50;
+    let mut base_name = None;
+    for arg in args {
+        if let Some(value) = arg.strip_prefix("--window=") {
+            window = value.parse::<usize>()?;
+        } else if let Some(value) = arg.strip_prefix("--depth=") {
+            depth = value.parse::<usize>()?;
+        } else if arg == "--no-delta" {
+            window = 0;
+        } else if base_name.is_none() && !arg.starts_with("-") {
+            base_name = Some(arg);
+        } else {
+            return Err(GitError::from("Unknown pack-objects argument"));
+        }
+    }
+    let base_name = match base_name {
+        Some(name) => name,
+        None => return Err(GitError::from("Missing pack base name")),
+    };
+
+    // One object name per line, anything after the hash is ignored
+    let stdin = io::stdin();
+    let mut hashes = Vec::new();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if let Some(hex) = line.split_whitespace().next() {
+            hashes.push(parse::hex_to_hash(hex)?);
+        }
+    }
+
+    println!("{}", pack::write_pack(repo, &hashes, base_name, window, depth)?);
+    Ok(())
+}
+
+// Parse the argument to gc --prune, like "now" or "2.weeks.ago"
+fn parse_prune_grace(arg: &str) -> GitResult<Duration> {
+    let cutoff = date::parse_date(arg)?;
+    let age = chrono::Local::now().timestamp() - cutoff.timestamp();
+    Ok(Duration::from_secs(if age > 0 { age as u64 } else { 0 }))
+}
+
+fn gc(repo: &Repository, args: &[String]) -> GitResult<()> {
+    let mut grace = Some(Duration::from_secs(gc::DEFAULT_PRUNE_GRACE));
+    for arg in args {
+        if let Some(value) = arg.strip_prefix("--prune=") {
+            grace = Some(parse_prune_grace(value)?);
+        } else if arg == "--no-prune" {
+            grace = None;
+        } else {
+            return Err(GitError::from("Unknown gc argument"));
+        }
+    }
+    gc::gc(repo, grace)
+}
+
+fn print_commit(hash: &str, commit: &Commit) {
     println!("commit {}", hash);
     println!("Author: {}", commit.author);
     println!("Date:   {}", commit.author_date.format("%a %e %b %H:%M:%S %Y %z"));
     print!("\n{}", commit.message);
+}
+
+fn show_commit(repo: &Repository, name: &str) -> GitResult<()> {
+    let hash = revision::peel(repo, &revision::resolve(repo, name)?, ObjectType::Commit)?;
+    let obj = read_obj(repo, &hash)?;
+    let commit = commit::from_object(&obj)?;
+    print_commit(&hash, &commit);
+    Ok(())
+}
+
+fn log(repo: &Repository, args: &[String]) -> GitResult<()> {
+    let mut opts = revwalk::WalkOptions::new();
+    let mut include = Vec::new();
+    let mut exclude_starts = Vec::new();
+    let mut symmetric = Vec::new();
+    // Patterns are compiled at the end, since -i may come after them
+    let mut author = None;
+    let mut grep = None;
+    let mut ignore_case = false;
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+        if arg == "-n" {
+            opts.max_count = match args.get(i) {
+                Some(n) => Some(n.parse::<usize>()?),
+                None => return Err(GitError::from("-n requires a value")),
+            };
+            i += 1;
+        } else if let Some(n) = arg.strip_prefix("--max-count=") {
+            opts.max_count = Some(n.parse::<usize>()?);
+        } else if let Some(n) = arg.strip_prefix("-n") {
+            opts.max_count = Some(n.parse::<usize>()?);
+        } else if arg.len() > 1 && arg.starts_with('-')
+                && arg[1..].chars().all(|c| c.is_ascii_digit()) {
+            opts.max_count = Some(arg[1..].parse::<usize>()?);
+        } else if arg == "--first-parent" {
+            opts.first_parent = true;
+        } else if arg == "--date-order" {
+            opts.order = revwalk::Order::DateTopo;
+        } else if arg == "--topo-order" {
+            opts.order = revwalk::Order::Topo;
+        } else if let Some(value) = arg.strip_prefix("--since=")
+                .or_else(|| arg.strip_prefix("--after=")) {
+            opts.since = Some(date::parse_date(value)?);
+        } else if let Some(value) = arg.strip_prefix("--until=")
+                .or_else(|| arg.strip_prefix("--before=")) {
+            opts.until = Some(date::parse_date(value)?);
+        } else if let Some(pattern) = arg.strip_prefix("--author=") {
+            author = Some(pattern);
+        } else if let Some(pattern) = arg.strip_prefix("--grep=") {
+            grep = Some(pattern);
+        } else if arg == "-i" || arg == "--regexp-ignore-case" {
+            ignore_case = true;
+        } else if arg.starts_with('-') {
+            return Err(GitError::from("Unknown log argument"));
+        } else if let Some(rev) = arg.strip_prefix('^') {
+            exclude_starts.push(revision::resolve(repo, rev)?);
+        } else if let Some(ndx) = arg.find("..") {
+            // An empty side of a range means HEAD
+            let resolve_side = |side: &str| if side.is_empty() {
+                revision::resolve(repo, "HEAD")
+            } else {
+                revision::resolve(repo, side)
+            };
+            if arg[ndx..].starts_with("...") {
+                let left = resolve_side(&arg[..ndx])?;
+                let right = resolve_side(&arg[ndx + 3..])?;
+                include.push(left.clone());
+                include.push(right.clone());
+                symmetric.push((left, right));
+            } else {
+                exclude_starts.push(resolve_side(&arg[..ndx])?);
+                include.push(resolve_side(&arg[ndx + 2..])?);
+            }
+        } else {
+            include.push(revision::resolve(repo, arg)?);
+        }
+    }
+    if include.is_empty() {
+        include.push(revision::resolve(repo, "HEAD")?);
+    }
+    if let Some(pattern) = author {
+        opts.author = Some(regex::compile(pattern, ignore_case)?);
+    }
+    if let Some(pattern) = grep {
+        opts.grep = Some(regex::compile(pattern, ignore_case)?);
+    }
+
+    // Hide everything reachable from the negative revisions, and whatever
+    // both sides of a symmetric difference have in common
+    let mut exclude = revwalk::ancestors(repo, &exclude_starts)?;
+    for (left, right) in symmetric {
+        let left_ancestors = revwalk::ancestors(repo, &[left])?;
+        for hash in revwalk::ancestors(repo, &[right])? {
+            if left_ancestors.contains(&hash) {
+                exclude.insert(hash);
+            }
+        }
+    }
+
+    let commits = revwalk::walk(repo, &include, &exclude, &opts)?;
+    for (ndx, (hash, commit)) in commits.iter().enumerate() {
+        if ndx > 0 {
+            println!();
+        }
+        print_commit(hash, commit);
+    }
     Ok(())
 }
 
 // Drop the user into vim so they can write a commit message
-fn prompt_commit_message() -> GitResult<Option<String>> {
+fn prompt_commit_message(repo: &Repository) -> GitResult<Option<String>> {
+    let path = repo.path("COMMIT_EDITMSG");
     // Create file
     {
-        let mut file = File::create(".git/COMMIT_EDITMSG")?;
+        let mut file = File::create(&path)?;
         file.write(b"
 # Please enter the commit message for your changes. Lines starting
 # with '#' will be ignored, and an empty message aborts the commit.\n")?;
@@ -61,11 +422,11 @@ fn prompt_commit_message() -> GitResult<Option<String>> {
 
     // Drop the user into vim
     Command::new("vim")
-                 .arg(".git/COMMIT_EDITMSG")
+                 .arg(&path)
                  .status()?;
 
     // Read and parse the file
-    let file = File::open(".git/COMMIT_EDITMSG")?;
+    let file = File::open(&path)?;
     parse_commit_message(file)
 }
 
@@ -92,15 +453,39 @@ fn parse_commit_message(f: File) -> GitResult<Option<String>> {
     })
 }
 
-fn write_commit(parents: &[String]) -> GitResult<()> {
-    // TODO
-    let author = "Nick Frost <nickfrostatx@gmail.com>";
-    let localtime = chrono::Local::now();
-    let author_date = localtime.with_timezone(localtime.offset());
+fn write_commit(repo: &Repository, merge_parents: &[String]) -> GitResult<()> {
+    let cfg = config::load(Some(repo))?;
+    let (author, author_date) = ident::identity(ident::Role::Author, &cfg)?;
+    let (committer, committer_date) = ident::identity(ident::Role::Committer, &cfg)?;
 
-    let tree = index::read()?.write_tree()?.to_string();
+    // Commit onto the branch HEAD points to, or HEAD itself when detached
+    let ref_name = match refs::symbolic_target(repo, "HEAD")? {
+        Some(branch) => branch,
+        None => String::from("HEAD"),
+    };
+    let old_head = match refs::read_ref(repo, &ref_name) {
+        Ok(hash) => Some(hash),
+        // The first commit on an unborn branch has no parent
+        Err(GitError::IoError(ref err)) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    let mut parents: Vec<String> = old_head.iter().cloned().collect();
+    for parent in merge_parents {
+        parents.push(revision::peel(repo, &revision::resolve(repo, parent)?, ObjectType::Commit)?);
+    }
+
+    // Keep the trees we wrote in the index for next time
+    let mut ndx = index::read(repo)?;
+    if ndx.has_conflicts() {
+        eprintln!("error: Committing is not possible because you have unmerged files.");
+        eprintln!("hint: Fix them up in the work tree, and then use 'git add/rm <file>'");
+        eprintln!("hint: as appropriate to mark resolution and make a commit.");
+        return Err(GitError::from("Exiting because of an unresolved conflict."));
+    }
+    let tree = ndx.write_tree(repo)?;
+    ndx.write(repo)?;
 
-    let message = match prompt_commit_message()? {
+    let message = match prompt_commit_message(repo)? {
         Some(msg) => msg,
         None => {
             println!("Aborting commit due to empty commit message.");
@@ -109,22 +494,33 @@ fn write_commit(parents: &[String]) -> GitResult<()> {
     };
 
     let commit = Commit {
-        tree: String::from(tree),
-        parents: Vec::from(parents),
-        author: String::from(author),
-        author_date: author_date,
-        committer: String::from(author),
-        committer_date: author_date.clone(),
+        tree,
+        parents,
+        author,
+        author_date,
+        committer,
+        committer_date,
         message: message,
     };
-    let hash = commit.as_object().write()?;
-    println!("{}", hash.to_string());
+    let hash = commit.as_object().write(repo)?.to_string();
+
+    let kind = match commit.parents.len() {
+        0 => "commit (initial)",
+        1 => "commit",
+        _ => "commit (merge)",
+    };
+    let subject = commit.message.lines().next().unwrap_or("");
+    refs::update_ref(repo, &ref_name, &hash, old_head.as_deref(),
+                     &commit.committer, &commit.committer_date,
+                     &format!("{}: {}", kind, subject))?;
+    println!("{}", hash);
 
     Ok(())
 }
 
-fn show_tree(hash: &str) -> GitResult<()> {
-    let obj = read_obj(hash)?;
+fn show_tree(repo: &Repository, name: &str) -> GitResult<()> {
+    let hash = revision::peel(repo, &revision::resolve(repo, name)?, ObjectType::Tree)?;
+    let obj = read_obj(repo, &hash)?;
     let tree = tree::from_object(&obj)?;
 
     for entry in tree.entries {
@@ -149,41 +545,70 @@ fn show_tree(hash: &str) -> GitResult<()> {
     Ok(())
 }
 
-fn write_tree() -> GitResult<()> {
-    let ndx = index::read()?;
-    println!("{}", ndx.write_tree()?);
+fn write_tree(repo: &Repository) -> GitResult<()> {
+    let mut ndx = index::read(repo)?;
+    if ndx.has_conflicts() {
+        for ((name, _), entry) in ndx.entries.iter().filter(|&(k, _)| k.1 != 0) {
+            eprintln!("{}: unmerged ({})", String::from_utf8_lossy(name),
+                      parse::bytes_to_hex(&entry.hash));
+        }
+        return Err(GitError::from("git-write-tree: error building trees"));
+    }
+    let tree = ndx.write_tree(repo)?;
+    ndx.write(repo)?;
+    println!("{}", tree);
     Ok(())
 }
 
-fn make_relative(path: &Path) -> Option<PathBuf> {
-    // TODO
-    Some(path.to_path_buf())
-}
+// Turn a path given on the command line into one relative to the top of
+// the work tree, resolving "." and ".." without touching the filesystem
+fn make_relative(repo: &Repository, path: &Path) -> GitResult<PathBuf> {
+    let work_tree = repo.work_tree()?;
+    let work_tree = work_tree.canonicalize().unwrap_or_else(|_| work_tree.to_path_buf());
+    let cwd = env::current_dir()?;
+    let cwd = cwd.canonicalize().unwrap_or(cwd);
 
-fn should_ignore(path: &Path) -> bool {
-    const IGNORE_NAMES: [&str; 3] = [".git", "target", "Cargo.lock"];
-    let to_ignore: HashSet<OsString> =
-            IGNORE_NAMES.iter().map(|p| OsString::from(p)).collect();
-    return path.components().any(
-            |p| to_ignore.contains(p.as_os_str()))
+    let mut normalized = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+    match normalized.strip_prefix(&work_tree) {
+        Ok(rel) => Ok(rel.to_path_buf()),
+        Err(_) => Err(GitError::from(format!("'{}' is outside repository at '{}'",
+                                             path.display(), work_tree.display()))),
+    }
 }
 
-fn add_recursive(ndx: &mut Index, path: &Path) -> GitResult<()> {
-    if should_ignore(path) {
+// Add a path relative to the top of the work tree, and anything under it
+// that isn't ignored
+fn add_recursive(repo: &Repository, ndx: &mut Index, ignores: &mut Ignores, path: &Path,
+                 force: bool) -> GitResult<()> {
+    let meta = fs::symlink_metadata(repo.work_tree()?.join(path))?;
+    let file_type = meta.file_type();
+
+    // Ignore rules never apply to what's already tracked
+    let name = index::path_to_name(path)?;
+    if !force && !ndx.is_tracked(&name) && ignores.is_ignored(&name, file_type.is_dir())? {
         return Ok(());
     }
 
-    let meta = fs::symlink_metadata(path)?;
-    let file_type = meta.file_type();
-
     if file_type.is_file() || file_type.is_symlink() {
         // Just add the file
-        ndx.add(&path, &meta)?;
+        ndx.add(repo, path, &meta)?;
     } else if file_type.is_dir() {
         // Recurse
-        for entry in fs::read_dir(path)? {
+        for entry in fs::read_dir(repo.work_tree()?.join(path))? {
             let entry = entry?;
-            add_recursive(ndx, &entry.path())?;
+            if entry.file_name() == ".git" {
+                continue;
+            }
+            add_recursive(repo, ndx, ignores, &path.join(entry.file_name()), force)?;
         }
     } else {
         // Skip any files that aren't paths, symlinks, or directories
@@ -191,27 +616,426 @@ fn add_recursive(ndx: &mut Index, path: &Path) -> GitResult<()> {
     Ok(())
 }
 
-fn add(paths: &[String]) -> GitResult<()> {
-    if paths.len() == 0 {
+fn add(repo: &Repository, args: &[String]) -> GitResult<()> {
+    let mut force = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        if arg == "-f" || arg == "--force" {
+            force = true;
+        } else if arg.starts_with('-') {
+            return Err(GitError::from("Unknown add argument"));
+        } else {
+            paths.push(arg);
+        }
+    }
+    if paths.is_empty() {
         println!("Nothing specified, nothing added.");
         println!("Maybe you wanted to say 'git add .'?");
         return Ok(());
     }
 
-    let mut ndx = index::read()?;
+    let cfg = config::load(Some(repo))?;
+    let mut ignores = Ignores::new(repo, &cfg)?;
+    let mut ndx = index::read(repo)?;
+    let mut ignored = Vec::new();
     for path in paths {
-        match make_relative(&Path::new(path)) {
-            Some(p) => add_recursive(&mut ndx, &p)?,
-            None => (),
+        let rel = make_relative(repo, Path::new(path))?;
+        let name = index::path_to_name(&rel)?;
+        let is_dir = repo.work_tree()?.join(&rel).is_dir();
+        // Naming an ignored path outright is probably a mistake
+        if !force && !ndx.is_tracked(&name) && ignores.is_ignored(&name, is_dir)? {
+            ignored.push(path);
+            continue;
         }
+        add_recursive(repo, &mut ndx, &mut ignores, &rel, force)?;
     }
-    ndx.write()
+    ndx.write(repo)?;
+
+    if !ignored.is_empty() {
+        eprintln!("The following paths are ignored by one of your .gitignore files:");
+        for path in ignored {
+            eprintln!("{}", path);
+        }
+        eprintln!("hint: Use -f if you really want to add them.");
+        process::exit(1);
+    }
+    Ok(())
 }
 
-fn rev_parse(paths: &[String]) -> GitResult<()> {
+fn check_ignore(repo: &Repository, args: &[String]) -> GitResult<()> {
+    let mut verbose = false;
+    let mut non_matching = false;
+    let mut quiet = false;
+    let mut no_index = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-v" | "--verbose" => verbose = true,
+            "-n" | "--non-matching" => non_matching = true,
+            "-q" | "--quiet" => quiet = true,
+            "--no-index" => no_index = true,
+            _ if arg.starts_with('-') => {
+                return Err(GitError::from("Unknown check-ignore argument"));
+            },
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        return Err(GitError::from("no path specified"));
+    }
+    if non_matching && !verbose {
+        return Err(GitError::from("--non-matching is only valid with --verbose"));
+    }
+
+    let cfg = config::load(Some(repo))?;
+    let mut ignores = Ignores::new(repo, &cfg)?;
+    let ndx = if no_index { None } else { Some(index::read(repo)?) };
+    let mut any_matched = false;
     for path in paths {
-        let full_ref = refs::expand_refname(&path)?;
-        println!("{}", refs::read_ref(&full_ref)?);
+        let rel = make_relative(repo, Path::new(path))?;
+        let name = index::path_to_name(&rel)?;
+        // Tracked files aren't subject to ignore rules
+        if ndx.as_ref().is_some_and(|ndx| ndx.contains(&name)) {
+            continue;
+        }
+        let is_dir = path.ends_with('/') || repo.work_tree()?.join(&rel).is_dir();
+        match ignores.matching(&name, is_dir)? {
+            Some(pattern) if verbose || !pattern.negated => {
+                any_matched = true;
+                if quiet {
+                    continue;
+                }
+                if verbose {
+                    println!("{}:{}:{}\t{}", pattern.source, pattern.line, pattern.text, path);
+                } else {
+                    println!("{}", path);
+                }
+            },
+            _ => if non_matching && !quiet {
+                println!("::\t{}", path);
+            },
+        }
+    }
+    if !any_matched {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn ls_files(repo: &Repository, args: &[String]) -> GitResult<()> {
+    let mut stage = false;
+    let mut unmerged = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-s" | "--stage" => stage = true,
+            // Unmerged entries are shown with their stages
+            "-u" | "--unmerged" => {
+                stage = true;
+                unmerged = true;
+            },
+            _ if arg.starts_with('-') => return Err(GitError::from("Unknown ls-files argument")),
+            _ => paths.push(index::path_to_name(&make_relative(repo, Path::new(arg))?)?),
+        }
+    }
+    // Without paths, list what's under the current directory
+    let prefix = status::cwd_prefix(repo.work_tree()?)?;
+    if paths.is_empty() {
+        paths.push(prefix[..prefix.len().saturating_sub(1)].to_vec());
+    }
+    let matches = |name: &[u8]| paths.iter().any(|path| {
+        path.is_empty() || name == &path[..]
+            || (name.starts_with(path) && name[path.len()] == b'/')
+    });
+
+    let ndx = index::read(repo)?;
+    for (&(ref name, n), entry) in ndx.entries.iter() {
+        if (unmerged && n == 0) || !matches(name) {
+            continue;
+        }
+        let display = status::quote_path(&status::relative_to(name, &prefix), false);
+        if stage {
+            println!("{:06o} {} {}\t{}", entry.mode.bits(), parse::bytes_to_hex(&entry.hash), n,
+                     display);
+        } else {
+            println!("{}", display);
+        }
+    }
+    Ok(())
+}
+
+// Turn command line paths into pathspecs relative to the top of the work tree
+fn update_index(repo: &Repository, args: &[String]) -> GitResult<()> {
+    let mut version = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = if let Some(value) = arg.strip_prefix("--index-version=") {
+            value
+        } else if arg == "--index-version" {
+            match args.next() {
+                Some(value) => value,
+                None => return Err(GitError::from("option `index-version' requires a value")),
+            }
+        } else {
+            return Err(GitError::from("Unknown update-index argument"));
+        };
+        version = Some(value.parse::<u32>()?);
+    }
+
+    // Only rewrite the index when something asked for a change
+    if let Some(version) = version {
+        let mut ndx = index::read(repo)?;
+        ndx.set_version(version)?;
+        ndx.write(repo)?;
+    }
+    Ok(())
+}
+
+fn pathspecs(repo: &Repository, args: &[&String]) -> GitResult<Vec<pathspec::Pathspec>> {
+    let mut specs = Vec::new();
+    for arg in args {
+        let name = index::path_to_name(&make_relative(repo, Path::new(arg))?)?;
+        specs.push(pathspec::Pathspec::new(arg, name));
+    }
+    Ok(specs)
+}
+
+fn checkout(repo: &Repository, args: &[String], switch: bool) -> GitResult<()> {
+    let mut opts = checkout::CheckoutOptions { force: false, detach: false, switch };
+    let mut positional = Vec::new();
+    let mut paths = None;
+    for (i, arg) in args.iter().enumerate() {
+        match arg.as_str() {
+            "-f" | "--force" => opts.force = true,
+            "--discard-changes" if switch => opts.force = true,
+            "-d" if switch => opts.detach = true,
+            "--detach" => opts.detach = true,
+            "--" if !switch => {
+                paths = Some(args[i + 1..].iter().collect::<Vec<&String>>());
+                break;
+            },
+            "-" => positional.push(arg),
+            _ if arg.starts_with('-') => return Err(GitError::from("Unknown checkout argument")),
+            _ => positional.push(arg),
+        }
+    }
+
+    // Anything after "--", or after a commit, is a path. Without "--", a
+    // single argument is a path if it isn't a branch or commit.
+    let has_dash_dash = paths.is_some();
+    let (source, paths) = match paths {
+        Some(_) if positional.len() > 1 => {
+            return Err(GitError::from("Only one commit can be given before \"--\""));
+        },
+        Some(paths) => (positional.first().cloned(), paths),
+        None if switch || positional.is_empty() => (None, Vec::new()),
+        None if revision::resolve(repo, positional[0]).is_ok() => {
+            (Some(positional[0]), positional[1..].to_vec())
+        },
+        None => (None, positional.clone()),
+    };
+    if has_dash_dash || !paths.is_empty() {
+        let restore_opts = checkout::RestoreOptions {
+            source: source.cloned(),
+            staged: source.is_some(),
+            worktree: true,
+            overlay: true,
+            report: !has_dash_dash,
+        };
+        return checkout::restore(repo, &pathspecs(repo, &paths)?, &restore_opts);
+    }
+
+    match positional.len() {
+        1 => checkout::checkout(repo, positional[0], &opts),
+        // "--detach" alone detaches at the current commit
+        0 if opts.detach => checkout::checkout(repo, "HEAD", &opts),
+        0 if switch => Err(GitError::from("missing branch or commit argument")),
+        0 => Err(GitError::from("You must specify a branch or commit to check out")),
+        _ => Err(GitError::from("Only one branch or commit can be checked out")),
+    }
+}
+
+fn restore(repo: &Repository, args: &[String]) -> GitResult<()> {
+    let mut opts = checkout::RestoreOptions {
+        source: None,
+        staged: false,
+        worktree: false,
+        overlay: false,
+        report: false,
+    };
+    // Split up bundled flags like -SW
+    let mut flags = Vec::new();
+    for arg in args {
+        if arg.len() > 2 && !arg.starts_with("--") && arg[1..].chars().all(|c| "SW".contains(c)) {
+            flags.extend(arg[1..].chars().map(|c| format!("-{}", c)));
+        } else {
+            flags.push(arg.clone());
+        }
+    }
+    let args = &flags;
+    let mut paths = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+        match arg.as_str() {
+            "-s" | "--source" => {
+                opts.source = match args.get(i) {
+                    Some(source) => Some(source.clone()),
+                    None => return Err(GitError::from(format!("{} requires a value", arg))),
+                };
+                i += 1;
+            },
+            "-S" | "--staged" => opts.staged = true,
+            "-W" | "--worktree" => opts.worktree = true,
+            "--overlay" => opts.overlay = true,
+            "--no-overlay" => opts.overlay = false,
+            "--" => {
+                paths.extend(args[i..].iter());
+                break;
+            },
+            _ if arg.starts_with("--source=") => opts.source = Some(String::from(&arg[9..])),
+            _ if arg.starts_with('-') => return Err(GitError::from("Unknown restore argument")),
+            _ => paths.push(arg),
+        }
+    }
+    // The work tree is what gets restored unless the index is asked for
+    if !opts.staged {
+        opts.worktree = true;
+    }
+    if paths.is_empty() {
+        return Err(GitError::from("you must specify path(s) to restore"));
+    }
+    checkout::restore(repo, &pathspecs(repo, &paths)?, &opts)
+}
+
+fn diff(repo: &Repository, args: &[String]) -> GitResult<()> {
+    let mut opts = diff::DiffOptions {
+        commits: Vec::new(),
+        cached: false,
+        context: None,
+        algorithm: None,
+    };
+    let parse_context = |value: &str| match value.parse() {
+        Ok(context) => Ok(Some(context)),
+        Err(_) => Err(GitError::from(format!("invalid context length '{}'", value))),
+    };
+    let parse_algorithm = |name: &str| match xdiff::Algorithm::from_name(name) {
+        Some(algorithm) => Ok(Some(algorithm)),
+        None => Err(GitError::from("option diff-algorithm accepts \"myers\", \"minimal\", \
+                                    \"patience\" and \"histogram\"")),
+    };
+    let mut positional = Vec::new();
+    let mut paths = None;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+        match arg.as_str() {
+            "--cached" | "--staged" => opts.cached = true,
+            "--minimal" => opts.algorithm = Some(xdiff::Algorithm::Minimal),
+            "--patience" => opts.algorithm = Some(xdiff::Algorithm::Patience),
+            "--histogram" => opts.algorithm = Some(xdiff::Algorithm::Histogram),
+            "--diff-algorithm" => {
+                opts.algorithm = match args.get(i) {
+                    Some(name) => parse_algorithm(name)?,
+                    None => return Err(GitError::from("--diff-algorithm requires a value")),
+                };
+                i += 1;
+            },
+            "--" => {
+                paths = Some(args[i..].iter().collect::<Vec<&String>>());
+                break;
+            },
+            _ if arg.starts_with("--diff-algorithm=") => {
+                opts.algorithm = parse_algorithm(&arg[17..])?;
+            },
+            _ if arg.starts_with("--unified=") => opts.context = parse_context(&arg[10..])?,
+            _ if arg.starts_with("-U") => opts.context = parse_context(&arg[2..])?,
+            _ if arg.starts_with('-') => return Err(GitError::from("Unknown diff argument")),
+            _ => positional.push(arg),
+        }
+    }
+
+    // Commits come first. Without "--", they end at the first argument that
+    // isn't one.
+    let is_rev = |arg: &str| arg.split("..").all(|rev| {
+        rev.is_empty() || revision::resolve(repo, rev).is_ok()
+    });
+    let (rev_args, paths) = match paths {
+        Some(paths) => (positional, paths),
+        None => {
+            let n = positional.iter().take_while(|arg| is_rev(arg)).count();
+            (positional[..n].to_vec(), positional[n..].to_vec())
+        },
+    };
+    let mut revs = Vec::new();
+    for arg in rev_args {
+        // A range "A..B" compares A with B, with HEAD for either left out
+        match arg.find("..") {
+            Some(pos) => {
+                for rev in [&arg[..pos], &arg[pos + 2..]].iter() {
+                    revs.push(String::from(if rev.is_empty() { "HEAD" } else { rev }));
+                }
+            },
+            None => revs.push(arg.clone()),
+        }
+    }
+    if revs.len() > 2 || (revs.len() == 2 && opts.cached) {
+        return Err(GitError::from("Too many commits to diff"));
+    }
+    opts.commits = revs;
+    diff::diff(repo, &pathspecs(repo, &paths)?, &opts)
+}
+
+fn status(repo: &Repository, args: &[String]) -> GitResult<()> {
+    let mut opts = status::StatusOptions {
+        format: status::Format::Long,
+        branch: false,
+        null_terminated: false,
+        untracked: status::UntrackedMode::Normal,
+    };
+    let mut format = None;
+    // Split up bundled flags like -sb
+    let mut flags = Vec::new();
+    for arg in args {
+        if arg.len() > 2 && !arg.starts_with("--") && !arg.starts_with("-u")
+                && arg[1..].chars().all(|c| "sbz".contains(c)) {
+            flags.extend(arg[1..].chars().map(|c| format!("-{}", c)));
+        } else {
+            flags.push(arg.clone());
+        }
+    }
+    for arg in flags.iter() {
+        match arg.as_str() {
+            "-s" | "--short" => format = Some(status::Format::Short),
+            "--long" => format = Some(status::Format::Long),
+            "--porcelain" | "--porcelain=v1" => format = Some(status::Format::Porcelain),
+            "--porcelain=v2" => format = Some(status::Format::PorcelainV2),
+            "-b" | "--branch" => opts.branch = true,
+            "-z" => opts.null_terminated = true,
+            "-u" | "-uall" | "--untracked-files" | "--untracked-files=all" => {
+                opts.untracked = status::UntrackedMode::All;
+            },
+            "-unormal" | "--untracked-files=normal" => {
+                opts.untracked = status::UntrackedMode::Normal;
+            },
+            "-uno" | "--untracked-files=no" => opts.untracked = status::UntrackedMode::No,
+            _ => return Err(GitError::from("Unknown status argument")),
+        }
+    }
+    // -z means porcelain unless asked otherwise
+    opts.format = match format {
+        Some(format) => format,
+        None if opts.null_terminated => status::Format::Porcelain,
+        None => status::Format::Long,
+    };
+    status::status(repo, &opts)
+}
+
+fn rev_parse(repo: &Repository, revs: &[String]) -> GitResult<()> {
+    for rev in revs {
+        println!("{}", revision::resolve(repo, rev)?);
     }
     Ok(())
 }
@@ -225,41 +1049,51 @@ fn main() {
 
     let result = match args[1].as_ref() {
         // Porcelain commands (I plan on implementing all of these)
-        "add" => add(&args[2..]),
+        "add" => repo::discover().and_then(|repo| add(&repo, &args[2..])),
         "branch" => Err(GitError::from("Command not implemented")),
-        "commit" => write_commit(&args[2..]),
-        "diff" => Err(GitError::from("Command not implemented")),
+        "checkout" => repo::discover().and_then(|repo| checkout(&repo, &args[2..], false)),
+        "commit" => repo::discover().and_then(|repo| write_commit(&repo, &args[2..])),
+        "config" => config_cmd(repo::discover().ok().as_ref(), &args[2..]),
+        "diff" => repo::discover().and_then(|repo| diff(&repo, &args[2..])),
         "fsck" => Err(GitError::from("Command not implemented")),
-        "init" => Err(GitError::from("Command not implemented")),
-        "log" => Err(GitError::from("Command not implemented")),
+        "gc" => repo::discover().and_then(|repo| gc(&repo, &args[2..])),
+        "init" => init(&args[2..]),
+        "log" => repo::discover().and_then(|repo| log(&repo, &args[2..])),
         "merge" => Err(GitError::from("Command not implemented")),
         "show" => Err(GitError::from("Command not implemented")),
-        "status" => Err(GitError::from("Command not implemented")),
+        "restore" => repo::discover().and_then(|repo| restore(&repo, &args[2..])),
+        "status" => repo::discover().and_then(|repo| status(&repo, &args[2..])),
+        "switch" => repo::discover().and_then(|repo| checkout(&repo, &args[2..], true)),
         // Plumbing commands
         "cat-file" =>  {
             if args.len() != 3 {
                 println!("usage: {} cat-file <sha1>", &args[0]);
                 return;
             }
-            cat_file(&args[2])
+            repo::discover().and_then(|repo| cat_file(&repo, &args[2]))
         },
-        "hash-object" => hash_object(),
+        "check-ignore" => repo::discover().and_then(|repo| check_ignore(&repo, &args[2..])),
+        "ls-files" => repo::discover().and_then(|repo| ls_files(&repo, &args[2..])),
+        "hash-object" => repo::discover().and_then(|repo| hash_object(&repo)),
+        "pack-objects" => repo::discover().and_then(|repo| pack_objects(&repo, &args[2..])),
         "show-commit" => {
             if args.len() != 3 {
                 println!("usage: {} show-commit <sha1>", &args[0]);
                 return;
             }
-            show_commit(&args[2])
+            repo::discover().and_then(|repo| show_commit(&repo, &args[2]))
         },
         "show-tree" => {
             if args.len() != 3 {
                 println!("usage: {} commit <sha1>", &args[0]);
                 return;
             }
-            show_tree(&args[2])
+            repo::discover().and_then(|repo| show_tree(&repo, &args[2]))
         },
-        "rev-parse" => rev_parse(&args[2..]),
-        "write-tree" => write_tree(),
+        "repack" => repo::discover().and_then(|repo| gc::gc(&repo, None)),
+        "rev-parse" => repo::discover().and_then(|repo| rev_parse(&repo, &args[2..])),
+        "update-index" => repo::discover().and_then(|repo| update_index(&repo, &args[2..])),
+        "write-tree" => repo::discover().and_then(|repo| write_tree(&repo)),
         _ => {
             println!("usage: {} <command> [<args>]", &args[0]);
             return;
@@ -268,6 +1102,10 @@ fn main() {
 
     match result {
         Ok(_) => (),
+        Err(GitError::Aborted(msg)) => {
+            eprint!("{}", msg);
+            process::exit(1);
+        },
         Err(err) => println!("fatal: {}", err),
     }
 }
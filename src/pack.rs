@@ -0,0 +1,767 @@
+extern crate byteorder;
+
+use cache::{self, Object, ObjectType};
+use delta;
+use flate2::{Compression, Crc};
+use flate2::bufread::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use self::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use sha1::{Digest, Sha1};
+use parse;
+use repo::Repository;
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use types::{GitError, GitResult};
+
+// An in-memory copy of a version 2 pack index, plus the pack it describes
+pub struct PackIndex {
+    pub pack_path: PathBuf,
+    fanout: [u32; 256],
+    hashes: Vec<[u8; 20]>,
+    offsets: Vec<u64>,
+}
+
+// Delta chains longer than this are assumed to be corrupt
+const MAX_DELTA_DEPTH: usize = 10000;
+
+// How many recently resolved delta bases to keep in memory
+const BASE_CACHE_SIZE: usize = 64;
+
+// A delta base: the pack it came from, its offset there, and its contents
+type CachedBase = (PathBuf, u64, ObjectType, Vec<u8>);
+
+thread_local! {
+    // Pack indexes are loaded once per process, the first time we need them,
+    // along with the pack directory they came from
+    static PACKS: RefCell<Option<(PathBuf, Rc<Vec<PackIndex>>)>> = const { RefCell::new(None) };
+
+    // Most recently used first, keyed by pack path and offset
+    static BASE_CACHE: RefCell<VecDeque<CachedBase>> = const { RefCell::new(VecDeque::new()) };
+}
+
+// Load a .idx file from disk
+pub fn read_index(idx_path: &Path) -> GitResult<PackIndex> {
+    let mut data = Vec::new();
+    File::open(idx_path)?.read_to_end(&mut data)?;
+    let mut cursor = Cursor::new(&data);
+
+    let mut sig = vec![0; 8];
+    cursor.read_exact(&mut sig)?;
+    if sig != b"\xfftOc\0\0\0\x02" {
+        return Err(GitError::from("Unsupported pack index version"));
+    }
+
+    let mut fanout = [0; 256];
+    for count in fanout.iter_mut() {
+        *count = cursor.read_u32::<BigEndian>()?;
+    }
+    let num_objects = fanout[255] as usize;
+
+    let mut hashes = Vec::with_capacity(num_objects);
+    for _ in 0..num_objects {
+        let mut hash = [0; 20];
+        cursor.read_exact(&mut hash)?;
+        hashes.push(hash);
+    }
+
+    // Skip over the CRC32 table, we don't verify entries on read
+    cursor.seek(SeekFrom::Current(4 * num_objects as i64))?;
+
+    // Offsets with the high bit set point into the table of 64-bit offsets
+    let mut small_offsets = Vec::with_capacity(num_objects);
+    for _ in 0..num_objects {
+        small_offsets.push(cursor.read_u32::<BigEndian>()?);
+    }
+    let mut offsets = Vec::with_capacity(num_objects);
+    for offset in small_offsets {
+        if offset & 0x8000_0000 == 0 {
+            offsets.push(offset as u64);
+        } else {
+            let pos = cursor.position();
+            let large_ndx = (offset & 0x7fff_ffff) as u64;
+            cursor.set_position(pos + large_ndx * 8);
+            offsets.push(cursor.read_u64::<BigEndian>()?);
+            cursor.set_position(pos);
+        }
+    }
+
+    Ok(PackIndex {
+        pack_path: idx_path.with_extension("pack"),
+        fanout,
+        hashes,
+        offsets,
+    })
+}
+
+impl PackIndex {
+    // Every object in the pack, in sorted order
+    pub fn hashes(&self) -> &[[u8; 20]] {
+        &self.hashes
+    }
+
+    // Find the offset of an object in the pack, if the pack contains it
+    pub fn find(&self, hash: &[u8; 20]) -> Option<u64> {
+        let first = hash[0] as usize;
+        let start = if first == 0 { 0 } else { self.fanout[first - 1] as usize };
+        let end = self.fanout[first] as usize;
+        match self.hashes[start..end].binary_search(hash) {
+            Ok(ndx) => Some(self.offsets[start + ndx]),
+            Err(_) => None,
+        }
+    }
+
+    // Every object whose hex name starts with prefix
+    pub fn find_prefix(&self, prefix: &str) -> Vec<[u8; 20]> {
+        // Pad the prefix with zeros to get the smallest hash it could match
+        let mut lowest = [0; 20];
+        for (i, c) in prefix.chars().enumerate() {
+            let nibble = c.to_digit(16).unwrap_or(0) as u8;
+            lowest[i / 2] |= if i % 2 == 0 { nibble << 4 } else { nibble };
+        }
+        let first = lowest[0] as usize;
+        let start = if first == 0 { 0 } else { self.fanout[first - 1] as usize };
+        let end = self.fanout[first] as usize;
+        let from = match self.hashes[start..end].binary_search(&lowest) {
+            Ok(ndx) | Err(ndx) => start + ndx,
+        };
+        self.hashes[from..end].iter()
+            .take_while(|hash| parse::bytes_to_hex(*hash).starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+// Every packed object whose hex name starts with prefix
+pub fn find_prefix(repo: &Repository, prefix: &str) -> GitResult<Vec<[u8; 20]>> {
+    let mut result = Vec::new();
+    for pack in packs(repo)?.iter() {
+        result.extend(pack.find_prefix(prefix));
+    }
+    Ok(result)
+}
+
+// Scan the pack directory for .idx files
+fn load_packs(pack_dir: &Path) -> GitResult<Vec<PackIndex>> {
+    let mut packs = Vec::new();
+    let dir = match fs::read_dir(pack_dir) {
+        Ok(dir) => dir,
+        Err(err) => match err.kind() {
+            // No pack directory just means no packs
+            io::ErrorKind::NotFound => return Ok(packs),
+            _ => return Err(GitError::from(err)),
+        },
+    };
+    for entry in dir {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "idx")
+                && path.with_extension("pack").is_file() {
+            packs.push(read_index(&path)?);
+        }
+    }
+    Ok(packs)
+}
+
+fn packs(repo: &Repository) -> GitResult<Rc<Vec<PackIndex>>> {
+    let pack_dir = repo.path("objects/pack");
+    let cached = PACKS.with(|p| p.borrow().clone());
+    match cached {
+        Some((ref dir, ref packs)) if *dir == pack_dir => Ok(packs.clone()),
+        _ => {
+            let packs = Rc::new(load_packs(&pack_dir)?);
+            PACKS.with(|p| *p.borrow_mut() = Some((pack_dir, packs.clone())));
+            Ok(packs)
+        },
+    }
+}
+
+// Forget the loaded pack indexes, e.g. after packs have been added or removed
+pub fn clear_cache() {
+    PACKS.with(|p| *p.borrow_mut() = None);
+    BASE_CACHE.with(|c| c.borrow_mut().clear());
+}
+
+// Read the variable-length type and size header of a pack entry
+fn read_entry_header<R: Read>(reader: &mut R) -> GitResult<(u8, usize)> {
+    let mut byte = reader.read_u8()?;
+    let type_num = (byte >> 4) & 0b111;
+    let mut size = (byte & 0b1111) as usize;
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = reader.read_u8()?;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+    }
+    Ok((type_num, size))
+}
+
+// Inflate exactly size bytes of zlib data from the reader
+fn inflate<R: io::BufRead>(reader: R, size: usize) -> GitResult<Vec<u8>> {
+    let mut decoder = ZlibDecoder::new(reader);
+    let mut data = vec![0; size];
+    decoder.read_exact(&mut data)?;
+    Ok(data)
+}
+
+// A raw pack entry, deltas still need their base applied
+enum PackEntry {
+    Whole(ObjectType, Vec<u8>),
+    OfsDelta(u64, Vec<u8>),
+    RefDelta([u8; 20], Vec<u8>),
+}
+
+// Read the entry stored at an offset in a pack file
+fn read_entry(pack_path: &Path, offset: u64) -> GitResult<PackEntry> {
+    let mut reader = BufReader::new(File::open(pack_path)?);
+
+    let mut sig = vec![0; 8];
+    reader.read_exact(&mut sig)?;
+    if sig != b"PACK\0\0\0\x02" && sig != b"PACK\0\0\0\x03" {
+        return Err(GitError::from("Bad pack file signature"));
+    }
+
+    reader.seek(SeekFrom::Start(offset))?;
+    let (type_num, size) = read_entry_header(&mut reader)?;
+    let kind = match type_num {
+        1 => ObjectType::Commit,
+        2 => ObjectType::Tree,
+        3 => ObjectType::Blob,
+        4 => ObjectType::Tag,
+        6 => {
+            // The base is a negative offset from this entry
+            let mut byte = reader.read_u8()?;
+            let mut rel = (byte & 0x7f) as u64;
+            while byte & 0x80 != 0 {
+                byte = reader.read_u8()?;
+                rel = ((rel + 1) << 7) | (byte & 0x7f) as u64;
+            }
+            if rel == 0 || rel > offset {
+                return Err(GitError::from("Invalid delta base offset"));
+            }
+            return Ok(PackEntry::OfsDelta(offset - rel, inflate(reader, size)?));
+        },
+        7 => {
+            let mut base_hash = [0; 20];
+            reader.read_exact(&mut base_hash)?;
+            return Ok(PackEntry::RefDelta(base_hash, inflate(reader, size)?));
+        },
+        _ => return Err(GitError::from("Invalid packed object type")),
+    };
+
+    Ok(PackEntry::Whole(kind, inflate(reader, size)?))
+}
+
+// Look up a base, moving it to the front so it's the last to be evicted
+fn cached_base(pack_path: &Path, offset: u64) -> Option<(ObjectType, Vec<u8>)> {
+    BASE_CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        let pos = cache.iter().position(|entry| entry.1 == offset && entry.0 == pack_path)?;
+        let entry = cache.remove(pos)?;
+        let base = (entry.2, entry.3.clone());
+        cache.push_front(entry);
+        Some(base)
+    })
+}
+
+fn store_base(pack_path: &Path, offset: u64, kind: ObjectType, data: &[u8]) {
+    BASE_CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        if let Some(pos) = cache.iter().position(|entry| entry.1 == offset && entry.0 == pack_path) {
+            let entry = cache.remove(pos).unwrap();
+            cache.push_front(entry);
+            return;
+        }
+        if cache.len() >= BASE_CACHE_SIZE {
+            cache.pop_back();
+        }
+        cache.push_front((pack_path.to_path_buf(), offset, kind, data.to_vec()));
+    });
+}
+
+// Read an entry, following its chain of delta bases back to a whole object
+fn resolve_entry(repo: &Repository, packs: &[PackIndex], pack_path: &Path, offset: u64)
+        -> GitResult<Object> {
+    // Deltas we still need to apply, outermost first
+    let mut chain: Vec<(PathBuf, u64, Vec<u8>)> = Vec::new();
+    let mut path = pack_path.to_path_buf();
+    let mut offset = offset;
+
+    let (kind, mut data) = loop {
+        if let Some(base) = cached_base(&path, offset) {
+            break base;
+        }
+        if chain.iter().any(|link| link.1 == offset && link.0 == path) {
+            return Err(GitError::from("Delta chain contains a cycle"));
+        }
+        if chain.len() >= MAX_DELTA_DEPTH {
+            return Err(GitError::from("Delta chain is too long"));
+        }
+
+        match read_entry(&path, offset)? {
+            PackEntry::Whole(kind, data) => {
+                if !chain.is_empty() {
+                    store_base(&path, offset, kind, &data);
+                }
+                break (kind, data);
+            },
+            PackEntry::OfsDelta(base_offset, delta) => {
+                chain.push((path.clone(), offset, delta));
+                offset = base_offset;
+            },
+            PackEntry::RefDelta(base_hash, delta) => {
+                chain.push((path.clone(), offset, delta));
+                match find_in_packs(packs, &base_hash) {
+                    Some((base_path, base_offset)) => {
+                        path = base_path;
+                        offset = base_offset;
+                    },
+                    // The base may also be a loose object
+                    None => match cache::read_loose(repo, &parse::bytes_to_hex(&base_hash))? {
+                        Some(obj) => break (obj.kind, obj.data),
+                        None => return Err(GitError::from("Missing delta base object")),
+                    },
+                }
+            },
+        }
+    };
+
+    while let Some((link_path, link_offset, delta)) = chain.pop() {
+        data = delta::apply_delta(&data, &delta)?;
+        // Anything still below the top of the chain is some delta's base
+        if !chain.is_empty() {
+            store_base(&link_path, link_offset, kind, &data);
+        }
+    }
+
+    Ok(Object {
+        kind,
+        data,
+    })
+}
+
+fn find_in_packs(packs: &[PackIndex], hash: &[u8; 20]) -> Option<(PathBuf, u64)> {
+    for pack in packs {
+        if let Some(offset) = pack.find(hash) {
+            return Some((pack.pack_path.clone(), offset));
+        }
+    }
+    None
+}
+
+// Look for an object in every pack in the repository
+pub fn read_packed(repo: &Repository, hash: &[u8; 20]) -> GitResult<Option<Object>> {
+    let packs = packs(repo)?;
+    match find_in_packs(&packs, hash) {
+        Some((path, offset)) => Ok(Some(resolve_entry(repo, &packs, &path, offset)?)),
+        None => Ok(None),
+    }
+}
+
+// An object chosen for a pack, possibly stored as a delta
+struct PackCandidate {
+    hash: [u8; 20],
+    kind: ObjectType,
+    data: Vec<u8>,
+    // Position of the base in the write order, the delta, and chain depth
+    delta: Option<(usize, Vec<u8>)>,
+    depth: usize,
+}
+
+fn type_number(kind: ObjectType) -> u8 {
+    match kind {
+        ObjectType::Commit => 1,
+        ObjectType::Tree => 2,
+        ObjectType::Blob => 3,
+        ObjectType::Tag => 4,
+    }
+}
+
+fn write_entry_header(out: &mut Vec<u8>, type_num: u8, mut size: usize) {
+    let mut byte = (type_num << 4) | (size & 0b1111) as u8;
+    size >>= 4;
+    while size != 0 {
+        out.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    out.push(byte);
+}
+
+// How far back an OFS_DELTA's base is, most significant bits first, with
+// one subtracted before each shift so no value has two encodings
+fn write_base_offset(out: &mut Vec<u8>, mut rel: u64) {
+    let mut rel_bytes = vec![(rel & 0x7f) as u8];
+    rel >>= 7;
+    while rel != 0 {
+        rel -= 1;
+        rel_bytes.push(0x80 | (rel & 0x7f) as u8);
+        rel >>= 7;
+    }
+    rel_bytes.reverse();
+    out.extend_from_slice(&rel_bytes);
+}
+
+fn deflate(data: &[u8]) -> GitResult<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+// Try deltifying each blob and tree against the objects just before it
+fn find_deltas(objects: &mut [PackCandidate], window: usize, max_depth: usize) {
+    for i in 0..objects.len() {
+        match objects[i].kind {
+            ObjectType::Blob | ObjectType::Tree => (),
+            _ => continue,
+        }
+        // Not worth it unless the delta is well under the object's size
+        let mut best_size = objects[i].data.len() / 2;
+        let mut best = None;
+        for j in i.saturating_sub(window)..i {
+            if objects[j].kind != objects[i].kind || objects[j].depth >= max_depth {
+                continue;
+            }
+            let delta = delta::create_delta(&objects[j].data, &objects[i].data);
+            if delta.len() < best_size {
+                best_size = delta.len();
+                best = Some((j, delta));
+            }
+        }
+        if let Some((j, delta)) = best {
+            objects[i].depth = objects[j].depth + 1;
+            objects[i].delta = Some((j, delta));
+        }
+    }
+}
+
+// Write an atomically renamed file alongside its final location
+fn write_file(path: &Path, data: &[u8]) -> GitResult<()> {
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut f = File::create(&tmp_path)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// A version 2 index for a pack, given the hash, CRC32 and offset of each
+// object in it
+fn build_index(entries: &mut [([u8; 20], u32, u64)], pack_digest: &Digest)
+        -> GitResult<Vec<u8>> {
+    // The index lists everything sorted by hash
+    entries.sort_by_key(|entry| entry.0);
+
+    let mut idx = Vec::new();
+    idx.write_all(b"\xfftOc\0\0\0\x02")?;
+    let mut count = 0;
+    for first in 0..256 {
+        while count < entries.len() && entries[count].0[0] as usize <= first {
+            count += 1;
+        }
+        idx.write_u32::<BigEndian>(count as u32)?;
+    }
+    for entry in entries.iter() {
+        idx.write_all(&entry.0)?;
+    }
+    for entry in entries.iter() {
+        idx.write_u32::<BigEndian>(entry.1)?;
+    }
+    let mut large_offsets = Vec::new();
+    for entry in entries.iter() {
+        if entry.2 < 0x8000_0000 {
+            idx.write_u32::<BigEndian>(entry.2 as u32)?;
+        } else {
+            idx.write_u32::<BigEndian>(0x8000_0000 | large_offsets.len() as u32)?;
+            large_offsets.push(entry.2);
+        }
+    }
+    for offset in large_offsets {
+        idx.write_u64::<BigEndian>(offset)?;
+    }
+    idx.write_all(&pack_digest.bytes())?;
+    let idx_digest = {
+        let mut m = Sha1::new();
+        m.update(&idx);
+        m.digest()
+    };
+    idx.write_all(&idx_digest.bytes())?;
+    Ok(idx)
+}
+
+// Write a version 2 pack and index containing the given objects
+// The files are named <base_name>-<pack sha1>.pack and .idx
+pub fn write_pack(repo: &Repository, hashes: &[[u8; 20]], base_name: &str,
+                  window: usize, max_depth: usize) -> GitResult<Digest> {
+    let mut seen = HashSet::new();
+    let mut objects = Vec::new();
+    for hash in hashes {
+        if !seen.insert(*hash) {
+            continue;
+        }
+        let obj = cache::read_obj(repo, &parse::bytes_to_hex(hash))?;
+        objects.push(PackCandidate {
+            hash: *hash, kind: obj.kind, data: obj.data, delta: None, depth: 0,
+        });
+    }
+
+    // Group by type with the largest objects first, so deltas mostly remove
+    // data from a base rather than add it
+    objects.sort_by(|a, b| type_number(a.kind).cmp(&type_number(b.kind))
+                            .then(b.data.len().cmp(&a.data.len())));
+    if window > 0 {
+        find_deltas(&mut objects, window, max_depth);
+    }
+
+    // Bases always come earlier in the write order, so every delta can
+    // refer to its base by offset
+    let mut pack = Vec::new();
+    pack.write_all(b"PACK\0\0\0\x02")?;
+    pack.write_u32::<BigEndian>(objects.len() as u32)?;
+    let mut offsets: Vec<u64> = Vec::with_capacity(objects.len());
+    let mut crcs = Vec::with_capacity(objects.len());
+    for obj in objects.iter() {
+        let offset = pack.len() as u64;
+        let mut entry = Vec::new();
+        match obj.delta {
+            Some((base_ndx, ref delta)) => {
+                write_entry_header(&mut entry, 6, delta.len());
+                write_base_offset(&mut entry, offset - offsets[base_ndx]);
+                entry.extend_from_slice(&deflate(delta)?);
+            },
+            None => {
+                write_entry_header(&mut entry, type_number(obj.kind), obj.data.len());
+                entry.extend_from_slice(&deflate(&obj.data)?);
+            },
+        }
+        let mut crc = Crc::new();
+        crc.update(&entry);
+        crcs.push(crc.sum());
+        offsets.push(offset);
+        pack.extend_from_slice(&entry);
+    }
+    let pack_digest = {
+        let mut m = Sha1::new();
+        m.update(&pack);
+        m.digest()
+    };
+    pack.extend_from_slice(&pack_digest.bytes());
+
+    let mut entries: Vec<([u8; 20], u32, u64)> = objects.iter().enumerate()
+        .map(|(i, obj)| (obj.hash, crcs[i], offsets[i]))
+        .collect();
+    let idx = build_index(&mut entries, &pack_digest)?;
+
+    // Write the pack first, since an index is what makes a pack visible
+    let name = format!("{}-{}", base_name, pack_digest);
+    write_file(Path::new(&format!("{}.pack", name)), &pack)?;
+    write_file(Path::new(&format!("{}.idx", name)), &idx)?;
+    clear_cache();
+
+    Ok(pack_digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::TempDir;
+
+    // Text with a few lines that differ between versions, so that versions
+    // make good deltas of each other
+    fn version(n: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for line in 0..200 {
+            if line % 50 == n {
+                writeln!(data, "line {} was changed in version {}", line, n).unwrap();
+            } else {
+                writeln!(data, "line {} is the same in every version", line).unwrap();
+            }
+        }
+        data
+    }
+
+    fn blob_hash(data: &[u8]) -> [u8; 20] {
+        Object { kind: ObjectType::Blob, data: data.to_vec() }.hash().bytes()
+    }
+
+    fn whole_entry(data: &[u8]) -> Vec<u8> {
+        let mut entry = Vec::new();
+        write_entry_header(&mut entry, type_number(ObjectType::Blob), data.len());
+        entry.extend_from_slice(&deflate(data).unwrap());
+        entry
+    }
+
+    fn ofs_delta_entry(rel: u64, delta: &[u8]) -> Vec<u8> {
+        let mut entry = Vec::new();
+        write_entry_header(&mut entry, 6, delta.len());
+        write_base_offset(&mut entry, rel);
+        entry.extend_from_slice(&deflate(delta).unwrap());
+        entry
+    }
+
+    fn ref_delta_entry(base: &[u8; 20], delta: &[u8]) -> Vec<u8> {
+        let mut entry = Vec::new();
+        write_entry_header(&mut entry, 7, delta.len());
+        entry.extend_from_slice(base);
+        entry.extend_from_slice(&deflate(delta).unwrap());
+        entry
+    }
+
+    // Write a pack and its index from entries that are already encoded
+    fn write_raw_pack(repo: &Repository, entries: &[([u8; 20], Vec<u8>)]) {
+        let mut pack = Vec::new();
+        pack.write_all(b"PACK\0\0\0\x02").unwrap();
+        pack.write_u32::<BigEndian>(entries.len() as u32).unwrap();
+        let mut index_entries = Vec::new();
+        for (hash, entry) in entries.iter() {
+            let mut crc = Crc::new();
+            crc.update(entry);
+            index_entries.push((*hash, crc.sum(), pack.len() as u64));
+            pack.extend_from_slice(entry);
+        }
+        let digest = {
+            let mut m = Sha1::new();
+            m.update(&pack);
+            m.digest()
+        };
+        pack.extend_from_slice(&digest.bytes());
+        let idx = build_index(&mut index_entries, &digest).unwrap();
+        let name = repo.path(format!("objects/pack/pack-{}", digest));
+        write_file(&name.with_extension("pack"), &pack).unwrap();
+        write_file(&name.with_extension("idx"), &idx).unwrap();
+        clear_cache();
+    }
+
+    #[test]
+    fn write_then_read_with_ofs_deltas() {
+        let dir = TempDir::new("pack-ofs-deltas");
+        let repo = dir.repo();
+        let mut hashes = Vec::new();
+        for n in 0..5 {
+            let obj = Object { kind: ObjectType::Blob, data: version(n) };
+            hashes.push(obj.write(&repo).unwrap().bytes());
+        }
+        let base_name = repo.path("objects/pack/pack");
+        write_pack(&repo, &hashes, base_name.to_str().unwrap(), 10, 50).unwrap();
+
+        // Make sure what's read back comes from the pack
+        for hash in hashes.iter() {
+            fs::remove_file(cache::path_for_hash(&repo, &parse::bytes_to_hex(hash))).unwrap();
+        }
+
+        let packs = load_packs(&repo.path("objects/pack")).unwrap();
+        assert_eq!(packs.len(), 1);
+        let deltas = hashes.iter()
+            .map(|hash| read_entry(&packs[0].pack_path, packs[0].find(hash).unwrap()).unwrap())
+            .filter(|entry| matches!(*entry, PackEntry::OfsDelta(..)))
+            .count();
+        assert_eq!(deltas, 4);
+
+        for (n, hash) in hashes.iter().enumerate() {
+            let obj = read_packed(&repo, hash).unwrap().unwrap();
+            assert!(obj.kind == ObjectType::Blob);
+            assert_eq!(obj.data, version(n));
+        }
+    }
+
+    #[test]
+    fn write_without_deltas() {
+        let dir = TempDir::new("pack-no-deltas");
+        let repo = dir.repo();
+        let obj = Object { kind: ObjectType::Blob, data: version(0) };
+        let hash = obj.write(&repo).unwrap().bytes();
+        let base_name = repo.path("objects/pack/pack");
+        write_pack(&repo, &[hash, hash], base_name.to_str().unwrap(), 0, 50).unwrap();
+
+        let packs = load_packs(&repo.path("objects/pack")).unwrap();
+        assert_eq!(packs[0].hashes(), &[hash]);
+        let entry = read_entry(&packs[0].pack_path, packs[0].find(&hash).unwrap()).unwrap();
+        assert!(matches!(entry, PackEntry::Whole(ObjectType::Blob, _)));
+    }
+
+    #[test]
+    fn read_ref_and_ofs_delta_chains() {
+        let dir = TempDir::new("pack-ref-deltas");
+        let repo = dir.repo();
+        let versions: Vec<Vec<u8>> = (0..5).map(version).collect();
+        let hashes: Vec<[u8; 20]> = versions.iter().map(|v| blob_hash(v)).collect();
+
+        // 1 is a REF_DELTA on 0, 2 an OFS_DELTA on 1, and 3 a REF_DELTA on 4,
+        // which is only a loose object
+        let entry0 = whole_entry(&versions[0]);
+        let entry1 = ref_delta_entry(&hashes[0], &delta::create_delta(&versions[0], &versions[1]));
+        let entry2 = ofs_delta_entry(entry1.len() as u64,
+                                     &delta::create_delta(&versions[1], &versions[2]));
+        let entry3 = ref_delta_entry(&hashes[4], &delta::create_delta(&versions[4], &versions[3]));
+        write_raw_pack(&repo, &[(hashes[0], entry0), (hashes[1], entry1),
+                                (hashes[2], entry2), (hashes[3], entry3)]);
+        Object { kind: ObjectType::Blob, data: versions[4].clone() }.write(&repo).unwrap();
+
+        for n in 0..4 {
+            let obj = read_packed(&repo, &hashes[n]).unwrap().unwrap();
+            assert_eq!(obj.data, versions[n]);
+        }
+        assert!(read_packed(&repo, &hashes[4]).unwrap().is_none());
+        // Every object can be read, whether it's in the pack or not
+        for (n, hash) in hashes.iter().enumerate() {
+            assert_eq!(cache::read_obj(&repo, &parse::bytes_to_hex(hash)).unwrap().data,
+                       versions[n]);
+        }
+    }
+
+    #[test]
+    fn missing_ref_delta_base() {
+        let dir = TempDir::new("pack-missing-base");
+        let repo = dir.repo();
+        let (base, target) = (version(0), version(1));
+        let entry = ref_delta_entry(&blob_hash(&base), &delta::create_delta(&base, &target));
+        write_raw_pack(&repo, &[(blob_hash(&target), entry)]);
+        assert!(read_packed(&repo, &blob_hash(&target)).is_err());
+    }
+
+    #[test]
+    fn base_offset_encoding() {
+        // Each extra byte adds one before shifting, as in git's pack format
+        let cases: &[(u64, &[u8])] = &[
+            (1, &[0x01]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x00]),
+            (16511, &[0xff, 0x7f]),
+            (16512, &[0x80, 0x80, 0x00]),
+        ];
+        for &(rel, encoded) in cases {
+            let mut out = Vec::new();
+            write_base_offset(&mut out, rel);
+            assert_eq!(out, encoded);
+        }
+    }
+
+    #[test]
+    fn index_with_large_offsets() {
+        let dir = TempDir::new("pack-large-offsets");
+        let offsets = [12, 0x7fff_ffff, 0x8000_0000, 0x1_2345_6789, 0xffff_ffff_0000];
+        let mut entries: Vec<([u8; 20], u32, u64)> = offsets.iter().enumerate()
+            .map(|(i, &offset)| {
+                let mut hash = [0x11; 20];
+                hash[0] = [0x00, 0x7f, 0x7f, 0xa0, 0xff][i];
+                hash[19] = i as u8;
+                (hash, 0, offset)
+            })
+            .collect();
+        let idx = build_index(&mut entries, &Sha1::new().digest()).unwrap();
+        let path = dir.path.join("pack-test.idx");
+        write_file(&path, &idx).unwrap();
+
+        let index = read_index(&path).unwrap();
+        assert_eq!(index.hashes().len(), offsets.len());
+        for &(hash, _, offset) in entries.iter() {
+            assert_eq!(index.find(&hash), Some(offset));
+        }
+        assert_eq!(index.find(&[0x7f; 20]), None);
+        assert_eq!(index.find_prefix("7f11").len(), 2);
+    }
+}
@@ -1,5 +1,5 @@
 use std::io::Read;
-use types::GitResult;
+use types::{GitError, GitResult};
 
 // Read from a reader up to, and not including, some end character
 pub fn read_until(reader: &mut Read, end: u8) -> GitResult<Vec<u8>> {
@@ -16,3 +16,27 @@ pub fn read_until(reader: &mut Read, end: u8) -> GitResult<Vec<u8>> {
 
     Ok(content)
 }
+
+// Format a byte slice as a lowercase hex string
+pub fn bytes_to_hex(data: &[u8]) -> String {
+    let mut result = String::with_capacity(data.len() * 2);
+    for byte in data {
+        result.push_str(&format!("{:02x}", byte));
+    }
+    result
+}
+
+// Parse a full 40 character hex string into raw hash bytes
+pub fn hex_to_hash(hex: &str) -> GitResult<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return Err(GitError::from("Invalid object name"));
+    }
+    let mut hash = [0; 20];
+    for i in 0..20 {
+        hash[i] = match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+            Ok(b) => b,
+            Err(_) => return Err(GitError::from("Invalid object name")),
+        };
+    }
+    Ok(hash)
+}
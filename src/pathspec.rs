@@ -0,0 +1,46 @@
+// Pathspecs limit a command to some paths
+//
+// Each one is an index name relative to the top of the work tree. It
+// matches that path, everything under it if it's a directory, and if it has
+// glob characters, anything wildmatch accepts with "*" crossing "/", as in
+// git's default pathspec rules.
+
+use wildmatch::{self, MatchFlags};
+
+pub struct Pathspec {
+    // What was typed, for error messages
+    pub original: String,
+    name: Vec<u8>,
+    is_glob: bool,
+}
+
+impl Pathspec {
+    pub fn new(original: &str, name: Vec<u8>) -> Pathspec {
+        let is_glob = name.iter().any(|&c| c == b'*' || c == b'?' || c == b'[' || c == b'\\');
+        Pathspec { original: String::from(original), name, is_glob }
+    }
+
+    pub fn matches(&self, name: &[u8]) -> bool {
+        // The top of the work tree covers everything
+        if self.name.is_empty() || name == &self.name[..] {
+            return true;
+        }
+        if name.starts_with(&self.name) && name[self.name.len()] == b'/' {
+            return true;
+        }
+        let flags = MatchFlags { pathname: false, ignore_case: false };
+        self.is_glob && wildmatch::wildmatch(&self.name, name, &flags)
+    }
+}
+
+// Whether any of the pathspecs match a name, marking the ones that do
+pub fn match_any(specs: &[Pathspec], matched: &mut [bool], name: &[u8]) -> bool {
+    let mut any = false;
+    for (spec, seen) in specs.iter().zip(matched.iter_mut()) {
+        if spec.matches(name) {
+            *seen = true;
+            any = true;
+        }
+    }
+    any
+}
@@ -1,30 +1,288 @@
-use std::io::Read;
-use std::fs::File;
+use chrono::{DateTime, FixedOffset};
+use repo::Repository;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::fs::{self, File};
 use std::path::Path;
-use types::GitResult;
+use types::{GitError, GitResult};
+
+// The all-zero object name, used for refs that didn't exist
+pub const NULL_HASH: &str = "0000000000000000000000000000000000000000";
+
+pub struct ReflogEntry {
+    pub new: String,
+    pub message: String,
+}
 
 // Read a ref, recurse if there is ever a symbolic ref
 // TODO: deal with symbolic ref loops
-pub fn read_ref(name: &str) -> GitResult<String> {
+pub fn read_ref(repo: &Repository, name: &str) -> GitResult<String> {
     let data = {
         let mut buf = String::new();
-        let mut f = File::open(&Path::new(".git").join(name))?;
+        let mut f = match File::open(repo.path(name)) {
+            Ok(f) => f,
+            Err(err) => match err.kind() {
+                // Refs that aren't loose may have been packed
+                io::ErrorKind::NotFound => return match find_packed_ref(repo, name)? {
+                    Some(hash) => Ok(hash),
+                    None => Err(GitError::from(err)),
+                },
+                _ => return Err(GitError::from(err)),
+            },
+        };
         f.read_to_string(&mut buf)?;
         // Don't need a newline
         buf.pop();
         buf
     };
 
-    if data.starts_with("ref: ") {
+    match data.strip_prefix("ref: ") {
         // Symbolic ref
-        read_ref(&data[5..])
-    } else {
-        Ok(data)
+        Some(target) => read_ref(repo, target),
+        None => Ok(data),
+    }
+}
+
+// If a ref is symbolic, get the name of the ref it points to
+pub fn symbolic_target(repo: &Repository, name: &str) -> GitResult<Option<String>> {
+    let mut buf = String::new();
+    match File::open(repo.path(name)) {
+        Ok(mut f) => f.read_to_string(&mut buf)?,
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => return Ok(None),
+            _ => return Err(GitError::from(err)),
+        },
+    };
+    match buf.strip_prefix("ref: ") {
+        Some(target) => Ok(Some(String::from(target.trim_end()))),
+        None => Ok(None),
+    }
+}
+
+// Read the reflog for a ref, oldest entry first
+pub fn read_reflog(repo: &Repository, name: &str) -> GitResult<Vec<ReflogEntry>> {
+    let f = match File::open(repo.path("logs").join(name)) {
+        Ok(f) => f,
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => return Ok(Vec::new()),
+            _ => return Err(GitError::from(err)),
+        },
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        // <old> <new> <committer> <timestamp> <tz>\t<message>
+        if line.len() < 82 || !line.is_char_boundary(82) {
+            return Err(GitError::from("Malformed reflog entry"));
+        }
+        let message = match line[82..].find('\t') {
+            Some(ndx) => &line[83 + ndx..],
+            None => "",
+        };
+        entries.push(ReflogEntry {
+            new: String::from(&line[41..81]),
+            message: String::from(message),
+        });
+    }
+    Ok(entries)
+}
+
+// Read a ref's own value without following symbolic refs
+// Returns None if the ref doesn't exist
+fn read_raw_ref(repo: &Repository, name: &str) -> GitResult<Option<String>> {
+    let mut buf = String::new();
+    match File::open(repo.path(name)) {
+        Ok(mut f) => {
+            f.read_to_string(&mut buf)?;
+            Ok(Some(String::from(buf.trim_end())))
+        },
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => find_packed_ref(repo, name),
+            _ => Err(GitError::from(err)),
+        },
+    }
+}
+
+// Whether updates to this ref should be recorded in its reflog
+fn should_log(repo: &Repository, name: &str) -> bool {
+    name == "HEAD" || name.starts_with("refs/heads/") || name.starts_with("refs/remotes/")
+        || repo.path("logs").join(name).is_file()
+}
+
+// Append an entry to a ref's reflog
+pub fn append_reflog(repo: &Repository, name: &str, old: Option<&str>, new: &str,
+                     committer: &str, date: &DateTime<FixedOffset>, message: &str)
+        -> GitResult<()> {
+    let path = repo.path("logs").join(name);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut f = fs::OpenOptions::new().append(true).create(true).open(path)?;
+    // Reflog messages are a single line
+    let message = message.replace('\n', " ");
+    writeln!(f, "{} {} {} {}\t{}", old.unwrap_or(NULL_HASH), new, committer,
+             date.format("%s %z"), message.trim())?;
+    Ok(())
+}
+
+fn create_lock(lock_path: &Path) -> GitResult<File> {
+    match fs::OpenOptions::new().write(true).create_new(true).open(lock_path) {
+        Ok(f) => Ok(f),
+        Err(err) => match err.kind() {
+            io::ErrorKind::AlreadyExists => Err(GitError::from(format!(
+                "Unable to create '{}': File exists. Another git process seems \
+                 to be running in this repository", lock_path.display()))),
+            _ => Err(GitError::from(err)),
+        },
+    }
+}
+
+// Point a ref at a new object, through a <ref>.lock file
+// The update fails unless the ref's current value is old, where None means
+// the ref must not exist yet. HEAD's reflog is also updated if HEAD is a
+// symbolic ref to this one.
+pub fn update_ref(repo: &Repository, name: &str, new: &str, old: Option<&str>,
+                  committer: &str, date: &DateTime<FixedOffset>, message: &str)
+        -> GitResult<()> {
+    let path = repo.path(name);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let lock_path = repo.path(format!("{}.lock", name));
+    let mut lock = create_lock(&lock_path)?;
+
+    // Now that we hold the lock, make sure nobody changed the ref under us
+    let current = match read_raw_ref(repo, name) {
+        Ok(current) => current,
+        Err(err) => {
+            let _ = fs::remove_file(&lock_path);
+            return Err(err);
+        },
+    };
+    if current.as_deref() != old {
+        let _ = fs::remove_file(&lock_path);
+        return Err(GitError::from(format!("cannot lock ref '{}': is at {} but expected {}",
+                                          name, current.unwrap_or(String::from(NULL_HASH)),
+                                          old.unwrap_or(NULL_HASH))));
+    }
+
+    let result = lock.write_all(format!("{}\n", new).as_bytes())
+        .and_then(|_| lock.sync_all())
+        .and_then(|_| fs::rename(&lock_path, &path));
+    match result {
+        Ok(_) => (),
+        Err(err) => {
+            let _ = fs::remove_file(&lock_path);
+            return Err(GitError::from(err));
+        },
+    }
+
+    if should_log(repo, name) {
+        append_reflog(repo, name, old, new, committer, date, message)?;
+    }
+    let head_target = symbolic_target(repo, "HEAD")?;
+    if name != "HEAD" && head_target.as_deref() == Some(name) {
+        append_reflog(repo, "HEAD", old, new, committer, date, message)?;
+    }
+    Ok(())
+}
+
+// Point HEAD at a branch, or detach it at new when branch is None, and log
+// the move from old to new in HEAD's reflog
+pub fn set_head(repo: &Repository, branch: Option<&str>, old: Option<&str>, new: &str,
+                committer: &str, date: &DateTime<FixedOffset>, message: &str)
+        -> GitResult<()> {
+    let path = repo.path("HEAD");
+    let lock_path = repo.path("HEAD.lock");
+    let mut lock = create_lock(&lock_path)?;
+    let contents = match branch {
+        Some(branch) => format!("ref: {}\n", branch),
+        None => format!("{}\n", new),
+    };
+    let result = lock.write_all(contents.as_bytes())
+        .and_then(|_| lock.sync_all())
+        .and_then(|_| fs::rename(&lock_path, &path));
+    match result {
+        Ok(_) => (),
+        Err(err) => {
+            let _ = fs::remove_file(&lock_path);
+            return Err(GitError::from(err));
+        },
+    }
+    append_reflog(repo, "HEAD", old, new, committer, date, message)
+}
+
+// Read every (name, hash) pair from packed-refs
+pub fn read_packed_refs(repo: &Repository) -> GitResult<Vec<(String, String)>> {
+    let f = match File::open(repo.path("packed-refs")) {
+        Ok(f) => f,
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => return Ok(Vec::new()),
+            _ => return Err(GitError::from(err)),
+        },
+    };
+    let mut refs = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        // Skip the header and peeled tag lines
+        if line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(hash), Some(name)) => refs.push((String::from(name), String::from(hash))),
+            _ => return Err(GitError::from("Malformed packed-refs file")),
+        }
+    }
+    Ok(refs)
+}
+
+fn find_packed_ref(repo: &Repository, name: &str) -> GitResult<Option<String>> {
+    Ok(read_packed_refs(repo)?.into_iter()
+            .find(|(packed_name, _)| packed_name == name)
+            .map(|(_, hash)| hash))
+}
+
+// Recursively collect loose refs under a directory
+fn list_loose_refs(repo: &Repository, dir: &Path, prefix: &str,
+                   refs: &mut Vec<(String, String)>) -> GitResult<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => return Ok(()),
+            _ => return Err(GitError::from(err)),
+        },
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(s) => format!("{}/{}", prefix, s),
+            Err(_) => return Err(GitError::from("Invalid UTF-8 ref name")),
+        };
+        if entry.file_type()?.is_dir() {
+            list_loose_refs(repo, &entry.path(), &name, refs)?;
+        } else if !name.ends_with(".lock") {
+            let hash = read_ref(repo, &name)?;
+            refs.push((name, hash));
+        }
+    }
+    Ok(())
+}
+
+// List every ref under refs/, with loose refs shadowing packed ones
+pub fn list_refs(repo: &Repository) -> GitResult<Vec<(String, String)>> {
+    let mut refs = Vec::new();
+    list_loose_refs(repo, &repo.path("refs"), "refs", &mut refs)?;
+    for (name, hash) in read_packed_refs(repo)? {
+        if !refs.iter().any(|(loose_name, _)| *loose_name == name) {
+            refs.push((name, hash));
+        }
     }
+    refs.sort();
+    Ok(refs)
 }
 
 // Find the ref that corresponds to a refname, and read it
-pub fn expand_refname(refname: &str) -> GitResult<String> {
+pub fn expand_refname(repo: &Repository, refname: &str) -> GitResult<String> {
     let to_try = [
         Path::new(refname).to_path_buf(),
         Path::new("refs").join(refname),
@@ -33,12 +291,15 @@ pub fn expand_refname(refname: &str) -> GitResult<String> {
         Path::new("refs/remotes").join(refname),
         Path::new("refs/heads").join(refname).join("HEAD"),
     ];
+    let packed = read_packed_refs(repo)?;
     for path in to_try.iter() {
-        if Path::new(".git").join(path).is_file() {
-            return match path.to_str() {
-                Some(s) => Ok(String::from(s)),
-                None => Err("Invalid UTF-8 string".into()),
-            };
+        let name = match path.to_str() {
+            Some(s) => s,
+            None => return Err("Invalid UTF-8 string".into()),
+        };
+        if repo.path(path).is_file()
+                || packed.iter().any(|(packed_name, _)| packed_name == name) {
+            return Ok(String::from(name));
         }
     }
     Err("unknown revision or refname not in the working tree".into())
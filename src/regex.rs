@@ -0,0 +1,174 @@
+use types::{GitError, GitResult};
+
+// A small regular expression matcher
+// It supports literals, ".", "[...]" classes, the "*", "+" and "?"
+// repetitions, "^" and "$" anchors and backslash escapes. Like git, the
+// anchors match at the start and end of every line.
+// There are no groups, alternation or counted repetition, so "\(", "\)",
+// "\|", "\{" and "\}" are errors rather than literals.
+pub struct Regex {
+    anchor_start: bool,
+    anchor_end: bool,
+    ignore_case: bool,
+    items: Vec<Item>,
+}
+
+enum Atom {
+    Any,
+    Char(char),
+    // Negated, and a list of inclusive ranges
+    Class(bool, Vec<(char, char)>),
+}
+
+struct Item {
+    atom: Atom,
+    min: usize,
+    max: Option<usize>,
+}
+
+impl Atom {
+    fn matches(&self, c: char, ignore_case: bool) -> bool {
+        match *self {
+            Atom::Any => c != '\n',
+            Atom::Char(expected) => if ignore_case {
+                c.to_lowercase().eq(expected.to_lowercase())
+            } else {
+                c == expected
+            },
+            Atom::Class(negated, ref ranges) => {
+                let found = ranges.iter().any(|&(lo, hi)| {
+                    (lo <= c && c <= hi) || (ignore_case && c.to_lowercase().chain(c.to_uppercase())
+                            .any(|alt| lo <= alt && alt <= hi))
+                });
+                found != negated
+            },
+        }
+    }
+}
+
+// Parse the body of a [...] class, starting just after the "["
+fn parse_class(chars: &[char], pos: &mut usize) -> GitResult<Atom> {
+    let mut negated = false;
+    if *pos < chars.len() && chars[*pos] == '^' {
+        negated = true;
+        *pos += 1;
+    }
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        if *pos >= chars.len() {
+            return Err(GitError::from("Unterminated character class in regex"));
+        }
+        let c = chars[*pos];
+        *pos += 1;
+        // A "]" right at the start is a literal
+        if c == ']' && !first {
+            break;
+        }
+        first = false;
+        if *pos + 1 < chars.len() && chars[*pos] == '-' && chars[*pos + 1] != ']' {
+            ranges.push((c, chars[*pos + 1]));
+            *pos += 2;
+        } else {
+            ranges.push((c, c));
+        }
+    }
+    Ok(Atom::Class(negated, ranges))
+}
+
+pub fn compile(pattern: &str, ignore_case: bool) -> GitResult<Regex> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut pos = 0;
+    let mut anchor_start = false;
+    let mut anchor_end = false;
+    let mut items: Vec<Item> = Vec::new();
+
+    if chars.first() == Some(&'^') {
+        anchor_start = true;
+        pos += 1;
+    }
+    while pos < chars.len() {
+        let c = chars[pos];
+        pos += 1;
+        let atom = match c {
+            '$' if pos == chars.len() => {
+                anchor_end = true;
+                break;
+            },
+            '.' => Atom::Any,
+            '[' => parse_class(&chars, &mut pos)?,
+            '\\' => {
+                if pos >= chars.len() {
+                    return Err(GitError::from("Trailing backslash in regex"));
+                }
+                match chars[pos] {
+                    '(' | ')' | '|' | '{' | '}' => {
+                        return Err(GitError::from(format!("Unsupported regex operator \\{}",
+                                                          chars[pos])));
+                    },
+                    _ => (),
+                }
+                pos += 1;
+                Atom::Char(chars[pos - 1])
+            },
+            '*' | '+' | '?' => {
+                let item = match items.last_mut() {
+                    Some(item) => item,
+                    None => return Err(GitError::from("Nothing to repeat in regex")),
+                };
+                match c {
+                    '*' => { item.min = 0; item.max = None; },
+                    '+' => { item.max = None; },
+                    _ => { item.min = 0; },
+                }
+                continue;
+            },
+            _ => Atom::Char(c),
+        };
+        items.push(Item { atom, min: 1, max: Some(1) });
+    }
+
+    Ok(Regex { anchor_start, anchor_end, ignore_case, items })
+}
+
+impl Regex {
+    fn match_here(&self, items: &[Item], line: &[char], pos: usize) -> bool {
+        let item = match items.first() {
+            Some(item) => item,
+            None => return !self.anchor_end || pos == line.len(),
+        };
+        // Greedily take as many as we can, then back off
+        let mut count = 0;
+        while pos + count < line.len() && item.max.is_none_or(|max| count < max)
+                && item.atom.matches(line[pos + count], self.ignore_case) {
+            count += 1;
+        }
+        loop {
+            if count < item.min {
+                return false;
+            }
+            if self.match_here(&items[1..], line, pos + count) {
+                return true;
+            }
+            if count == 0 {
+                return false;
+            }
+            count -= 1;
+        }
+    }
+
+    fn match_line(&self, line: &[char]) -> bool {
+        if self.anchor_start {
+            return self.match_here(&self.items, line, 0);
+        }
+        (0..line.len() + 1).any(|start| self.match_here(&self.items, line, start))
+    }
+
+    // Whether any line of the text matches
+    pub fn is_match(&self, text: &str) -> bool {
+        text.split('\n').any(|line| {
+            let chars: Vec<char> = line.chars().collect();
+            self.match_line(&chars)
+        })
+    }
+}
@@ -0,0 +1,127 @@
+use config;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use types::{GitError, GitResult};
+
+pub struct Repository {
+    // Always absolute
+    pub git_dir: PathBuf,
+    // None for bare repositories
+    pub work_tree: Option<PathBuf>,
+}
+
+impl Repository {
+    // A path inside the git directory
+    pub fn path<P: AsRef<Path>>(&self, rel: P) -> PathBuf {
+        self.git_dir.join(rel)
+    }
+
+    // The work tree, for commands that can't run in a bare repository
+    pub fn work_tree(&self) -> GitResult<&Path> {
+        match self.work_tree {
+            Some(ref dir) => Ok(dir),
+            None => Err(GitError::from("this operation must be run in a work tree")),
+        }
+    }
+}
+
+fn absolute(path: &Path) -> GitResult<PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(env::current_dir()?.join(path))
+    }
+}
+
+// Whether a directory looks like a git directory
+fn is_git_dir(dir: &Path) -> bool {
+    dir.join("HEAD").is_file() && dir.join("objects").is_dir() && dir.join("refs").is_dir()
+}
+
+// Follow a "gitdir: <path>" file, as used by worktrees and submodules
+fn read_gitdir_file(file: &Path) -> GitResult<PathBuf> {
+    let mut contents = String::new();
+    File::open(file)?.read_to_string(&mut contents)?;
+    if !contents.starts_with("gitdir: ") {
+        return Err(GitError::from(format!("invalid gitfile format: {}", file.display())));
+    }
+    let target = Path::new(contents[8..].trim_end());
+    let dir = match file.parent() {
+        Some(parent) => parent.join(target),
+        None => target.to_path_buf(),
+    };
+    if !is_git_dir(&dir) {
+        return Err(GitError::from(format!("not a git repository: {}", dir.display())));
+    }
+    Ok(dir)
+}
+
+// Directories from GIT_CEILING_DIRECTORIES, which discovery won't go above
+fn ceiling_dirs() -> Vec<PathBuf> {
+    match env::var_os("GIT_CEILING_DIRECTORIES") {
+        Some(dirs) => env::split_paths(&dirs)
+            .filter(|d| d.is_absolute())
+            .map(|d| d.canonicalize().unwrap_or(d))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+// Pick the work tree from GIT_WORK_TREE, then core.worktree and core.bare
+fn find_work_tree(git_dir: &Path, default: Option<PathBuf>) -> GitResult<Option<PathBuf>> {
+    if let Some(dir) = env::var_os("GIT_WORK_TREE") {
+        return Ok(Some(absolute(Path::new(&dir))?));
+    }
+    let cfg = config::load_path(&git_dir.join("config"))?;
+    if let Some(dir) = cfg.get("core.worktree") {
+        return Ok(Some(git_dir.join(dir)));
+    }
+    if cfg.get_bool("core.bare")?.unwrap_or(false) {
+        return Ok(None);
+    }
+    Ok(default)
+}
+
+// Find the repository for the current directory, from GIT_DIR or by
+// looking for .git in each parent directory
+pub fn discover() -> GitResult<Repository> {
+    if let Some(dir) = env::var_os("GIT_DIR") {
+        let git_dir = absolute(Path::new(&dir))?;
+        if !is_git_dir(&git_dir) {
+            return Err(GitError::from(format!("not a git repository: '{}'",
+                                              git_dir.display())));
+        }
+        // Without anything else to go on, the cwd is the top of the work tree
+        let work_tree = find_work_tree(&git_dir, Some(env::current_dir()?))?;
+        return Ok(Repository { git_dir, work_tree });
+    }
+
+    let ceilings = ceiling_dirs();
+    let mut dir = env::current_dir()?.canonicalize()?;
+    loop {
+        let dot_git = dir.join(".git");
+        if dot_git.is_dir() && is_git_dir(&dot_git) {
+            let work_tree = find_work_tree(&dot_git, Some(dir.clone()))?;
+            return Ok(Repository { git_dir: dot_git, work_tree });
+        }
+        if dot_git.is_file() {
+            let git_dir = read_gitdir_file(&dot_git)?;
+            let work_tree = find_work_tree(&git_dir, Some(dir.clone()))?;
+            return Ok(Repository { git_dir, work_tree });
+        }
+        if is_git_dir(&dir) {
+            // A bare repository
+            let work_tree = find_work_tree(&dir, None)?;
+            return Ok(Repository { git_dir: dir, work_tree });
+        }
+
+        // A ceiling stops us moving up into it, but the cwd itself is always checked
+        dir = match dir.parent() {
+            Some(parent) if !ceilings.iter().any(|c| c == parent) => parent.to_path_buf(),
+            _ => break,
+        };
+    }
+    Err(GitError::from("not a git repository (or any of the parent directories): .git"))
+}
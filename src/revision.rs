@@ -0,0 +1,323 @@
+use cache::{self, ObjectType, read_obj};
+use commit;
+use index;
+use parse;
+use refs;
+use repo::Repository;
+use regex;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use tag;
+use tree::{self, EntryMode};
+use types::{GitError, GitResult};
+
+// Resolve a revision (see gitrevisions(7)) to the full hex name of the
+// object it refers to
+pub fn resolve(repo: &Repository, spec: &str) -> GitResult<String> {
+    if let Some(pattern) = spec.strip_prefix(":/") {
+        let mut starts = Vec::new();
+        for (_, hash) in refs::list_refs(repo)? {
+            starts.push(hash);
+        }
+        if let Ok(hash) = refs::read_ref(repo, "HEAD") {
+            starts.push(hash);
+        }
+        return search_message(repo, pattern, &starts);
+    }
+    if let Some(path) = spec.strip_prefix(':') {
+        return index_path(repo, path);
+    }
+
+    match find_path_separator(spec) {
+        Some(ndx) => {
+            let tree = peel(repo, &resolve(repo, &spec[..ndx])?, ObjectType::Tree)?;
+            tree_path(repo, &tree, &spec[ndx + 1..])
+        },
+        None => resolve_rev(repo, spec),
+    }
+}
+
+// Find the ":" between a revision and a path, ignoring any inside braces
+fn find_path_separator(spec: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (ndx, c) in spec.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            ':' if depth == 0 => return Some(ndx),
+            _ => (),
+        }
+    }
+    None
+}
+
+// Resolve a revision that doesn't name a path, applying any ~ and ^ suffixes
+fn resolve_rev(repo: &Repository, spec: &str) -> GitResult<String> {
+    let base_end = spec.find(['~', '^']).unwrap_or(spec.len());
+    let mut hash = resolve_base(repo, &spec[..base_end])?;
+
+    let bytes = spec.as_bytes();
+    let mut pos = base_end;
+    while pos < bytes.len() {
+        let op = bytes[pos];
+        pos += 1;
+
+        if op == b'^' && pos < bytes.len() && bytes[pos] == b'{' {
+            let close = match spec[pos..].find('}') {
+                Some(ndx) => pos + ndx,
+                None => return Err(GitError::from("Missing '}' in revision")),
+            };
+            let inner = &spec[pos + 1..close];
+            pos = close + 1;
+            hash = match inner {
+                "" => peel_tags(repo, &hash)?,
+                "object" => hash,
+                "commit" => peel(repo, &hash, ObjectType::Commit)?,
+                "tree" => peel(repo, &hash, ObjectType::Tree)?,
+                "blob" => peel(repo, &hash, ObjectType::Blob)?,
+                "tag" => peel(repo, &hash, ObjectType::Tag)?,
+                _ if inner.starts_with('/') => {
+                    let start = peel(repo, &hash, ObjectType::Commit)?;
+                    search_message(repo, &inner[1..], &[start])?
+                },
+                _ => return Err(GitError::from("Unknown object type in revision")),
+            };
+            continue;
+        }
+
+        let digits_start = pos;
+        while pos < bytes.len() && bytes[pos].is_ascii_digit() {
+            pos += 1;
+        }
+        let n = if pos == digits_start {
+            1
+        } else {
+            spec[digits_start..pos].parse::<usize>()?
+        };
+
+        if op == b'^' {
+            hash = peel(repo, &hash, ObjectType::Commit)?;
+            if n > 0 {
+                let commit = commit::from_object(&read_obj(repo, &hash)?)?;
+                hash = match commit.parents.get(n - 1) {
+                    Some(parent) => parent.clone(),
+                    None => return Err(GitError::from("Commit has no such parent")),
+                };
+            }
+        } else {
+            for _ in 0..n {
+                let commit_hash = peel(repo, &hash, ObjectType::Commit)?;
+                let commit = commit::from_object(&read_obj(repo, &commit_hash)?)?;
+                hash = match commit.parents.first() {
+                    Some(parent) => parent.clone(),
+                    None => return Err(GitError::from("Commit has no parent")),
+                };
+            }
+        }
+    }
+
+    Ok(hash)
+}
+
+fn is_hex(s: &str) -> bool {
+    s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Resolve a ref name, object name or reflog selector
+fn resolve_base(repo: &Repository, base: &str) -> GitResult<String> {
+    if base.is_empty() {
+        return Err(GitError::from("Empty revision"));
+    }
+    if base == "@" {
+        return refs::read_ref(repo, "HEAD");
+    }
+
+    match base.find("@{") {
+        Some(ndx) if base.ends_with('}') => {
+            let name = &base[..ndx];
+            let selector = &base[ndx + 2..base.len() - 1];
+            if let Some(n) = selector.strip_prefix('-') {
+                if !name.is_empty() {
+                    return Err(GitError::from("Invalid previous branch selector"));
+                }
+                let n = n.parse::<usize>()?;
+                return resolve_base(repo, &previous_branch(repo, n)?);
+            }
+            return reflog_entry(repo, name, selector.parse::<usize>()?);
+        },
+        _ => (),
+    }
+
+    if base.len() == 40 && is_hex(base) {
+        return Ok(base.to_lowercase());
+    }
+    match refs::expand_refname(repo, base) {
+        Ok(full_ref) => refs::read_ref(repo, &full_ref),
+        Err(err) => if base.len() >= 4 && is_hex(base) {
+            cache::resolve_prefix(repo, base)
+        } else {
+            Err(err)
+        },
+    }
+}
+
+// The value a ref had n changes ago, from its reflog
+// An empty name means the current branch
+fn reflog_entry(repo: &Repository, name: &str, n: usize) -> GitResult<String> {
+    let full_ref = if name.is_empty() {
+        match refs::symbolic_target(repo, "HEAD")? {
+            Some(branch) => branch,
+            None => String::from("HEAD"),
+        }
+    } else if name == "@" {
+        String::from("HEAD")
+    } else {
+        refs::expand_refname(repo, name)?
+    };
+
+    let entries = refs::read_reflog(repo, &full_ref)?;
+    if entries.is_empty() && n == 0 {
+        return refs::read_ref(repo, &full_ref);
+    }
+    if n >= entries.len() {
+        return Err(GitError::from(format!("log for '{}' only has {} entries",
+                                          full_ref, entries.len())));
+    }
+    Ok(entries[entries.len() - 1 - n].new.clone())
+}
+
+// The branch (or commit) that was checked out n checkouts ago
+pub fn previous_branch(repo: &Repository, n: usize) -> GitResult<String> {
+    if n == 0 {
+        return Err(GitError::from("Invalid previous branch selector"));
+    }
+    let mut count = 0;
+    for entry in refs::read_reflog(repo, "HEAD")?.iter().rev() {
+        if !entry.message.starts_with("checkout: moving from ") {
+            continue;
+        }
+        count += 1;
+        if count == n {
+            let rest = &entry.message[22..];
+            return match rest.find(" to ") {
+                Some(ndx) => Ok(String::from(&rest[..ndx])),
+                None => Err(GitError::from("Malformed checkout reflog entry")),
+            };
+        }
+    }
+    Err(GitError::from(format!("no {}th previous branch checkout", n)))
+}
+
+// Follow tags until we get to something that isn't a tag
+fn peel_tags(repo: &Repository, hash: &str) -> GitResult<String> {
+    let mut hash = String::from(hash);
+    loop {
+        let obj = read_obj(repo, &hash)?;
+        if obj.kind != ObjectType::Tag {
+            return Ok(hash);
+        }
+        hash = parse::bytes_to_hex(&tag::target(&obj)?);
+    }
+}
+
+// Follow tags, and commits to their trees, until we get an object of
+// the requested type
+pub fn peel(repo: &Repository, hash: &str, kind: ObjectType) -> GitResult<String> {
+    let mut hash = String::from(hash);
+    loop {
+        let obj = read_obj(repo, &hash)?;
+        if obj.kind == kind {
+            return Ok(hash);
+        }
+        hash = match obj.kind {
+            ObjectType::Tag => parse::bytes_to_hex(&tag::target(&obj)?),
+            ObjectType::Commit if kind == ObjectType::Tree => commit::from_object(&obj)?.tree,
+            _ => return Err(GitError::from(format!("object {} is not a {}",
+                                                   hash, kind.name()))),
+        };
+    }
+}
+
+// Find the youngest commit reachable from starts whose message matches
+// A leading "!-" negates the match, and "!!" is a literal "!"
+fn search_message(repo: &Repository, pattern: &str, starts: &[String]) -> GitResult<String> {
+    let (pattern, negate) = if let Some(rest) = pattern.strip_prefix("!-") {
+        (rest, true)
+    } else if pattern.starts_with("!!") {
+        (&pattern[1..], false)
+    } else if pattern.starts_with('!') {
+        return Err(GitError::from("Invalid message search modifier"));
+    } else {
+        (pattern, false)
+    };
+    let re = regex::compile(pattern, false)?;
+
+    // Walk newest first by committer date
+    let mut seen = HashSet::new();
+    let mut pending = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    for start in starts {
+        let hash = peel_tags(repo, start)?;
+        if seen.insert(hash.clone()) {
+            let obj = read_obj(repo, &hash)?;
+            if obj.kind == ObjectType::Commit {
+                let commit = commit::from_object(&obj)?;
+                queue.push((commit.committer_date.timestamp(), hash.clone()));
+                pending.insert(hash, commit);
+            }
+        }
+    }
+    while let Some((_, hash)) = queue.pop() {
+        let commit = match pending.remove(&hash) {
+            Some(commit) => commit,
+            None => continue,
+        };
+        if re.is_match(&commit.message) != negate {
+            return Ok(hash);
+        }
+        for parent in commit.parents.iter() {
+            if seen.insert(parent.clone()) {
+                let parent_commit = commit::from_object(&read_obj(repo, parent)?)?;
+                queue.push((parent_commit.committer_date.timestamp(), parent.clone()));
+                pending.insert(parent.clone(), parent_commit);
+            }
+        }
+    }
+    Err(GitError::from(format!("no commit message matches '{}'", pattern)))
+}
+
+// Look a path up in the index, optionally prefixed with a stage "n:"
+fn index_path(repo: &Repository, spec: &str) -> GitResult<String> {
+    let bytes = spec.as_bytes();
+    let (stage, path) = if bytes.len() >= 2 && bytes[1] == b':' && bytes[0] >= b'0'
+            && bytes[0] <= b'3' {
+        (bytes[0] - b'0', &spec[2..])
+    } else {
+        (0, spec)
+    };
+    let ndx = index::read(repo)?;
+    match ndx.entries.get(&(path.as_bytes().to_vec(), stage)) {
+        Some(entry) => Ok(parse::bytes_to_hex(&entry.hash)),
+        None if ndx.contains(path.as_bytes()) => Err(GitError::from(format!(
+            "path '{}' is in the index, but not at stage {}", path, stage))),
+        None => Err(GitError::from(format!("path '{}' does not exist in the index", path))),
+    }
+}
+
+// Look a path up inside a tree
+fn tree_path(repo: &Repository, tree_hash: &str, path: &str) -> GitResult<String> {
+    let mut hash = String::from(tree_hash);
+    let mut is_tree = true;
+    for part in path.split('/').filter(|p| !p.is_empty()) {
+        if !is_tree {
+            return Err(GitError::from(format!("path '{}' does not exist", path)));
+        }
+        let tree = tree::from_object(&read_obj(repo, &hash)?)?;
+        let entry = match tree.entries.iter().find(|e| e.name == part.as_bytes()) {
+            Some(entry) => entry,
+            None => return Err(GitError::from(format!("path '{}' does not exist", path))),
+        };
+        is_tree = entry.mode == EntryMode::Tree;
+        hash = parse::bytes_to_hex(&entry.hash);
+    }
+    Ok(hash)
+}
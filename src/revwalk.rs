@@ -0,0 +1,224 @@
+use cache::{ObjectType, read_obj};
+use chrono::{DateTime, FixedOffset};
+use commit::{self, Commit};
+use regex::Regex;
+use repo::Repository;
+use revision;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use types::GitResult;
+
+// How many commits in a row have to be older than --since before we assume
+// the rest of history is too, allowing for a little clock skew
+const SINCE_SLOP: usize = 5;
+
+#[derive(PartialEq, Eq)]
+pub enum Order {
+    // Newest commit date first
+    Date,
+    // No parent before all of its children, otherwise newest first
+    DateTopo,
+    // No parent before all of its children, keeping lines of history together
+    Topo,
+}
+
+pub struct WalkOptions {
+    pub order: Order,
+    pub first_parent: bool,
+    pub max_count: Option<usize>,
+    pub since: Option<DateTime<FixedOffset>>,
+    pub until: Option<DateTime<FixedOffset>>,
+    pub author: Option<Regex>,
+    pub grep: Option<Regex>,
+}
+
+impl WalkOptions {
+    pub fn new() -> WalkOptions {
+        WalkOptions {
+            order: Order::Date,
+            first_parent: false,
+            max_count: None,
+            since: None,
+            until: None,
+            author: None,
+            grep: None,
+        }
+    }
+
+    fn matches(&self, commit: &Commit) -> bool {
+        if self.since.is_some_and(|since| commit.committer_date < since) {
+            return false;
+        }
+        if self.until.is_some_and(|until| commit.committer_date > until) {
+            return false;
+        }
+        if self.author.as_ref().is_some_and(|re| !re.is_match(&commit.author)) {
+            return false;
+        }
+        if self.grep.as_ref().is_some_and(|re| !re.is_match(&commit.message)) {
+            return false;
+        }
+        true
+    }
+}
+
+fn read_commit(repo: &Repository, hash: &str) -> GitResult<Commit> {
+    commit::from_object(&read_obj(repo, hash)?)
+}
+
+// Every commit reachable from the starting points, including themselves
+pub fn ancestors(repo: &Repository, starts: &[String]) -> GitResult<HashSet<String>> {
+    let mut seen = HashSet::new();
+    let mut stack = Vec::new();
+    for start in starts {
+        stack.push(revision::peel(repo, start, ObjectType::Commit)?);
+    }
+    while let Some(hash) = stack.pop() {
+        if !seen.insert(hash.clone()) {
+            continue;
+        }
+        for parent in read_commit(repo, &hash)?.parents {
+            if !seen.contains(&parent) {
+                stack.push(parent);
+            }
+        }
+    }
+    Ok(seen)
+}
+
+// Walk the history from the included commits, hiding everything in
+// exclude, and return the commits in the requested order
+pub fn walk(repo: &Repository, include: &[String], exclude: &HashSet<String>, opts: &WalkOptions)
+        -> GitResult<Vec<(String, Commit)>> {
+    let mut seen = HashSet::new();
+    let mut queue = BinaryHeap::new();
+    let mut pending: HashMap<String, Commit> = HashMap::new();
+    for start in include {
+        let hash = revision::peel(repo, start, ObjectType::Commit)?;
+        if exclude.contains(&hash) || !seen.insert(hash.clone()) {
+            continue;
+        }
+        let commit = read_commit(repo, &hash)?;
+        queue.push((commit.committer_date.timestamp(), hash.clone()));
+        pending.insert(hash, commit);
+    }
+
+    // Sorting needs every commit, even the ones that won't be shown, to know
+    // where the rest go
+    let sorting = opts.order != Order::Date;
+    let mut commits: Vec<(String, Commit)> = Vec::new();
+    let mut too_old = 0;
+    while let Some((_, hash)) = queue.pop() {
+        let commit = match pending.remove(&hash) {
+            Some(commit) => commit,
+            None => continue,
+        };
+        match opts.since {
+            Some(since) if commit.committer_date < since => {
+                too_old += 1;
+                if too_old > SINCE_SLOP {
+                    break;
+                }
+            },
+            _ => too_old = 0,
+        }
+        let num_parents = if opts.first_parent { 1 } else { commit.parents.len() };
+        for parent in commit.parents.iter().take(num_parents) {
+            if exclude.contains(parent) || !seen.insert(parent.clone()) {
+                continue;
+            }
+            let parent_commit = read_commit(repo, parent)?;
+            queue.push((parent_commit.committer_date.timestamp(), parent.clone()));
+            pending.insert(parent.clone(), parent_commit);
+        }
+        if sorting || opts.matches(&commit) {
+            commits.push((hash, commit));
+        }
+        // Without sorting we can stop as soon as we have enough
+        if !sorting && opts.max_count == Some(commits.len()) {
+            break;
+        }
+    }
+    if !sorting {
+        return Ok(commits);
+    }
+
+    let mut result = Vec::new();
+    for (hash, commit) in topo_sort(commits, opts) {
+        if opts.max_count.is_some_and(|max| result.len() >= max) {
+            break;
+        }
+        if opts.matches(&commit) {
+            result.push((hash, commit));
+        }
+    }
+    Ok(result)
+}
+
+// Reorder date ordered commits so each is shown before its parents
+fn topo_sort(commits: Vec<(String, Commit)>, opts: &WalkOptions)
+        -> Vec<(String, Commit)> {
+    let num_parents = |commit: &Commit| {
+        if opts.first_parent { commit.parents.len().min(1) } else { commit.parents.len() }
+    };
+
+    // Count how many of each commit's children we still have to show
+    let mut children: HashMap<String, usize> = HashMap::new();
+    for (hash, _) in commits.iter() {
+        children.insert(hash.clone(), 0);
+    }
+    for (_, commit) in commits.iter() {
+        for parent in commit.parents.iter().take(num_parents(commit)) {
+            if let Some(count) = children.get_mut(parent) {
+                *count += 1;
+            }
+        }
+    }
+
+    let mut order: HashMap<String, usize> = HashMap::new();
+    for (ndx, (hash, _)) in commits.iter().enumerate() {
+        order.insert(hash.clone(), ndx);
+    }
+    let mut by_hash: HashMap<String, Commit> = commits.into_iter().collect();
+
+    // Commits ready to be shown, by their position in date order
+    let mut ready: Vec<usize> = children.iter()
+        .filter(|&(_, count)| *count == 0)
+        .map(|(hash, _)| order[hash])
+        .collect();
+    // Keep the newest commit at the end, where we pop from
+    ready.sort_by(|a, b| b.cmp(a));
+
+    let mut hashes_by_order: Vec<String> = vec![String::new(); order.len()];
+    for (hash, ndx) in order.iter() {
+        hashes_by_order[*ndx] = hash.clone();
+    }
+
+    let mut result = Vec::new();
+    while let Some(ndx) = ready.pop() {
+        let hash = hashes_by_order[ndx].clone();
+        let commit = match by_hash.remove(&hash) {
+            Some(commit) => commit,
+            None => continue,
+        };
+        let mut newly_ready = Vec::new();
+        for parent in commit.parents.iter().take(num_parents(&commit)) {
+            if let Some(count) = children.get_mut(parent) {
+                *count -= 1;
+                if *count == 0 {
+                    newly_ready.push(order[parent]);
+                }
+            }
+        }
+        match opts.order {
+            // Show the parents straight after their last child, the last
+            // parent's line of history first
+            Order::Topo => ready.extend(newly_ready),
+            _ => {
+                ready.extend(newly_ready);
+                ready.sort_by(|a, b| b.cmp(a));
+            },
+        }
+        result.push((hash, commit));
+    }
+    result
+}
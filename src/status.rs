@@ -0,0 +1,740 @@
+// The status command: what's staged, what isn't, and what isn't tracked
+
+use cache::{Object, ObjectType};
+use config::{self, Config};
+use ignore::Ignores;
+use index::{self, Index};
+use parse;
+use refs;
+use repo::Repository;
+use revision;
+use revwalk;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, Metadata};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use tree::{self, EntryMode};
+use types::{GitError, GitResult};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Long,
+    Short,
+    Porcelain,
+    PorcelainV2,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UntrackedMode {
+    No,
+    // Untracked directories are shown as a whole
+    Normal,
+    All,
+}
+
+pub struct StatusOptions {
+    pub format: Format,
+    pub branch: bool,
+    pub null_terminated: bool,
+    pub untracked: UntrackedMode,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Unmodified,
+    Added,
+    Modified,
+    Deleted,
+    TypeChanged,
+}
+
+impl Change {
+    fn code(&self) -> char {
+        match *self {
+            Change::Unmodified => ' ',
+            Change::Added => 'A',
+            Change::Modified => 'M',
+            Change::Deleted => 'D',
+            Change::TypeChanged => 'T',
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match *self {
+            Change::Unmodified => "",
+            Change::Added => "new file:   ",
+            Change::Modified => "modified:   ",
+            Change::Deleted => "deleted:    ",
+            Change::TypeChanged => "typechange: ",
+        }
+    }
+}
+
+// The mode and hash of stages 1 to 3 of a merge conflict, whichever exist
+type Stages = [Option<(EntryMode, [u8; 20])>; 3];
+
+struct FileStatus {
+    name: Vec<u8>,
+    staged: Change,
+    unstaged: Change,
+    head: Option<(EntryMode, [u8; 20])>,
+    index: Option<(EntryMode, [u8; 20])>,
+    worktree_mode: Option<EntryMode>,
+    // The stages of a path with a merge conflict
+    unmerged: Option<Stages>,
+}
+
+// The short status code and long description of a conflict, from which
+// stages exist
+fn unmerged_status(stages: &Stages) -> (&'static str, &'static str) {
+    match (stages[0].is_some(), stages[1].is_some(), stages[2].is_some()) {
+        (true, false, false) => ("DD", "both deleted:    "),
+        (false, true, false) => ("AU", "added by us:     "),
+        (true, true, false) => ("UD", "deleted by them: "),
+        (false, false, true) => ("UA", "added by them:   "),
+        (true, false, true) => ("DU", "deleted by us:   "),
+        (false, true, true) => ("AA", "both added:      "),
+        _ => ("UU", "both modified:   "),
+    }
+}
+
+struct Upstream {
+    // Like "origin/master"
+    name: String,
+    // None when the upstream branch doesn't exist any more
+    ahead_behind: Option<(usize, usize)>,
+}
+
+struct BranchInfo {
+    head: Option<String>,
+    // None when HEAD is detached
+    branch: Option<String>,
+    upstream: Option<Upstream>,
+}
+
+// C-style quoting for names with unusual characters, as core.quotePath does
+pub fn quote_path(name: &[u8], quote_space: bool) -> String {
+    let needs_quotes = name.iter().any(|&c| !(0x20..0x7f).contains(&c) || c == b'"' || c == b'\\'
+                                       || (quote_space && c == b' '));
+    if !needs_quotes {
+        return String::from_utf8_lossy(name).into_owned();
+    }
+    let mut quoted = String::from("\"");
+    for &c in name {
+        match c {
+            b'\x07' => quoted.push_str("\\a"),
+            b'\x08' => quoted.push_str("\\b"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\x0b' => quoted.push_str("\\v"),
+            b'\x0c' => quoted.push_str("\\f"),
+            b'\r' => quoted.push_str("\\r"),
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            c if !(0x20..0x7f).contains(&c) => quoted.push_str(&format!("\\{:03o}", c)),
+            c => quoted.push(c as char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// An index name as seen from a prefix directory, which ends in "/" unless
+// it's the top of the work tree
+pub fn relative_to(name: &[u8], prefix: &[u8]) -> Vec<u8> {
+    let mut common = 0;
+    while let Some(pos) = prefix[common..].iter().position(|&c| c == b'/') {
+        let end = common + pos + 1;
+        if name.len() < end || name[common..end] != prefix[common..end] {
+            break;
+        }
+        common = end;
+    }
+    let ups = prefix[common..].iter().filter(|&&c| c == b'/').count();
+    let mut rel = b"../".repeat(ups);
+    rel.extend_from_slice(&name[common..]);
+    if rel.is_empty() {
+        rel.extend_from_slice(b"./");
+    }
+    rel
+}
+
+// Where the cwd is inside the work tree, in the form relative_to wants
+pub fn cwd_prefix(work_tree: &Path) -> GitResult<Vec<u8>> {
+    let cwd = env::current_dir()?;
+    let cwd = cwd.canonicalize().unwrap_or(cwd);
+    let work_tree = work_tree.canonicalize().unwrap_or_else(|_| work_tree.to_path_buf());
+    match cwd.strip_prefix(&work_tree) {
+        Ok(rel) => {
+            let mut prefix = index::path_to_name(rel)?;
+            if !prefix.is_empty() {
+                prefix.push(b'/');
+            }
+            Ok(prefix)
+        },
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+// Hash a work tree file the way add would store it
+pub fn hash_file(path: &Path, meta: &Metadata) -> GitResult<[u8; 20]> {
+    let data = if meta.file_type().is_symlink() {
+        path.read_link()?.as_os_str().as_bytes().to_vec()
+    } else {
+        fs::read(path)?
+    };
+    Ok(Object { kind: ObjectType::Blob, data }.hash().bytes())
+}
+
+fn compare(old: &(EntryMode, [u8; 20]), new_mode: EntryMode, new_hash: &[u8; 20]) -> Change {
+    let is_link = |mode: EntryMode| mode == EntryMode::Symlink;
+    if is_link(old.0) != is_link(new_mode) {
+        Change::TypeChanged
+    } else if old.0 != new_mode || old.1 != *new_hash {
+        Change::Modified
+    } else {
+        Change::Unmodified
+    }
+}
+
+// Compare HEAD with the index, and the index with the work tree
+fn changes(repo: &Repository, ndx: &Index, head: Option<&str>) -> GitResult<Vec<FileStatus>> {
+    let work_tree = repo.work_tree()?;
+    let head_files = match head {
+        Some(hash) => {
+            let tree = revision::peel(repo, hash, ObjectType::Tree)?;
+            tree::read_recursive(repo, &tree)?
+        },
+        None => BTreeMap::new(),
+    };
+
+    let mut files: BTreeMap<Vec<u8>, FileStatus> = BTreeMap::new();
+    for (name, &(mode, hash)) in head_files.iter() {
+        files.insert(name.clone(), FileStatus {
+            name: name.clone(),
+            staged: Change::Deleted,
+            unstaged: Change::Unmodified,
+            head: Some((mode, hash)),
+            index: None,
+            worktree_mode: None,
+            unmerged: None,
+        });
+    }
+
+    for (&(ref name, stage), entry) in ndx.entries.iter() {
+        let path = work_tree.join(OsStr::from_bytes(name));
+        if stage != 0 {
+            let status = files.entry(name.clone()).or_insert(FileStatus {
+                name: name.clone(),
+                staged: Change::Unmodified,
+                unstaged: Change::Unmodified,
+                head: None,
+                index: None,
+                worktree_mode: None,
+                unmerged: None,
+            });
+            if status.unmerged.is_none() {
+                // Conflicts are shown on their own, not as staged changes
+                status.staged = Change::Unmodified;
+                status.worktree_mode = match fs::symlink_metadata(&path) {
+                    Ok(ref meta) => index::mode_from_metadata(meta),
+                    Err(_) => None,
+                };
+            }
+            let stages = status.unmerged.get_or_insert([None; 3]);
+            stages[stage as usize - 1] = Some((entry.mode, entry.hash));
+            continue;
+        }
+
+        let staged = match head_files.get(name) {
+            Some(old) => compare(old, entry.mode, &entry.hash),
+            // An intent-to-add entry has nothing staged yet
+            None if entry.intent_to_add => Change::Unmodified,
+            None => Change::Added,
+        };
+
+        let (unstaged, worktree_mode) = match fs::symlink_metadata(&path) {
+            // Sparse checkouts leave these out of the work tree on purpose
+            _ if entry.skip_worktree => (Change::Unmodified, Some(entry.mode)),
+            Ok(ref meta) if entry.intent_to_add => {
+                (Change::Added, index::mode_from_metadata(meta))
+            },
+            Ok(ref meta) => match index::mode_from_metadata(meta) {
+                Some(mode) => {
+                    if ndx.is_unchanged(entry, meta) {
+                        (Change::Unmodified, Some(mode))
+                    } else {
+                        let hash = hash_file(&path, meta)?;
+                        (compare(&(entry.mode, entry.hash), mode, &hash), Some(mode))
+                    }
+                },
+                // Replaced with a directory
+                None => (Change::Deleted, None),
+            },
+            // Also gone when a leading directory was replaced by a file
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound
+                || err.kind() == io::ErrorKind::NotADirectory => (Change::Deleted, None),
+            Err(err) => return Err(GitError::from(err)),
+        };
+
+        let status = files.entry(name.clone()).or_insert(FileStatus {
+            name: name.clone(),
+            staged: Change::Added,
+            unstaged: Change::Unmodified,
+            head: None,
+            index: None,
+            worktree_mode: None,
+            unmerged: None,
+        });
+        status.staged = staged;
+        status.unstaged = unstaged;
+        status.index = if entry.intent_to_add { None } else { Some((entry.mode, entry.hash)) };
+        status.worktree_mode = worktree_mode;
+    }
+
+    Ok(files.into_values()
+       .filter(|s| s.staged != Change::Unmodified || s.unstaged != Change::Unmodified
+               || s.unmerged.is_some())
+       .collect())
+}
+
+// What differs from HEAD in the index or work tree, with a letter for each
+// path like diff's --name-status, for checkout to report
+pub fn local_changes(repo: &Repository, ndx: &Index, head: Option<&str>)
+        -> GitResult<Vec<(char, Vec<u8>)>> {
+    Ok(changes(repo, ndx, head)?.into_iter().map(|file| {
+        let code = if file.unmerged.is_some() {
+            'U'
+        } else if file.head.is_some() && file.unstaged == Change::Deleted {
+            'D'
+        } else if file.staged == Change::Unmodified {
+            file.unstaged.code()
+        } else if file.staged == Change::Modified && file.unstaged == Change::TypeChanged {
+            'T'
+        } else {
+            file.staged.code()
+        };
+        (code, file.name)
+    }).collect())
+}
+
+// Whether an untracked directory holds anything that isn't ignored
+fn has_untracked(work_tree: &Path, ignores: &mut Ignores, dir: &[u8]) -> GitResult<bool> {
+    for entry in fs::read_dir(work_tree.join(OsStr::from_bytes(dir)))? {
+        let entry = entry?;
+        let mut name = dir.to_vec();
+        name.push(b'/');
+        name.extend_from_slice(entry.file_name().as_bytes());
+        let is_dir = entry.file_type()?.is_dir();
+        if ignores.is_ignored(&name, is_dir)? {
+            continue;
+        }
+        if !is_dir || has_untracked(work_tree, ignores, &name)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Collect untracked paths under a directory, with a trailing "/" on
+// directories that are shown as a whole
+fn find_untracked(work_tree: &Path, ndx: &Index, ignores: &mut Ignores, dir: &[u8],
+                  mode: UntrackedMode, found: &mut Vec<Vec<u8>>) -> GitResult<()> {
+    for entry in fs::read_dir(work_tree.join(OsStr::from_bytes(dir)))? {
+        let entry = entry?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        let mut name = dir.to_vec();
+        if !name.is_empty() {
+            name.push(b'/');
+        }
+        name.extend_from_slice(entry.file_name().as_bytes());
+
+        let is_dir = entry.file_type()?.is_dir();
+        if is_dir && ndx.is_tracked(&name) {
+            find_untracked(work_tree, ndx, ignores, &name, mode, found)?;
+        } else if ndx.contains(&name) || ignores.is_ignored(&name, is_dir)? {
+            continue;
+        } else if !is_dir {
+            found.push(name);
+        } else if mode == UntrackedMode::All {
+            find_untracked(work_tree, ndx, ignores, &name, mode, found)?;
+        } else if has_untracked(work_tree, ignores, &name)? {
+            name.push(b'/');
+            found.push(name);
+        }
+    }
+    Ok(())
+}
+
+// The remote-tracking branch a local branch merges from
+fn upstream_ref(cfg: &Config, branch: &str) -> Option<String> {
+    let remote = cfg.get(&format!("branch.{}.remote", branch))?;
+    let merge = cfg.get(&format!("branch.{}.merge", branch))?;
+    if remote == "." {
+        return Some(String::from(merge));
+    }
+    if !merge.starts_with("refs/heads/") {
+        return None;
+    }
+    Some(format!("refs/remotes/{}/{}", remote, &merge[11..]))
+}
+
+pub fn short_ref_name(name: &str) -> &str {
+    for prefix in ["refs/heads/", "refs/remotes/", "refs/tags/"].iter() {
+        if let Some(short) = name.strip_prefix(prefix) {
+            return short;
+        }
+    }
+    name
+}
+
+fn branch_info(repo: &Repository, cfg: &Config) -> GitResult<BranchInfo> {
+    let target = refs::symbolic_target(repo, "HEAD")?;
+    let head = match refs::read_ref(repo, "HEAD") {
+        Ok(hash) => Some(hash),
+        Err(GitError::IoError(ref err)) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    let branch = target.as_ref().map(|t| String::from(short_ref_name(t)));
+
+    let upstream = match (branch.as_ref(), upstream_ref(cfg, branch.as_ref().map_or("", |b| b))) {
+        (Some(_), Some(upstream)) => {
+            let name = String::from(short_ref_name(&upstream));
+            let ahead_behind = match (refs::read_ref(repo, &upstream), head.as_ref()) {
+                (Ok(theirs), Some(ours)) => {
+                    let ours = revwalk::ancestors(repo, std::slice::from_ref(ours))?;
+                    let theirs = revwalk::ancestors(repo, &[theirs])?;
+                    Some((ours.difference(&theirs).count(), theirs.difference(&ours).count()))
+                },
+                (Ok(_), None) => Some((0, 0)),
+                (Err(_), _) => None,
+            };
+            Some(Upstream { name, ahead_behind })
+        },
+        _ => None,
+    };
+    Ok(BranchInfo { head, branch, upstream })
+}
+
+// What HEAD was detached at, from the last checkout in its reflog. The
+// name is the ref that was checked out if it still points there, or else
+// the abbreviated commit.
+fn detached_from(repo: &Repository, head: &str) -> GitResult<Option<(bool, String)>> {
+    let entries = match refs::read_reflog(repo, "HEAD") {
+        Ok(entries) => entries,
+        Err(_) => return Ok(None),
+    };
+    for entry in entries.iter().rev() {
+        if !entry.message.starts_with("checkout: moving from ") {
+            continue;
+        }
+        let target = match entry.message.rfind(" to ") {
+            Some(ndx) => &entry.message[ndx + 4..],
+            None => continue,
+        };
+        let same_commit = |hash: &str| {
+            revision::peel(repo, hash, ObjectType::Commit).ok().as_ref() == Some(&entry.new)
+        };
+        let name = match refs::expand_refname(repo, target) {
+            Ok(ref full_ref) if target != "HEAD"
+                && refs::read_ref(repo, full_ref).ok().is_some_and(|h| same_commit(&h)) => {
+                let name = full_ref.trim_start_matches("refs/tags/");
+                String::from(if name.len() < full_ref.len() {
+                    name
+                } else {
+                    full_ref.trim_start_matches("refs/remotes/")
+                })
+            },
+            _ => String::from(&entry.new[..7]),
+        };
+        return Ok(Some((entry.new == head, name)));
+    }
+    Ok(None)
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "commit" } else { "commits" }
+}
+
+fn print_long(repo: &Repository, info: &BranchInfo, files: &[FileStatus],
+              untracked: &[Vec<u8>], opts: &StatusOptions, prefix: &[u8]) -> GitResult<()> {
+    let display = |name: &[u8]| quote_path(&relative_to(name, prefix), false);
+
+    match (info.branch.as_ref(), info.head.as_ref()) {
+        (Some(branch), _) => println!("On branch {}", branch),
+        (None, Some(head)) => match detached_from(repo, head)? {
+            Some((true, name)) => println!("HEAD detached at {}", name),
+            Some((false, name)) => println!("HEAD detached from {}", name),
+            None => println!("Not currently on any branch."),
+        },
+        (None, None) => println!("Not currently on any branch."),
+    }
+    if let Some(ref upstream) = info.upstream {
+        match upstream.ahead_behind {
+            None => {
+                println!("Your branch is based on '{}', but the upstream is gone.",
+                         upstream.name);
+                println!("  (use \"git branch --unset-upstream\" to fixup)");
+            },
+            Some((0, 0)) => println!("Your branch is up to date with '{}'.", upstream.name),
+            Some((ahead, 0)) => {
+                println!("Your branch is ahead of '{}' by {} {}.",
+                         upstream.name, ahead, plural(ahead));
+                println!("  (use \"git push\" to publish your local commits)");
+            },
+            Some((0, behind)) => {
+                println!("Your branch is behind '{}' by {} {}, and can be fast-forwarded.",
+                         upstream.name, behind, plural(behind));
+                println!("  (use \"git pull\" to update your local branch)");
+            },
+            Some((ahead, behind)) => {
+                println!("Your branch and '{}' have diverged,", upstream.name);
+                println!("and have {} and {} different commits each, respectively.",
+                         ahead, behind);
+                println!("  (use \"git pull\" to merge the remote branch into yours)");
+            },
+        }
+        println!();
+    }
+    if info.head.is_none() {
+        println!();
+        println!("No commits yet");
+        println!();
+    }
+
+    let unmerged: Vec<(&FileStatus, &'static str)> = files.iter()
+        .filter_map(|f| f.unmerged.as_ref().map(|stages| (f, unmerged_status(stages).0)))
+        .collect();
+    let merging = repo.path("MERGE_HEAD").exists();
+    if merging && !unmerged.is_empty() {
+        println!("You have unmerged paths.");
+        println!("  (fix conflicts and run \"git commit\")");
+        println!("  (use \"git merge --abort\" to abort the merge)");
+        println!();
+    } else if merging {
+        println!("All conflicts fixed but you are still merging.");
+        println!("  (use \"git commit\" to conclude merge)");
+        println!();
+    }
+
+    let staged: Vec<&FileStatus> =
+        files.iter().filter(|f| f.staged != Change::Unmodified).collect();
+    if !staged.is_empty() {
+        println!("Changes to be committed:");
+        if merging {
+            // Unstaging won't undo the merge
+        } else if info.head.is_some() {
+            println!("  (use \"git restore --staged <file>...\" to unstage)");
+        } else {
+            println!("  (use \"git rm --cached <file>...\" to unstage)");
+        }
+        for file in staged.iter() {
+            println!("\t{}{}", file.staged.description(), display(&file.name));
+        }
+        println!();
+    }
+
+    if !unmerged.is_empty() {
+        println!("Unmerged paths:");
+        if merging {
+            // Unstaging won't undo the merge
+        } else if info.head.is_some() {
+            println!("  (use \"git restore --staged <file>...\" to unstage)");
+        } else {
+            println!("  (use \"git rm --cached <file>...\" to unstage)");
+        }
+        let both_deleted = unmerged.iter().any(|&(_, code)| code == "DD");
+        let del_mod = unmerged.iter().any(|&(_, code)| code == "UD" || code == "DU");
+        let not_deleted = unmerged.iter().any(|&(_, code)| code != "DD" && code != "UD"
+                                              && code != "DU");
+        if !both_deleted && !del_mod {
+            println!("  (use \"git add <file>...\" to mark resolution)");
+        } else if both_deleted && !del_mod && !not_deleted {
+            println!("  (use \"git rm <file>...\" to mark resolution)");
+        } else {
+            println!("  (use \"git add/rm <file>...\" as appropriate to mark resolution)");
+        }
+        for &(file, _) in unmerged.iter() {
+            let description = file.unmerged.as_ref().map_or("", |s| unmerged_status(s).1);
+            println!("\t{}{}", description, display(&file.name));
+        }
+        println!();
+    }
+
+    let unstaged: Vec<&FileStatus> =
+        files.iter().filter(|f| f.unstaged != Change::Unmodified).collect();
+    if !unstaged.is_empty() {
+        println!("Changes not staged for commit:");
+        if unstaged.iter().any(|f| f.unstaged == Change::Deleted) {
+            println!("  (use \"git add/rm <file>...\" to update what will be committed)");
+        } else {
+            println!("  (use \"git add <file>...\" to update what will be committed)");
+        }
+        println!("  (use \"git restore <file>...\" to discard changes in working directory)");
+        for file in unstaged.iter() {
+            println!("\t{}{}", file.unstaged.description(), display(&file.name));
+        }
+        println!();
+    }
+
+    if !untracked.is_empty() {
+        println!("Untracked files:");
+        println!("  (use \"git add <file>...\" to include in what will be committed)");
+        for name in untracked {
+            println!("\t{}", display(name));
+        }
+        println!();
+    } else if opts.untracked == UntrackedMode::No {
+        println!("Untracked files not listed (use -u option to show untracked files)");
+    }
+
+    if !staged.is_empty() {
+        // The changes speak for themselves
+    } else if !unstaged.is_empty() || !unmerged.is_empty() {
+        println!("no changes added to commit (use \"git add\" and/or \"git commit -a\")");
+    } else if !untracked.is_empty() {
+        println!("nothing added to commit but untracked files present (use \"git add\" to track)");
+    } else if info.head.is_none() {
+        println!("nothing to commit (create/copy files and use \"git add\" to track)");
+    } else if opts.untracked == UntrackedMode::No {
+        println!("nothing to commit (use -u to show untracked files)");
+    } else {
+        println!("nothing to commit, working tree clean");
+    }
+    Ok(())
+}
+
+fn print_short(info: &BranchInfo, files: &[FileStatus], untracked: &[Vec<u8>],
+               opts: &StatusOptions, prefix: &[u8]) -> GitResult<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let end = if opts.null_terminated { "\0" } else { "\n" };
+    // Short format shows paths from the cwd, porcelain from the top
+    let display = |name: &[u8]| {
+        let name = if opts.format == Format::Short {
+            relative_to(name, prefix)
+        } else {
+            name.to_vec()
+        };
+        if opts.null_terminated {
+            String::from_utf8_lossy(&name).into_owned()
+        } else {
+            quote_path(&name, true)
+        }
+    };
+
+    if opts.branch {
+        let mut line = match (info.branch.as_ref(), info.head.as_ref()) {
+            (Some(branch), None) => format!("## No commits yet on {}", branch),
+            (Some(branch), Some(_)) => format!("## {}", branch),
+            (None, _) => String::from("## HEAD (no branch)"),
+        };
+        if let Some(ref upstream) = info.upstream {
+            line.push_str(&format!("...{}", upstream.name));
+            match upstream.ahead_behind {
+                None => line.push_str(" [gone]"),
+                Some((0, 0)) => (),
+                Some((ahead, 0)) => line.push_str(&format!(" [ahead {}]", ahead)),
+                Some((0, behind)) => line.push_str(&format!(" [behind {}]", behind)),
+                Some((ahead, behind)) => {
+                    line.push_str(&format!(" [ahead {}, behind {}]", ahead, behind))
+                },
+            }
+        }
+        write!(out, "{}{}", line, end)?;
+    }
+
+    for file in files {
+        match file.unmerged {
+            Some(ref stages) => {
+                write!(out, "{} {}{}", unmerged_status(stages).0, display(&file.name), end)?;
+            },
+            None => write!(out, "{}{} {}{}", file.staged.code(), file.unstaged.code(),
+                           display(&file.name), end)?,
+        }
+    }
+    for name in untracked {
+        write!(out, "?? {}{}", display(name), end)?;
+    }
+    Ok(())
+}
+
+fn print_porcelain_v2(info: &BranchInfo, files: &[FileStatus], untracked: &[Vec<u8>],
+                      opts: &StatusOptions) -> GitResult<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let end = if opts.null_terminated { "\0" } else { "\n" };
+    let display = |name: &[u8]| if opts.null_terminated {
+        String::from_utf8_lossy(name).into_owned()
+    } else {
+        quote_path(name, false)
+    };
+    let code = |change: Change| if change == Change::Unmodified { '.' } else { change.code() };
+    let mode = |mode: Option<EntryMode>| format!("{:06o}", mode.map_or(0, |m| m.bits()));
+    let hash = |entry: Option<(EntryMode, [u8; 20])>| {
+        parse::bytes_to_hex(&entry.map_or([0; 20], |e| e.1))
+    };
+
+    if opts.branch {
+        write!(out, "# branch.oid {}{}", info.head.as_ref().map_or("(initial)", |h| h), end)?;
+        write!(out, "# branch.head {}{}", info.branch.as_ref().map_or("(detached)", |b| b), end)?;
+        if let Some(ref upstream) = info.upstream {
+            write!(out, "# branch.upstream {}{}", upstream.name, end)?;
+            if let Some((ahead, behind)) = upstream.ahead_behind {
+                write!(out, "# branch.ab +{} -{}{}", ahead, behind, end)?;
+            }
+        }
+    }
+
+    for file in files.iter().filter(|f| f.unmerged.is_none()) {
+        let index_mode = file.index.map(|e| e.0);
+        let worktree_mode = match file.unstaged {
+            Change::Unmodified => index_mode,
+            _ => file.worktree_mode,
+        };
+        write!(out, "1 {}{} N... {} {} {} {} {} {}{}", code(file.staged), code(file.unstaged),
+               mode(file.head.map(|e| e.0)), mode(index_mode), mode(worktree_mode),
+               hash(file.head), hash(file.index), display(&file.name), end)?;
+    }
+    // Conflicts come after all the ordinary changes
+    for file in files {
+        if let Some(ref stages) = file.unmerged {
+            write!(out, "u {} N... {} {} {} {} {} {} {} {}{}", unmerged_status(stages).0,
+                   mode(stages[0].map(|e| e.0)), mode(stages[1].map(|e| e.0)),
+                   mode(stages[2].map(|e| e.0)), mode(file.worktree_mode),
+                   hash(stages[0]), hash(stages[1]), hash(stages[2]),
+                   display(&file.name), end)?;
+        }
+    }
+    for name in untracked {
+        write!(out, "? {}{}", display(name), end)?;
+    }
+    Ok(())
+}
+
+pub fn status(repo: &Repository, opts: &StatusOptions) -> GitResult<()> {
+    let work_tree = repo.work_tree()?;
+    let cfg = config::load(Some(repo))?;
+    let ndx = index::read(repo)?;
+    let info = branch_info(repo, &cfg)?;
+    let files = changes(repo, &ndx, info.head.as_deref())?;
+
+    let mut untracked = Vec::new();
+    if opts.untracked != UntrackedMode::No {
+        let mut ignores = Ignores::new(repo, &cfg)?;
+        find_untracked(work_tree, &ndx, &mut ignores, b"", opts.untracked, &mut untracked)?;
+        untracked.sort();
+    }
+
+    let prefix = cwd_prefix(work_tree)?;
+    match opts.format {
+        Format::Long => print_long(repo, &info, &files, &untracked, opts, &prefix),
+        Format::Short | Format::Porcelain => print_short(&info, &files, &untracked, opts, &prefix),
+        Format::PorcelainV2 => print_porcelain_v2(&info, &files, &untracked, opts),
+    }
+}
@@ -0,0 +1,15 @@
+use cache::{Object, ObjectType};
+use parse;
+use types::{GitError, GitResult};
+
+// The object a tag points to, from its "object" header line
+pub fn target(object: &Object) -> GitResult<[u8; 20]> {
+    if object.kind != ObjectType::Tag {
+        return Err(GitError::from("Expected a tag object"));
+    }
+    let data = &object.data;
+    if data.len() < 48 || &data[..7] != b"object " || data[47] != b'\n' {
+        return Err(GitError::from("Malformed tag object"));
+    }
+    parse::hex_to_hash(&String::from_utf8(data[7..47].to_vec())?)
+}
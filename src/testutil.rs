@@ -0,0 +1,39 @@
+// Helpers for unit tests that need files on disk
+
+use repo::Repository;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+// A scratch directory, removed again when the test is done with it
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    // The name only has to be unique among the tests, the process ID keeps
+    // separate runs apart
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("git-rs-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    // An empty repository with its work tree at the top of the directory
+    pub fn repo(&self) -> Repository {
+        let git_dir = self.path.join(".git");
+        for dir in ["objects/pack", "refs/heads", "refs/tags"].iter() {
+            fs::create_dir_all(git_dir.join(dir)).unwrap();
+        }
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+        Repository { git_dir, work_tree: Some(self.path.clone()) }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
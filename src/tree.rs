@@ -1,4 +1,6 @@
-use cache::{Object, ObjectType};
+use cache::{self, Object, ObjectType};
+use repo::Repository;
+use std::collections::BTreeMap;
 use std::io::{BufRead, Cursor, Read, Write};
 use parse;
 use types::{GitError, GitResult};
@@ -7,7 +9,7 @@ pub struct Tree {
     pub entries: Vec<TreeEntry>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum EntryMode {
     NormalFile,
     ExecutableFile,
@@ -15,6 +17,18 @@ pub enum EntryMode {
     Tree,
 }
 
+impl EntryMode {
+    // The mode as git writes it in octal
+    pub fn bits(&self) -> u32 {
+        match *self {
+            EntryMode::NormalFile => 0o100644,
+            EntryMode::ExecutableFile => 0o100755,
+            EntryMode::Symlink => 0o120000,
+            EntryMode::Tree => 0o040000,
+        }
+    }
+}
+
 pub struct TreeEntry {
     pub mode: EntryMode,
     pub name: Vec<u8>,
@@ -49,6 +63,49 @@ pub fn from_object(object: &Object) -> GitResult<Tree> {
     Ok(Tree { entries: entries })
 }
 
+// Whether a single path component is safe to check out. Like git's
+// verify_path, this keeps a tree from naming anything outside the work tree
+// or inside .git.
+pub fn is_valid_component(name: &[u8]) -> bool {
+    !name.is_empty() && name != b"." && name != b".."
+        && !name.eq_ignore_ascii_case(b".git")
+        && !name.iter().any(|&c| c == b'/' || c == b'\0')
+}
+
+// Whether a slash separated path is safe to check out
+pub fn verify_path(path: &[u8]) -> bool {
+    path.split(|&c| c == b'/').all(is_valid_component)
+}
+
+pub type Files = BTreeMap<Vec<u8>, (EntryMode, [u8; 20])>;
+
+// Every blob under a tree, keyed by its full path like index entries are
+// It's an error for any entry to have a name that isn't safe to check out
+pub fn read_recursive(repo: &Repository, hash: &str) -> GitResult<Files> {
+    let mut files = BTreeMap::new();
+    let mut stack = vec![(Vec::new(), String::from(hash))];
+    while let Some((prefix, hash)) = stack.pop() {
+        for entry in from_object(&cache::read_obj(repo, &hash)?)?.entries {
+            let mut name = prefix.clone();
+            name.extend_from_slice(&entry.name);
+            if !is_valid_component(&entry.name) {
+                return Err(GitError::from(format!("invalid path '{}'",
+                                                  String::from_utf8_lossy(&name))));
+            }
+            match entry.mode {
+                EntryMode::Tree => {
+                    name.push(b'/');
+                    stack.push((name, parse::bytes_to_hex(&entry.hash)));
+                },
+                mode => {
+                    files.insert(name, (mode, entry.hash));
+                },
+            }
+        }
+    }
+    Ok(files)
+}
+
 impl Tree {
     pub fn as_object(&self) -> Object {
         let mut data: Vec<u8> = Vec::new();
@@ -9,7 +9,12 @@ use std::time;
 #[derive(Debug)]
 pub enum GitError {
     Message(&'static str),
+    Custom(String),
     IoError(io::Error),
+    // A command that gave up after explaining why in full, like checkout
+    // listing the files it would overwrite. It's shown as it is, not as a
+    // fatal error.
+    Aborted(String),
 }
 
 pub type GitResult<T> = Result<T, GitError>;
@@ -18,7 +23,9 @@ impl error::Error for GitError {
     fn description(&self) -> &str {
         match *self {
             GitError::Message(msg) => msg,
+            GitError::Custom(ref msg) => msg,
             GitError::IoError(ref err) => err.description(),
+            GitError::Aborted(ref msg) => msg,
         }
     }
 
@@ -34,7 +41,9 @@ impl fmt::Display for GitError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             GitError::Message(msg) => msg.fmt(f),
+            GitError::Custom(ref msg) => msg.fmt(f),
             GitError::IoError(ref err) => err.fmt(f),
+            GitError::Aborted(ref msg) => msg.fmt(f),
         }
     }
 }
@@ -45,6 +54,12 @@ impl From<&'static str> for GitError {
     }
 }
 
+impl From<String> for GitError {
+    fn from(err: String) -> GitError {
+        GitError::Custom(err)
+    }
+}
+
 impl From<io::Error> for GitError {
     fn from(err: io::Error) -> GitError {
         GitError::IoError(err)
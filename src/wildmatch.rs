@@ -0,0 +1,422 @@
+// Glob matching with git's wildmatch rules
+// "*" and "?" don't match "/" when matching paths, "**" between slashes
+// matches any number of directories, and "[...]" is a character class.
+
+pub struct MatchFlags {
+    // Wildcards don't match "/"
+    pub pathname: bool,
+    pub ignore_case: bool,
+}
+
+fn lower(c: u8, flags: &MatchFlags) -> u8 {
+    if flags.ignore_case { c.to_ascii_lowercase() } else { c }
+}
+
+// A POSIX class like "[:alpha:]" inside brackets, or None if the name isn't
+// one. With ignore_case, c has already been lowercased.
+fn posix_class(name: &[u8], c: u8, flags: &MatchFlags) -> Option<bool> {
+    let matched = match name {
+        b"alnum" => c.is_ascii_alphanumeric(),
+        b"alpha" => c.is_ascii_alphabetic(),
+        b"blank" => c == b' ' || c == b'\t',
+        b"cntrl" => c.is_ascii_control(),
+        b"digit" => c.is_ascii_digit(),
+        b"graph" => c.is_ascii_graphic(),
+        b"lower" => c.is_ascii_lowercase(),
+        b"print" => c.is_ascii_graphic() || c == b' ',
+        b"punct" => c.is_ascii_punctuation(),
+        b"space" => c == b' ' || (b'\t'..=b'\r').contains(&c),
+        b"upper" => c.is_ascii_uppercase() || (flags.ignore_case && c.is_ascii_lowercase()),
+        b"xdigit" => c.is_ascii_hexdigit(),
+        _ => return None,
+    };
+    Some(matched)
+}
+
+// Match a [...] class starting after the "[", returning whether it matched
+// and where the class ends, or None if the class is malformed
+fn match_class(pattern: &[u8], mut p: usize, c: u8, flags: &MatchFlags)
+        -> Option<(bool, usize)> {
+    let negated = p < pattern.len() && (pattern[p] == b'!' || pattern[p] == b'^');
+    if negated {
+        p += 1;
+    }
+    let c = lower(c, flags);
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        if p >= pattern.len() {
+            // Unterminated classes never match
+            return None;
+        }
+        let mut lo = pattern[p];
+        if lo == b']' && !first {
+            p += 1;
+            break;
+        }
+        first = false;
+        if lo == b'[' && pattern.get(p + 1) == Some(&b':') {
+            // Without a closing ":]" the "[" is just a character
+            let end = p + 2 + pattern[p + 2..].iter().position(|&b| b == b']')?;
+            if end > p + 2 && pattern[end - 1] == b':' {
+                if posix_class(&pattern[p + 2..end - 1], c, flags)? {
+                    matched = true;
+                }
+                p = end + 1;
+                continue;
+            }
+        }
+        if lo == b'\\' && p + 1 < pattern.len() {
+            p += 1;
+            lo = pattern[p];
+        }
+        p += 1;
+        if p + 1 < pattern.len() && pattern[p] == b'-' && pattern[p + 1] != b']' {
+            let mut hi = pattern[p + 1];
+            p += 2;
+            if hi == b'\\' && p < pattern.len() {
+                hi = pattern[p];
+                p += 1;
+            }
+            // Like git, the ends of a range keep their case, and either case
+            // of the character can fall within it
+            if (lo <= c && c <= hi) || (flags.ignore_case && c.is_ascii_lowercase()
+                                        && lo <= c.to_ascii_uppercase()
+                                        && c.to_ascii_uppercase() <= hi) {
+                matched = true;
+            }
+        } else if lower(lo, flags) == c {
+            matched = true;
+        }
+    }
+    Some((matched != negated, p))
+}
+
+fn do_match(pattern: &[u8], mut p: usize, text: &[u8], mut t: usize,
+            flags: &MatchFlags) -> bool {
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                let double = p + 1 < pattern.len() && pattern[p + 1] == b'*';
+                if double && flags.pathname {
+                    // Only special as a whole path component
+                    let at_start = p == 0 || pattern[p - 1] == b'/';
+                    let mut end = p + 2;
+                    while end < pattern.len() && pattern[end] == b'*' {
+                        end += 1;
+                    }
+                    let at_end = end == pattern.len() || pattern[end] == b'/';
+                    if at_start && at_end {
+                        if end == pattern.len() {
+                            return true;
+                        }
+                        // "**/" also matches no directories at all
+                        if do_match(pattern, end + 1, text, t, flags) {
+                            return true;
+                        }
+                        for i in t..text.len() {
+                            if text[i] == b'/' && do_match(pattern, end + 1, text, i + 1, flags) {
+                                return true;
+                            }
+                        }
+                        return false;
+                    }
+                }
+                let mut next = p + 1;
+                while next < pattern.len() && pattern[next] == b'*' {
+                    next += 1;
+                }
+                // "**" that isn't a whole component acts like "*"
+                let crosses_slash = !flags.pathname;
+                let mut i = t;
+                loop {
+                    if do_match(pattern, next, text, i, flags) {
+                        return true;
+                    }
+                    if i >= text.len() || (!crosses_slash && text[i] == b'/') {
+                        return false;
+                    }
+                    i += 1;
+                }
+            },
+            b'?' => {
+                if t >= text.len() || (flags.pathname && text[t] == b'/') {
+                    return false;
+                }
+                p += 1;
+                t += 1;
+            },
+            b'[' => {
+                if t >= text.len() || (flags.pathname && text[t] == b'/') {
+                    return false;
+                }
+                match match_class(pattern, p + 1, text[t], flags) {
+                    Some((true, end)) => {
+                        p = end;
+                        t += 1;
+                    },
+                    _ => return false,
+                }
+            },
+            b'\\' => {
+                // A trailing backslash escapes nothing, so never matches
+                if p + 1 >= pattern.len() || t >= text.len()
+                        || lower(pattern[p + 1], flags) != lower(text[t], flags) {
+                    return false;
+                }
+                p += 2;
+                t += 1;
+            },
+            c => {
+                if t >= text.len() || lower(c, flags) != lower(text[t], flags) {
+                    return false;
+                }
+                p += 1;
+                t += 1;
+            },
+        }
+    }
+    t == text.len()
+}
+
+pub fn wildmatch(pattern: &[u8], text: &[u8], flags: &MatchFlags) -> bool {
+    do_match(pattern, 0, text, 0, flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From git's t3070-wildmatch.sh. The flags give the result with
+    // pathname alone, pathname and ignore_case, neither, and ignore_case
+    // alone, in that order, with "x" where git doesn't check one.
+    const CASES: &[(&str, &str, &str)] = &[
+        // Basic wildmatch features
+        ("1111", "foo", "foo"),
+        ("0000", "foo", "bar"),
+        ("1111", "", ""),
+        ("1111", "foo", "???"),
+        ("0000", "foo", "??"),
+        ("1111", "foo", "*"),
+        ("1111", "foo", "f*"),
+        ("0000", "foo", "*f"),
+        ("1111", "foo", "*foo*"),
+        ("1111", "foobar", "*ob*a*r*"),
+        ("1111", "aaaaaaabababab", "*ab"),
+        ("1111", "foo*", r"foo\*"),
+        ("0000", "foobar", r"foo\*bar"),
+        ("1111", r"f\oo", r"f\\oo"),
+        ("1111", "ball", "*[al]?"),
+        ("0000", "ten", "[ten]"),
+        ("1111", "ten", "**[!te]"),
+        ("0000", "ten", "**[!ten]"),
+        ("1111", "ten", "t[a-g]n"),
+        ("0000", "ten", "t[!a-g]n"),
+        ("1111", "ton", "t[!a-g]n"),
+        ("1111", "ton", "t[^a-g]n"),
+        ("1111", "a]b", "a[]]b"),
+        ("1111", "a-b", "a[]-]b"),
+        ("1111", "a]b", "a[]-]b"),
+        ("0000", "aab", "a[]-]b"),
+        ("1111", "aab", "a[]a-]b"),
+        ("1111", "]", "]"),
+
+        // Extended slash-matching features
+        ("0011", "foo/baz/bar", "foo*bar"),
+        ("0011", "foo/baz/bar", "foo**bar"),
+        ("1111", "foobazbar", "foo**bar"),
+        ("1111", "foo/baz/bar", "foo/**/bar"),
+        ("1100", "foo/baz/bar", "foo/**/**/bar"),
+        ("1111", "foo/b/a/z/bar", "foo/**/bar"),
+        ("1111", "foo/b/a/z/bar", "foo/**/**/bar"),
+        ("1100", "foo/bar", "foo/**/bar"),
+        ("1100", "foo/bar", "foo/**/**/bar"),
+        ("0011", "foo/bar", "foo?bar"),
+        ("0011", "foo/bar", "foo[/]bar"),
+        ("0011", "foo/bar", "foo[^a-z]bar"),
+        ("0011", "foo/bar", "f[^eiu][^eiu][^eiu][^eiu][^eiu]r"),
+        ("1111", "foo-bar", "f[^eiu][^eiu][^eiu][^eiu][^eiu]r"),
+        ("1100", "foo", "**/foo"),
+        ("11xx", "XXX/foo", "**/foo"),
+        ("1111", "bar/baz/foo", "**/foo"),
+        ("0011", "bar/baz/foo", "*/foo"),
+        ("0011", "foo/bar/baz", "**/bar*"),
+        ("1111", "deep/foo/bar/baz", "**/bar/*"),
+        ("0011", "deep/foo/bar/baz/", "**/bar/*"),
+        ("1111", "deep/foo/bar/baz/", "**/bar/**"),
+        ("0000", "deep/foo/bar", "**/bar/*"),
+        ("1111", "deep/foo/bar/", "**/bar/**"),
+        ("0011", "foo/bar/baz", "**/bar**"),
+        ("1111", "foo/bar/baz/x", "*/bar/**"),
+        ("0011", "deep/foo/bar/baz/x", "*/bar/**"),
+        ("1111", "deep/foo/bar/baz/x", "**/bar/*/*"),
+
+        // Various additional tests
+        ("0000", "acrt", "a[c-c]st"),
+        ("1111", "acrt", "a[c-c]rt"),
+        ("0000", "]", "[!]-]"),
+        ("1111", "a", "[!]-]"),
+        ("0000", "", r"\"),
+        ("0000", r"\", r"\"),
+        ("0000", r"XXX/\", r"*/\"),
+        ("1111", r"XXX/\", r"*/\\"),
+        ("1111", "foo", "foo"),
+        ("1111", "@foo", "@foo"),
+        ("0000", "foo", "@foo"),
+        ("1111", "[ab]", r"\[ab]"),
+        ("1111", "[ab]", "[[]ab]"),
+        ("1111", "[ab]", "[[:]ab]"),
+        ("0000", "[ab]", "[[::]ab]"),
+        ("1111", "[ab]", "[[:digit]ab]"),
+        ("1111", "[ab]", r"[\[:]ab]"),
+        ("1111", "?a?b", r"\??\?b"),
+        ("1111", "abc", r"\a\b\c"),
+        ("0000", "foo", ""),
+        ("1111", "foo/bar/baz/to", "**/t[o]"),
+
+        // Character class tests
+        ("1x1x", "a1B", "[[:alpha:]][[:digit:]][[:upper:]]"),
+        ("0101", "a", "[[:digit:][:upper:][:space:]]"),
+        ("1x1x", "A", "[[:digit:][:upper:][:space:]]"),
+        ("1x1x", "1", "[[:digit:][:upper:][:space:]]"),
+        ("0x0x", "1", "[[:digit:][:upper:][:spaci:]]"),
+        ("1x1x", " ", "[[:digit:][:upper:][:space:]]"),
+        ("0x0x", ".", "[[:digit:][:upper:][:space:]]"),
+        ("1x1x", ".", "[[:digit:][:punct:][:space:]]"),
+        ("1x1x", "5", "[[:xdigit:]]"),
+        ("1x1x", "f", "[[:xdigit:]]"),
+        ("1x1x", "D", "[[:xdigit:]]"),
+        ("1x1x", "_", "[[:alnum:][:alpha:][:blank:][:cntrl:][:digit:][:graph:]\
+                        [:lower:][:print:][:punct:][:space:][:upper:][:xdigit:]]"),
+        ("1x1x", ".", "[^[:alnum:][:alpha:][:blank:][:cntrl:][:digit:][:lower:]\
+                        [:space:][:upper:][:xdigit:]]"),
+        ("1x1x", "5", "[a-c[:digit:]x-z]"),
+        ("1x1x", "b", "[a-c[:digit:]x-z]"),
+        ("1x1x", "y", "[a-c[:digit:]x-z]"),
+        ("0x0x", "q", "[a-c[:digit:]x-z]"),
+
+        // Additional tests, including some malformed wildmatch patterns
+        ("1111", "]", r"[\\-^]"),
+        ("0000", "[", r"[\\-^]"),
+        ("1111", "-", r"[\-_]"),
+        ("1111", "]", r"[\]]"),
+        ("0000", r"\]", r"[\]]"),
+        ("0000", r"\", r"[\]]"),
+        ("0000", "ab", "a[]b"),
+        ("0x00", "a[]b", "a[]b"),
+        ("0x00", "ab[", "ab["),
+        ("0000", "ab", "[!"),
+        ("0000", "ab", "[-"),
+        ("1111", "-", "[-]"),
+        ("0000", "-", "[a-"),
+        ("0000", "-", "[!a-"),
+        ("1x1x", "-", "[--A]"),
+        ("1x1x", "5", "[--A]"),
+        ("1111", " ", "[ --]"),
+        ("1111", "$", "[ --]"),
+        ("1111", "-", "[ --]"),
+        ("0000", "0", "[ --]"),
+        ("1x1x", "-", "[---]"),
+        ("1x1x", "-", "[------]"),
+        ("0000", "j", "[a-e-n]"),
+        ("1x1x", "-", "[a-e-n]"),
+        ("1x1x", "a", "[!------]"),
+        ("0000", "[", "[]-a]"),
+        ("1x1x", "^", "[]-a]"),
+        ("0000", "^", "[!]-a]"),
+        ("1x1x", "[", "[!]-a]"),
+        ("1111", "^", "[a^bc]"),
+        ("1x1x", "-b]", "[a-]b]"),
+        ("0000", r"\", r"[\]"),
+        ("1111", r"\", r"[\\]"),
+        ("0000", r"\", r"[!\\]"),
+        ("1111", "G", r"[A-\\]"),
+        ("0000", "aaabbb", "b*a"),
+        ("0000", "aabcaa", "*ba*"),
+        ("1111", ",", "[,]"),
+        ("1111", ",", r"[\\,]"),
+        ("1111", r"\", r"[\\,]"),
+        ("1111", "-", "[,-.]"),
+        ("0000", "+", "[,-.]"),
+        ("0000", "-.]", "[,-.]"),
+        ("1111", "2", r"[\1-\3]"),
+        ("1111", "3", r"[\1-\3]"),
+        ("0000", "4", r"[\1-\3]"),
+        ("1111", r"\", r"[[-\]]"),
+        ("1111", "[", r"[[-\]]"),
+        ("1111", "]", r"[[-\]]"),
+        ("0000", "-", r"[[-\]]"),
+
+        // Test recursion
+        ("1111", "-adobe-courier-bold-o-normal--12-120-75-75-m-70-iso8859-1",
+         "-*-*-*-*-*-*-12-*-*-*-m-*-*-*"),
+        ("0000", "-adobe-courier-bold-o-normal--12-120-75-75-X-70-iso8859-1",
+         "-*-*-*-*-*-*-12-*-*-*-m-*-*-*"),
+        ("0000", "-adobe-courier-bold-o-normal--12-120-75-75-/-70-iso8859-1",
+         "-*-*-*-*-*-*-12-*-*-*-m-*-*-*"),
+        ("1111", "XXX/adobe/courier/bold/o/normal//12/120/75/75/m/70/iso8859/1",
+         "XXX/*/*/*/*/*/*/12/*/*/*/m/*/*/*"),
+        ("0000", "XXX/adobe/courier/bold/o/normal//12/120/75/75/X/70/iso8859/1",
+         "XXX/*/*/*/*/*/*/12/*/*/*/m/*/*/*"),
+        ("1111", "abcd/abcdefg/abcdefghijk/abcdefghijklmnop.txt", "**/*a*b*g*n*t"),
+        ("0000", "abcd/abcdefg/abcdefghijk/abcdefghijklmnop.txtz", "**/*a*b*g*n*t"),
+        ("0000", "foo", "*/*/*"),
+        ("0000", "foo/bar", "*/*/*"),
+        ("1111", "foo/bba/arr", "*/*/*"),
+        ("0011", "foo/bb/aa/rr", "*/*/*"),
+        ("1111", "foo/bb/aa/rr", "**/**/**"),
+        ("1111", "abcXdefXghi", "*X*i"),
+        ("0011", "ab/cXd/efXg/hi", "*X*i"),
+        ("1111", "ab/cXd/efXg/hi", "*/*X*/*/*i"),
+        ("1111", "ab/cXd/efXg/hi", "**/*X*/**/*i"),
+
+        // Extra pathmatch tests
+        ("0000", "foo", "fo"),
+        ("1111", "foo/bar", "foo/bar"),
+        ("1111", "foo/bar", "foo/*"),
+        ("0011", "foo/bba/arr", "foo/*"),
+        ("1111", "foo/bba/arr", "foo/**"),
+        ("0011", "foo/bba/arr", "foo*"),
+        ("0011", "foo/bba/arr", "foo**"),
+        ("0011", "foo/bba/arr", "foo/*arr"),
+        ("0011", "foo/bba/arr", "foo/**arr"),
+        ("0000", "foo/bba/arr", "foo/*z"),
+        ("0000", "foo/bba/arr", "foo/**z"),
+        ("0011", "foo/bar", "foo?bar"),
+        ("0011", "foo/bar", "foo[/]bar"),
+        ("0011", "foo/bar", "foo[^a-z]bar"),
+        ("0011", "ab/cXd/efXg/hi", "*Xg*i"),
+
+        // Extra case-sensitivity tests
+        ("0101", "a", "[A-Z]"),
+        ("1111", "A", "[A-Z]"),
+        ("0101", "A", "[a-z]"),
+        ("1111", "a", "[a-z]"),
+        ("0101", "a", "[[:upper:]]"),
+        ("1111", "A", "[[:upper:]]"),
+        ("0101", "A", "[[:lower:]]"),
+        ("1111", "a", "[[:lower:]]"),
+        ("0101", "A", "[B-Za]"),
+        ("1111", "a", "[B-Za]"),
+        ("0101", "A", "[B-a]"),
+        ("1111", "a", "[B-a]"),
+        ("0101", "z", "[Z-y]"),
+        ("1111", "Z", "[Z-y]"),
+    ];
+
+    #[test]
+    fn t3070() {
+        for &(expected, text, pattern) in CASES {
+            let modes = [(true, false), (true, true), (false, false), (false, true)];
+            for (&(pathname, ignore_case), result) in modes.iter().zip(expected.chars()) {
+                if result == 'x' {
+                    continue;
+                }
+                let flags = MatchFlags { pathname, ignore_case };
+                assert_eq!(wildmatch(pattern.as_bytes(), text.as_bytes(), &flags), result == '1',
+                           "{:?} against {:?} with pathname {} and ignore_case {}",
+                           pattern, text, pathname, ignore_case);
+            }
+        }
+    }
+}
@@ -0,0 +1,1345 @@
+// Line diffs, following git's xdiff so hunks come out the same as git's
+//
+// Lines are compared whole, newline included. Before diffing, lines common
+// to the start and end are trimmed, and lines with no match in the other
+// file are marked changed up front. Myers' algorithm then finds a shortest
+// edit script, using the linear-space divide and conquer refinement, with
+// git's heuristics to bail out of expensive searches. Patience and histogram
+// diffs instead anchor on lines that are rare in both files, and fall back to
+// Myers where there are none. Finally groups of changes are slid up or down
+// to where they read best.
+
+use std::collections::HashMap;
+use std::ops::{Index, IndexMut};
+
+// Tuning constants, all from xdiff
+const MAX_COST_MIN: isize = 256;
+const SNAKE_CNT: isize = 20;
+const HEUR_MIN_COST: isize = 256;
+const K_HEUR: isize = 4;
+const MAX_EQLIMIT: usize = 1024;
+const SIMSCAN_WINDOW: usize = 100;
+const KPDIS_RUN: usize = 4;
+const LINE_MAX: isize = isize::MAX;
+// Lines more common than this aren't used to anchor a histogram diff
+const MAX_CHAIN_LENGTH: usize = 64;
+
+// For the indent heuristic
+const MAX_INDENT: i32 = 200;
+const MAX_BLANKS: i32 = 20;
+const INDENT_HEURISTIC_MAX_SLIDING: isize = 100;
+const START_OF_FILE_PENALTY: i32 = 1;
+const END_OF_FILE_PENALTY: i32 = 21;
+const TOTAL_BLANK_WEIGHT: i32 = -30;
+const POST_BLANK_WEIGHT: i32 = 6;
+const RELATIVE_INDENT_PENALTY: i32 = -4;
+const RELATIVE_INDENT_WITH_BLANK_PENALTY: i32 = 10;
+const RELATIVE_OUTDENT_PENALTY: i32 = 24;
+const RELATIVE_OUTDENT_WITH_BLANK_PENALTY: i32 = 17;
+const RELATIVE_DEDENT_PENALTY: i32 = 23;
+const RELATIVE_DEDENT_WITH_BLANK_PENALTY: i32 = 17;
+const INDENT_WEIGHT: i32 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Myers,
+    // Myers without the heuristics that give up on finding the smallest diff
+    Minimal,
+    Patience,
+    Histogram,
+}
+
+impl Algorithm {
+    // The algorithm for a --diff-algorithm or diff.algorithm value
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name.to_lowercase().as_str() {
+            "myers" | "default" => Some(Algorithm::Myers),
+            "minimal" => Some(Algorithm::Minimal),
+            "patience" => Some(Algorithm::Patience),
+            "histogram" => Some(Algorithm::Histogram),
+            _ => None,
+        }
+    }
+}
+
+// A run of changed lines: chg1 lines at i1 in the old file were replaced by
+// chg2 lines at i2 in the new one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub i1: usize,
+    pub i2: usize,
+    pub chg1: usize,
+    pub chg2: usize,
+}
+
+// Split data into lines, each keeping its newline. The last line may not
+// have one.
+pub fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (ndx, &c) in data.iter().enumerate() {
+        if c == b'\n' {
+            lines.push(&data[start..ndx + 1]);
+            start = ndx + 1;
+        }
+    }
+    if start < data.len() {
+        lines.push(&data[start..]);
+    }
+    lines
+}
+
+struct DiffFile<'a> {
+    recs: &'a [&'a [u8]],
+    // Equal lines, in either file, share a class
+    ha: Vec<usize>,
+    // Whether each line changed, offset by one so that the lines before
+    // the start and after the end can be looked at, and are unchanged
+    rchg: Vec<bool>,
+    // The lines left to diff after trimming and discarding, as indexes into
+    // recs, and their classes
+    rindex: Vec<usize>,
+    reff_ha: Vec<usize>,
+}
+
+impl<'a> DiffFile<'a> {
+    fn nrec(&self) -> isize {
+        self.recs.len() as isize
+    }
+
+    fn changed(&self, i: isize) -> bool {
+        self.rchg[(i + 1) as usize]
+    }
+
+    fn set_changed(&mut self, i: isize, changed: bool) {
+        self.rchg[(i + 1) as usize] = changed;
+    }
+}
+
+// Give every distinct line a class, and count how often each class appears
+// in each file
+fn classify<'a>(file1: &[&'a [u8]], file2: &[&'a [u8]])
+        -> (Vec<usize>, Vec<usize>, Vec<(usize, usize)>) {
+    let mut classes: HashMap<&[u8], usize> = HashMap::new();
+    let mut counts: Vec<(usize, usize)> = Vec::new();
+    let mut ha1 = Vec::with_capacity(file1.len());
+    let mut ha2 = Vec::with_capacity(file2.len());
+    let mut class_of = |rec| {
+        let next = classes.len();
+        let class = *classes.entry(rec).or_insert(next);
+        if class == counts.len() {
+            counts.push((0, 0));
+        }
+        class
+    };
+    for rec in file1 {
+        let class = class_of(rec);
+        ha1.push(class);
+    }
+    for rec in file2 {
+        let class = class_of(rec);
+        ha2.push(class);
+    }
+    for &class in ha1.iter() {
+        counts[class].0 += 1;
+    }
+    for &class in ha2.iter() {
+        counts[class].1 += 1;
+    }
+    (ha1, ha2, counts)
+}
+
+// Roughly the square root, as a power of two
+fn bogosqrt(mut n: usize) -> usize {
+    let mut i = 1;
+    while n > 0 {
+        i <<= 1;
+        n >>= 2;
+    }
+    i
+}
+
+// Whether a line with many matches sits in a run of lines that mostly have
+// none, in which case it's better treated as changed too. dis is 0 for lines
+// with no match, 1 for a few and 2 for many.
+fn clean_mmatch(dis: &[u8], i: usize, mut s: usize, mut e: usize) -> bool {
+    if i - s > SIMSCAN_WINDOW {
+        s = i - SIMSCAN_WINDOW;
+    }
+    if e - i > SIMSCAN_WINDOW {
+        e = i + SIMSCAN_WINDOW;
+    }
+
+    let (mut rdis0, mut rpdis0) = (0, 1);
+    let mut r = 1;
+    while i >= s + r {
+        match dis[i - r] {
+            0 => rdis0 += 1,
+            2 => rpdis0 += 1,
+            _ => break,
+        }
+        r += 1;
+    }
+    if rdis0 == 0 {
+        return false;
+    }
+    let (mut rdis1, mut rpdis1) = (0, 1);
+    r = 1;
+    while i + r <= e {
+        match dis[i + r] {
+            0 => rdis1 += 1,
+            2 => rpdis1 += 1,
+            _ => break,
+        }
+        r += 1;
+    }
+    if rdis1 == 0 {
+        return false;
+    }
+    rdis1 += rdis0;
+    rpdis1 += rpdis0;
+    rpdis1 * KPDIS_RUN < rpdis1 + rdis1
+}
+
+// Leave out the lines common to the start and end, and mark lines with no
+// match in the other file as changed, so there's less left to diff
+fn optimize(file1: &mut DiffFile, file2: &mut DiffFile, counts: &[(usize, usize)]) {
+    let n1 = file1.recs.len();
+    let n2 = file2.recs.len();
+    let lim = n1.min(n2);
+    let mut start = 0;
+    while start < lim && file1.ha[start] == file2.ha[start] {
+        start += 1;
+    }
+    let mut end = 0;
+    while end < lim - start && file1.ha[n1 - 1 - end] == file2.ha[n2 - 1 - end] {
+        end += 1;
+    }
+
+    discard(file1, start, n1 - end, |class| counts[class].1);
+    discard(file2, start, n2 - end, |class| counts[class].0);
+}
+
+// Keep the lines in a range that are worth diffing. matches says how often
+// a class appears in the other file.
+fn discard<F: Fn(usize) -> usize>(file: &mut DiffFile, dstart: usize, dend: usize, matches: F) {
+    let mlim = bogosqrt(file.recs.len()).min(MAX_EQLIMIT);
+    let mut dis = vec![0; file.recs.len() + 1];
+    for (i, dis) in dis.iter_mut().enumerate().take(dend).skip(dstart) {
+        let nm = matches(file.ha[i]);
+        *dis = if nm == 0 { 0 } else if nm >= mlim { 2 } else { 1 };
+    }
+    for i in dstart..dend {
+        if dis[i] == 1 || (dis[i] == 2 && !clean_mmatch(&dis, i, dstart, dend - 1)) {
+            file.rindex.push(i);
+            let class = file.ha[i];
+            file.reff_ha.push(class);
+        } else {
+            file.set_changed(i as isize, true);
+        }
+    }
+}
+
+// A vector indexed by diagonal, which can be negative
+struct KVec {
+    v: Vec<isize>,
+    base: isize,
+}
+
+impl Index<isize> for KVec {
+    type Output = isize;
+    fn index(&self, d: isize) -> &isize {
+        &self.v[(d + self.base) as usize]
+    }
+}
+
+impl IndexMut<isize> for KVec {
+    fn index_mut(&mut self, d: isize) -> &mut isize {
+        &mut self.v[(d + self.base) as usize]
+    }
+}
+
+struct Env {
+    mxcost: isize,
+    kvdf: KVec,
+    kvdb: KVec,
+}
+
+// Where to divide the problem, and whether each half needs a minimal diff
+struct Split {
+    i1: isize,
+    i2: isize,
+    min_lo: bool,
+    min_hi: bool,
+}
+
+// Find the middle snake of the shortest edit script between the two
+// ranges, searching forward and backward at once. When that gets too
+// expensive, settle for a good enough split point instead.
+fn split(ha1: &[usize], (off1, lim1): (isize, isize), ha2: &[usize], (off2, lim2): (isize, isize),
+         need_min: bool, env: &mut Env) -> Split {
+    let dmin = off1 - lim2;
+    let dmax = lim1 - off2;
+    let fmid = off1 - off2;
+    let bmid = lim1 - lim2;
+    let odd = (fmid - bmid) & 1 != 0;
+    let (mut fmin, mut fmax) = (fmid, fmid);
+    let (mut bmin, mut bmax) = (bmid, bmid);
+    let h1 = |i: isize| ha1[i as usize];
+    let h2 = |i: isize| ha2[i as usize];
+
+    env.kvdf[fmid] = off1;
+    env.kvdb[bmid] = lim1;
+
+    let mut ec = 1;
+    loop {
+        let mut got_snake = false;
+
+        // Widen the range of diagonals by one, or narrow it at the edges
+        if fmin > dmin {
+            fmin -= 1;
+            env.kvdf[fmin - 1] = -1;
+        } else {
+            fmin += 1;
+        }
+        if fmax < dmax {
+            fmax += 1;
+            env.kvdf[fmax + 1] = -1;
+        } else {
+            fmax -= 1;
+        }
+
+        let mut d = fmax;
+        while d >= fmin {
+            let mut i1 = if env.kvdf[d - 1] >= env.kvdf[d + 1] {
+                env.kvdf[d - 1] + 1
+            } else {
+                env.kvdf[d + 1]
+            };
+            let prev1 = i1;
+            let mut i2 = i1 - d;
+            while i1 < lim1 && i2 < lim2 && h1(i1) == h2(i2) {
+                i1 += 1;
+                i2 += 1;
+            }
+            if i1 - prev1 > SNAKE_CNT {
+                got_snake = true;
+            }
+            env.kvdf[d] = i1;
+            if odd && bmin <= d && d <= bmax && env.kvdb[d] <= i1 {
+                return Split { i1, i2, min_lo: true, min_hi: true };
+            }
+            d -= 2;
+        }
+
+        if bmin > dmin {
+            bmin -= 1;
+            env.kvdb[bmin - 1] = LINE_MAX;
+        } else {
+            bmin += 1;
+        }
+        if bmax < dmax {
+            bmax += 1;
+            env.kvdb[bmax + 1] = LINE_MAX;
+        } else {
+            bmax -= 1;
+        }
+
+        d = bmax;
+        while d >= bmin {
+            let mut i1 = if env.kvdb[d - 1] < env.kvdb[d + 1] {
+                env.kvdb[d - 1]
+            } else {
+                env.kvdb[d + 1] - 1
+            };
+            let prev1 = i1;
+            let mut i2 = i1 - d;
+            while i1 > off1 && i2 > off2 && h1(i1 - 1) == h2(i2 - 1) {
+                i1 -= 1;
+                i2 -= 1;
+            }
+            if prev1 - i1 > SNAKE_CNT {
+                got_snake = true;
+            }
+            env.kvdb[d] = i1;
+            if !odd && fmin <= d && d <= fmax && i1 <= env.kvdf[d] {
+                return Split { i1, i2, min_lo: true, min_hi: true };
+            }
+            d -= 2;
+        }
+
+        if need_min {
+            ec += 1;
+            continue;
+        }
+
+        // Once the edit cost is high, take any diagonal that has got far
+        // from its corner without straying far from the middle, as long as
+        // it ends in a long enough snake
+        if got_snake && ec > HEUR_MIN_COST {
+            let mut best = 0;
+            let mut found = None;
+            d = fmax;
+            while d >= fmin {
+                let dd = if d > fmid { d - fmid } else { fmid - d };
+                let i1 = env.kvdf[d];
+                let i2 = i1 - d;
+                let v = (i1 - off1) + (i2 - off2) - dd;
+                if v > K_HEUR * ec && v > best && off1 + SNAKE_CNT <= i1 && i1 < lim1
+                        && off2 + SNAKE_CNT <= i2 && i2 < lim2 {
+                    let mut k = 1;
+                    while h1(i1 - k) == h2(i2 - k) {
+                        if k == SNAKE_CNT {
+                            best = v;
+                            found = Some((i1, i2));
+                            break;
+                        }
+                        k += 1;
+                    }
+                }
+                d -= 2;
+            }
+            if let Some((i1, i2)) = found {
+                return Split { i1, i2, min_lo: true, min_hi: false };
+            }
+
+            best = 0;
+            d = bmax;
+            while d >= bmin {
+                let dd = if d > bmid { d - bmid } else { bmid - d };
+                let i1 = env.kvdb[d];
+                let i2 = i1 - d;
+                let v = (lim1 - i1) + (lim2 - i2) - dd;
+                if v > K_HEUR * ec && v > best && off1 < i1 && i1 <= lim1 - SNAKE_CNT
+                        && off2 < i2 && i2 <= lim2 - SNAKE_CNT {
+                    let mut k = 0;
+                    while h1(i1 + k) == h2(i2 + k) {
+                        if k == SNAKE_CNT - 1 {
+                            best = v;
+                            found = Some((i1, i2));
+                            break;
+                        }
+                        k += 1;
+                    }
+                }
+                d -= 2;
+            }
+            if let Some((i1, i2)) = found {
+                return Split { i1, i2, min_lo: false, min_hi: true };
+            }
+        }
+
+        // Enough is enough: split at whichever path has got furthest
+        if ec >= env.mxcost {
+            let (mut fbest, mut fbest1) = (-1, -1);
+            d = fmax;
+            while d >= fmin {
+                let mut i1 = env.kvdf[d].min(lim1);
+                let mut i2 = i1 - d;
+                if lim2 < i2 {
+                    i1 = lim2 + d;
+                    i2 = lim2;
+                }
+                if fbest < i1 + i2 {
+                    fbest = i1 + i2;
+                    fbest1 = i1;
+                }
+                d -= 2;
+            }
+
+            let (mut bbest, mut bbest1) = (LINE_MAX, LINE_MAX);
+            d = bmax;
+            while d >= bmin {
+                let mut i1 = env.kvdb[d].max(off1);
+                let mut i2 = i1 - d;
+                if i2 < off2 {
+                    i1 = off2 + d;
+                    i2 = off2;
+                }
+                if i1 + i2 < bbest {
+                    bbest = i1 + i2;
+                    bbest1 = i1;
+                }
+                d -= 2;
+            }
+
+            return if (lim1 + lim2) - bbest < fbest - (off1 + off2) {
+                Split { i1: fbest1, i2: fbest - fbest1, min_lo: true, min_hi: false }
+            } else {
+                Split { i1: bbest1, i2: bbest - bbest1, min_lo: false, min_hi: true }
+            };
+        }
+        ec += 1;
+    }
+}
+
+// Mark the changed lines between two ranges of the remaining lines
+fn compare(file1: &mut DiffFile, (mut off1, mut lim1): (isize, isize),
+           file2: &mut DiffFile, (mut off2, mut lim2): (isize, isize),
+           need_min: bool, env: &mut Env) {
+    // Skip what matches at either end
+    while off1 < lim1 && off2 < lim2
+            && file1.reff_ha[off1 as usize] == file2.reff_ha[off2 as usize] {
+        off1 += 1;
+        off2 += 1;
+    }
+    while off1 < lim1 && off2 < lim2
+            && file1.reff_ha[lim1 as usize - 1] == file2.reff_ha[lim2 as usize - 1] {
+        lim1 -= 1;
+        lim2 -= 1;
+    }
+
+    if off1 == lim1 {
+        for i in off2..lim2 {
+            let line = file2.rindex[i as usize] as isize;
+            file2.set_changed(line, true);
+        }
+    } else if off2 == lim2 {
+        for i in off1..lim1 {
+            let line = file1.rindex[i as usize] as isize;
+            file1.set_changed(line, true);
+        }
+    } else {
+        let spl = split(&file1.reff_ha, (off1, lim1), &file2.reff_ha, (off2, lim2), need_min,
+                        env);
+        compare(file1, (off1, spl.i1), file2, (off2, spl.i2), spl.min_lo, env);
+        compare(file1, (spl.i1, lim1), file2, (spl.i2, lim2), spl.min_hi, env);
+    }
+}
+
+// Find the shortest edit script, or one close to it unless it has to be
+// minimal
+fn myers(file1: &mut DiffFile, file2: &mut DiffFile, need_min: bool) {
+    let n1 = file1.reff_ha.len() as isize;
+    let n2 = file2.reff_ha.len() as isize;
+    let ndiags = n1 + n2 + 3;
+    let mut env = Env {
+        mxcost: (bogosqrt(ndiags as usize) as isize).max(MAX_COST_MIN),
+        kvdf: KVec { v: vec![0; ndiags as usize], base: n2 + 1 },
+        kvdb: KVec { v: vec![0; ndiags as usize], base: n2 + 1 },
+    };
+    compare(file1, (0, n1), file2, (0, n2), need_min, &mut env);
+}
+
+// A run of changed lines, from start up to end
+struct Group {
+    start: isize,
+    end: isize,
+}
+
+impl Group {
+    fn first(file: &DiffFile) -> Group {
+        let mut g = Group { start: 0, end: 0 };
+        while file.changed(g.end) {
+            g.end += 1;
+        }
+        g
+    }
+
+    // Move to the next group, which is empty between two unchanged lines
+    fn next(&mut self, file: &DiffFile) -> bool {
+        if self.end == file.nrec() {
+            return false;
+        }
+        self.start = self.end + 1;
+        self.end = self.start;
+        while file.changed(self.end) {
+            self.end += 1;
+        }
+        true
+    }
+
+    fn previous(&mut self, file: &DiffFile) -> bool {
+        if self.start == 0 {
+            return false;
+        }
+        self.end = self.start - 1;
+        self.start = self.end;
+        while file.changed(self.start - 1) {
+            self.start -= 1;
+        }
+        true
+    }
+
+    // Shift the group down a line if the line after it matches its first
+    // line, merging with any group it runs into
+    fn slide_down(&mut self, file: &mut DiffFile) -> bool {
+        if self.end < file.nrec() && file.ha[self.start as usize] == file.ha[self.end as usize] {
+            file.set_changed(self.start, false);
+            file.set_changed(self.end, true);
+            self.start += 1;
+            self.end += 1;
+            while file.changed(self.end) {
+                self.end += 1;
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn slide_up(&mut self, file: &mut DiffFile) -> bool {
+        if self.start > 0
+                && file.ha[self.start as usize - 1] == file.ha[self.end as usize - 1] {
+            self.start -= 1;
+            self.end -= 1;
+            file.set_changed(self.start, true);
+            file.set_changed(self.end, false);
+            while file.changed(self.start - 1) {
+                self.start -= 1;
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// How far a line is indented, with tabs to multiples of 8, or -1 if it's
+// blank
+fn get_indent(rec: &[u8]) -> i32 {
+    let mut ret = 0;
+    for &c in rec {
+        match c {
+            b' ' => ret += 1,
+            b'\t' => ret += 8 - ret % 8,
+            b'\n' | b'\r' | b'\x0b' | b'\x0c' => (),
+            _ => return ret,
+        }
+        if ret >= MAX_INDENT {
+            return MAX_INDENT;
+        }
+    }
+    -1
+}
+
+struct SplitMeasurement {
+    end_of_file: bool,
+    // The indent of the line after the split
+    indent: i32,
+    // Blank lines before and after it, and the indents beyond them
+    pre_blank: i32,
+    pre_indent: i32,
+    post_blank: i32,
+    post_indent: i32,
+}
+
+fn measure_split(file: &DiffFile, split: isize) -> SplitMeasurement {
+    let (end_of_file, indent) = if split >= file.nrec() {
+        (true, -1)
+    } else {
+        (false, get_indent(file.recs[split as usize]))
+    };
+
+    let mut pre_blank = 0;
+    let mut pre_indent = -1;
+    let mut i = split - 1;
+    while i >= 0 {
+        pre_indent = get_indent(file.recs[i as usize]);
+        if pre_indent != -1 {
+            break;
+        }
+        pre_blank += 1;
+        if pre_blank == MAX_BLANKS {
+            pre_indent = 0;
+            break;
+        }
+        i -= 1;
+    }
+
+    let mut post_blank = 0;
+    let mut post_indent = -1;
+    i = split + 1;
+    while i < file.nrec() {
+        post_indent = get_indent(file.recs[i as usize]);
+        if post_indent != -1 {
+            break;
+        }
+        post_blank += 1;
+        if post_blank == MAX_BLANKS {
+            post_indent = 0;
+            break;
+        }
+        i += 1;
+    }
+
+    SplitMeasurement {
+        end_of_file,
+        indent,
+        pre_blank,
+        pre_indent,
+        post_blank,
+        post_indent,
+    }
+}
+
+// How bad a split is, as (effective indent, penalty); lower is better
+fn score_add_split(m: &SplitMeasurement, score: &mut (i32, i32)) {
+    if m.pre_indent == -1 && m.pre_blank == 0 {
+        score.1 += START_OF_FILE_PENALTY;
+    }
+    if m.end_of_file {
+        score.1 += END_OF_FILE_PENALTY;
+    }
+
+    let post_blank = if m.indent == -1 { 1 + m.post_blank } else { 0 };
+    let total_blank = m.pre_blank + post_blank;
+    score.1 += TOTAL_BLANK_WEIGHT * total_blank;
+    score.1 += POST_BLANK_WEIGHT * post_blank;
+
+    let indent = if m.indent != -1 { m.indent } else { m.post_indent };
+    let any_blanks = total_blank != 0;
+    score.0 += indent;
+
+    if indent == -1 || m.pre_indent == -1 || indent == m.pre_indent {
+        // Nothing more to say
+    } else if indent > m.pre_indent {
+        score.1 += if any_blanks {
+            RELATIVE_INDENT_WITH_BLANK_PENALTY
+        } else {
+            RELATIVE_INDENT_PENALTY
+        };
+    } else if m.post_indent != -1 && m.post_indent > indent {
+        // Probably the start of a block, like an "else"
+        score.1 += if any_blanks {
+            RELATIVE_OUTDENT_WITH_BLANK_PENALTY
+        } else {
+            RELATIVE_OUTDENT_PENALTY
+        };
+    } else {
+        // Probably the end of a block
+        score.1 += if any_blanks {
+            RELATIVE_DEDENT_WITH_BLANK_PENALTY
+        } else {
+            RELATIVE_DEDENT_PENALTY
+        };
+    }
+}
+
+fn score_cmp(s1: (i32, i32), s2: (i32, i32)) -> i32 {
+    let cmp_indents = (s1.0 > s2.0) as i32 - (s1.0 < s2.0) as i32;
+    INDENT_WEIGHT * cmp_indents + (s1.1 - s2.1)
+}
+
+// Slide each group of changes in file as far as it can go, merging groups
+// that meet, then settle it where it lines up with a change in the other
+// file, or else where the indent heuristic likes it best
+fn change_compact(file: &mut DiffFile, other: &DiffFile) {
+    let mut g = Group::first(file);
+    let mut go = Group::first(other);
+
+    loop {
+        if g.end != g.start {
+            let mut groupsize;
+            let mut earliest_end;
+            let mut end_matching_other;
+            loop {
+                groupsize = g.end - g.start;
+                end_matching_other = -1;
+
+                while g.slide_up(file) {
+                    go.previous(other);
+                }
+                earliest_end = g.end;
+                if go.end > go.start {
+                    end_matching_other = g.end;
+                }
+
+                while g.slide_down(file) {
+                    go.next(other);
+                    if go.end > go.start {
+                        end_matching_other = g.end;
+                    }
+                }
+                if groupsize == g.end - g.start {
+                    break;
+                }
+            }
+
+            if g.end == earliest_end {
+                // It can't move
+            } else if end_matching_other != -1 {
+                while go.end == go.start {
+                    g.slide_up(file);
+                    go.previous(other);
+                }
+            } else {
+                let mut shift = earliest_end;
+                if g.end - groupsize - 1 > shift {
+                    shift = g.end - groupsize - 1;
+                }
+                if g.end - INDENT_HEURISTIC_MAX_SLIDING > shift {
+                    shift = g.end - INDENT_HEURISTIC_MAX_SLIDING;
+                }
+                let mut best_shift = -1;
+                let mut best_score = (0, 0);
+                while shift <= g.end {
+                    let mut score = (0, 0);
+                    score_add_split(&measure_split(file, shift), &mut score);
+                    score_add_split(&measure_split(file, shift - groupsize), &mut score);
+                    if best_shift == -1 || score_cmp(score, best_score) <= 0 {
+                        best_score = score;
+                        best_shift = shift;
+                    }
+                    shift += 1;
+                }
+                while g.end > best_shift {
+                    g.slide_up(file);
+                    go.previous(other);
+                }
+            }
+        }
+
+        if !g.next(file) {
+            break;
+        }
+        go.next(other);
+    }
+}
+
+// Collect the changed lines of both files into runs
+fn build_script(file1: &DiffFile, file2: &DiffFile) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let (n1, n2) = (file1.nrec(), file2.nrec());
+    let (mut i1, mut i2) = (0, 0);
+    while i1 < n1 || i2 < n2 {
+        if file1.changed(i1) || file2.changed(i2) {
+            let (s1, s2) = (i1, i2);
+            while i1 < n1 && file1.changed(i1) {
+                i1 += 1;
+            }
+            while i2 < n2 && file2.changed(i2) {
+                i2 += 1;
+            }
+            changes.push(Change {
+                i1: s1 as usize,
+                i2: s2 as usize,
+                chg1: (i1 - s1) as usize,
+                chg2: (i2 - s2) as usize,
+            });
+        } else {
+            i1 += 1;
+            i2 += 1;
+        }
+    }
+    changes
+}
+
+// Mark every line in a range as changed
+fn mark_changed(file: &mut DiffFile, (start, end): (usize, usize)) {
+    for line in start..end {
+        file.set_changed(line as isize, true);
+    }
+}
+
+// Set up both files for diffing, with their lines classified
+fn prepare<'a>(lines1: &'a [&'a [u8]], lines2: &'a [&'a [u8]])
+        -> (DiffFile<'a>, DiffFile<'a>, Vec<(usize, usize)>) {
+    let (ha1, ha2, counts) = classify(lines1, lines2);
+    let new_file = |recs, ha: Vec<usize>| DiffFile {
+        recs,
+        rchg: vec![false; ha.len() + 2],
+        ha,
+        rindex: Vec::new(),
+        reff_ha: Vec::new(),
+    };
+    (new_file(lines1, ha1), new_file(lines2, ha2), counts)
+}
+
+// Diff part of each file with Myers' algorithm, as if they were whole
+// files of their own
+fn fall_back(file1: &mut DiffFile, (start1, end1): (usize, usize),
+             file2: &mut DiffFile, (start2, end2): (usize, usize)) {
+    let (recs1, recs2) = (file1.recs, file2.recs);
+    let (mut sub1, mut sub2, counts) = prepare(&recs1[start1..end1], &recs2[start2..end2]);
+    optimize(&mut sub1, &mut sub2, &counts);
+    myers(&mut sub1, &mut sub2, false);
+    for line in start1..end1 {
+        file1.set_changed(line as isize, sub1.changed((line - start1) as isize));
+    }
+    for line in start2..end2 {
+        file2.set_changed(line as isize, sub2.changed((line - start2) as isize));
+    }
+}
+
+// A line of the first file in a patience diff, and where it's found in the
+// second
+struct PatienceEntry {
+    line1: usize,
+    line2: Option<usize>,
+    // Whether it appears just once in each file
+    unique: bool,
+}
+
+// The longest sequence of lines found once in each range, in the same order
+// in both, as pairs of line numbers
+fn unique_common_lines(file1: &DiffFile, (start1, end1): (usize, usize),
+                       file2: &DiffFile, (start2, end2): (usize, usize))
+        -> Option<Vec<(usize, usize)>> {
+    let mut classes: HashMap<usize, usize> = HashMap::new();
+    let mut entries: Vec<PatienceEntry> = Vec::new();
+    for line in start1..end1 {
+        match classes.get(&file1.ha[line]) {
+            Some(&ndx) => entries[ndx].unique = false,
+            None => {
+                classes.insert(file1.ha[line], entries.len());
+                entries.push(PatienceEntry { line1: line, line2: None, unique: true });
+            },
+        }
+    }
+    let mut has_matches = false;
+    for line in start2..end2 {
+        if let Some(&ndx) = classes.get(&file2.ha[line]) {
+            has_matches = true;
+            let entry = &mut entries[ndx];
+            if entry.line2.is_some() {
+                entry.unique = false;
+            } else {
+                entry.line2 = Some(line);
+            }
+        }
+    }
+    if !has_matches {
+        return None;
+    }
+
+    // Patience sorting: for each pair in first file order, find the longest
+    // sequence so far it can extend. sequence holds the pair ending the
+    // best sequence of each length, that being the one with the smallest
+    // line in the second file.
+    let pairs: Vec<(usize, usize)> = entries.iter()
+        .filter(|entry| entry.unique)
+        .filter_map(|entry| entry.line2.map(|line2| (entry.line1, line2)))
+        .collect();
+    let mut sequence: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = Vec::with_capacity(pairs.len());
+    for (ndx, pair) in pairs.iter().enumerate() {
+        // Second file lines are all different, so this never finds one
+        let len = match sequence.binary_search_by(|&other| pairs[other].1.cmp(&pair.1)) {
+            Ok(len) | Err(len) => len,
+        };
+        previous.push(if len == 0 { None } else { Some(sequence[len - 1]) });
+        if len == sequence.len() {
+            sequence.push(ndx);
+        } else {
+            sequence[len] = ndx;
+        }
+    }
+
+    let mut common = Vec::new();
+    let mut next = sequence.last().cloned();
+    while let Some(ndx) = next {
+        common.push(pairs[ndx]);
+        next = previous[ndx];
+    }
+    common.reverse();
+    Some(common)
+}
+
+// Match up the lines found once in both ranges, then diff the gaps between
+// them the same way, since lines can become unique in a smaller range
+fn patience(file1: &mut DiffFile, (mut line1, end1): (usize, usize),
+            file2: &mut DiffFile, (mut line2, end2): (usize, usize)) {
+    if line1 == end1 || line2 == end2 {
+        mark_changed(file1, (line1, end1));
+        mark_changed(file2, (line2, end2));
+        return;
+    }
+    let common = match unique_common_lines(file1, (line1, end1), file2, (line2, end2)) {
+        Some(common) => common,
+        // Nothing in common at all
+        None => {
+            mark_changed(file1, (line1, end1));
+            mark_changed(file2, (line2, end2));
+            return;
+        },
+    };
+    if common.is_empty() {
+        fall_back(file1, (line1, end1), file2, (line2, end2));
+        return;
+    }
+
+    let mut ndx = 0;
+    loop {
+        // Grow the matching lines around each unique pair
+        let (next1, next2) = match common.get(ndx) {
+            Some(&(mut next1, mut next2)) => {
+                while next1 > line1 && next2 > line2
+                        && file1.ha[next1 - 1] == file2.ha[next2 - 1] {
+                    next1 -= 1;
+                    next2 -= 1;
+                }
+                (next1, next2)
+            },
+            None => (end1, end2),
+        };
+        while line1 < next1 && line2 < next2 && file1.ha[line1] == file2.ha[line2] {
+            line1 += 1;
+            line2 += 1;
+        }
+
+        if next1 > line1 || next2 > line2 {
+            patience(file1, (line1, next1), file2, (line2, next2));
+        }
+        if ndx == common.len() {
+            return;
+        }
+
+        // Skip over pairs that follow on directly
+        while ndx + 1 < common.len() && common[ndx + 1].0 == common[ndx].0 + 1
+                && common[ndx + 1].1 == common[ndx].1 + 1 {
+            ndx += 1;
+        }
+        line1 = common[ndx].0 + 1;
+        line2 = common[ndx].1 + 1;
+        ndx += 1;
+    }
+}
+
+// A histogram diff's matching region. Line numbers here start at 1, so 0
+// can mean there's none.
+#[derive(Default)]
+struct Region {
+    begin1: usize,
+    end1: usize,
+    begin2: usize,
+    end2: usize,
+}
+
+// Find the longest run of matching lines in the two ranges that includes
+// the rarest lines it can, or None when every common line is too common to
+// be worth it
+fn find_lcs(file1: &DiffFile, line1: usize, count1: usize,
+            file2: &DiffFile, line2: usize, count2: usize) -> Option<Region> {
+    let h1 = |line: usize| file1.ha[line - 1];
+    let h2 = |line: usize| file2.ha[line - 1];
+    let last1 = line1 + count1 - 1;
+    let last2 = line2 + count2 - 1;
+
+    // For each class in the first range, its first line and how many lines
+    // it has, and for each line, the next with the same class
+    let mut records: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut next_ptrs = vec![0; count1];
+    for ptr in (line1..last1 + 1).rev() {
+        let record = records.entry(h1(ptr)).or_insert((0, 0));
+        next_ptrs[ptr - line1] = record.0;
+        record.0 = ptr;
+        record.1 += 1;
+    }
+    let count_of = |line: usize| records[&h1(line)].1;
+
+    let mut lcs = Region::default();
+    let mut cnt = MAX_CHAIN_LENGTH + 1;
+    let mut has_common = false;
+    let mut b_ptr = line2;
+    while b_ptr <= last2 {
+        let mut b_next = b_ptr + 1;
+        match records.get(&h2(b_ptr)) {
+            Some(&(_, count)) if count > cnt => has_common = true,
+            Some(&(first, count)) => {
+                has_common = true;
+                let mut a_ptr = first;
+                loop {
+                    let mut np = next_ptrs[a_ptr - line1];
+                    let (mut as_, mut bs) = (a_ptr, b_ptr);
+                    let (mut ae, mut be) = (a_ptr, b_ptr);
+                    let mut rc = count;
+                    while line1 < as_ && line2 < bs && h1(as_ - 1) == h2(bs - 1) {
+                        as_ -= 1;
+                        bs -= 1;
+                        if rc > 1 {
+                            rc = rc.min(count_of(as_));
+                        }
+                    }
+                    while ae < last1 && be < last2 && h1(ae + 1) == h2(be + 1) {
+                        ae += 1;
+                        be += 1;
+                        if rc > 1 {
+                            rc = rc.min(count_of(ae));
+                        }
+                    }
+
+                    if b_next <= be {
+                        b_next = be + 1;
+                    }
+                    if lcs.end1 - lcs.begin1 < ae - as_ || rc < cnt {
+                        lcs = Region { begin1: as_, end1: ae, begin2: bs, end2: be };
+                        cnt = rc;
+                    }
+
+                    // Try the next occurrence past this run
+                    while np != 0 && np <= ae {
+                        np = next_ptrs[np - line1];
+                    }
+                    if np == 0 {
+                        break;
+                    }
+                    a_ptr = np;
+                }
+            },
+            None => (),
+        }
+        b_ptr = b_next;
+    }
+
+    if has_common && cnt > MAX_CHAIN_LENGTH {
+        None
+    } else {
+        Some(lcs)
+    }
+}
+
+// Split the ranges around their best matching run and diff each side, so
+// that rare lines anchor the diff
+fn histogram(file1: &mut DiffFile, (mut line1, mut count1): (usize, usize),
+             file2: &mut DiffFile, (mut line2, mut count2): (usize, usize)) {
+    loop {
+        if count1 == 0 || count2 == 0 {
+            mark_changed(file1, (line1 - 1, line1 - 1 + count1));
+            mark_changed(file2, (line2 - 1, line2 - 1 + count2));
+            return;
+        }
+        let lcs = match find_lcs(file1, line1, count1, file2, line2, count2) {
+            Some(lcs) => lcs,
+            None => {
+                fall_back(file1, (line1 - 1, line1 - 1 + count1),
+                          file2, (line2 - 1, line2 - 1 + count2));
+                return;
+            },
+        };
+        if lcs.begin1 == 0 && lcs.begin2 == 0 {
+            mark_changed(file1, (line1 - 1, line1 - 1 + count1));
+            mark_changed(file2, (line2 - 1, line2 - 1 + count2));
+            return;
+        }
+        histogram(file1, (line1, lcs.begin1 - line1), file2, (line2, lcs.begin2 - line2));
+        count1 = line1 + count1 - 1 - lcs.end1;
+        line1 = lcs.end1 + 1;
+        count2 = line2 + count2 - 1 - lcs.end2;
+        line2 = lcs.end2 + 1;
+    }
+}
+
+// Diff two files' lines, as split_lines gives them
+pub fn diff(lines1: &[&[u8]], lines2: &[&[u8]], algorithm: Algorithm) -> Vec<Change> {
+    let (mut file1, mut file2, counts) = prepare(lines1, lines2);
+    let (n1, n2) = (lines1.len(), lines2.len());
+    match algorithm {
+        Algorithm::Myers | Algorithm::Minimal => {
+            optimize(&mut file1, &mut file2, &counts);
+            myers(&mut file1, &mut file2, algorithm == Algorithm::Minimal);
+        },
+        Algorithm::Patience => patience(&mut file1, (0, n1), &mut file2, (0, n2)),
+        Algorithm::Histogram => histogram(&mut file1, (1, n1), &mut file2, (1, n2)),
+    }
+
+    change_compact(&mut file1, &file2);
+    change_compact(&mut file2, &file1);
+    build_script(&file1, &file2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::write_hunks;
+
+    // The expected hunks are all git diff's output for the same files
+
+    fn hunks(old: &str, new: &str, algorithm: Algorithm, context: usize) -> String {
+        let (lines1, lines2) = (split_lines(old.as_bytes()), split_lines(new.as_bytes()));
+        let changes = diff(&lines1, &lines2, algorithm);
+        let mut out = Vec::new();
+        write_hunks(&mut out, &lines1, &lines2, &changes, context).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn numbered(count: usize, replace: &[(usize, &str)]) -> String {
+        (1..count + 1).map(|n| match replace.iter().find(|r| r.0 == n) {
+            Some(r) => format!("{} {}\n", r.1, n),
+            None => format!("line {}\n", n),
+        }).collect()
+    }
+
+    #[test]
+    fn lines_keep_their_newlines() {
+        assert!(split_lines(b"").is_empty());
+        assert_eq!(split_lines(b"a\n\nb\n"), [&b"a\n"[..], b"\n", b"b\n"]);
+        assert_eq!(split_lines(b"a\nb"), [&b"a\n"[..], b"b"]);
+    }
+
+    #[test]
+    fn changes() {
+        let old = numbered(10, &[]);
+        let new = numbered(10, &[(5, "changed")]);
+        let (lines1, lines2) = (split_lines(old.as_bytes()), split_lines(new.as_bytes()));
+        assert_eq!(diff(&lines1, &lines2, Algorithm::Myers),
+                   [Change { i1: 4, i2: 4, chg1: 1, chg2: 1 }]);
+        assert!(diff(&lines1, &lines1, Algorithm::Myers).is_empty());
+
+        let (lines1, lines2) = (split_lines(b"a\nb\nc\n"), split_lines(b"b\nc\nd\n"));
+        assert_eq!(diff(&lines1, &lines2, Algorithm::Myers),
+                   [Change { i1: 0, i2: 0, chg1: 1, chg2: 0 },
+                    Change { i1: 3, i2: 2, chg1: 0, chg2: 1 }]);
+    }
+
+    #[test]
+    fn one_change() {
+        assert_eq!(hunks(&numbered(10, &[]), &numbered(10, &[(5, "changed")]), Algorithm::Myers, 3), "\
+@@ -2,7 +2,7 @@ line 1
+ line 2
+ line 3
+ line 4
+-line 5
++changed 5
+ line 6
+ line 7
+ line 8
+");
+    }
+
+    #[test]
+    fn separate_and_merged_hunks() {
+        let old = numbered(20, &[]);
+        assert_eq!(hunks(&old, &numbered(20, &[(3, "new"), (17, "new")]), Algorithm::Myers, 3), "\
+@@ -1,6 +1,6 @@
+ line 1
+ line 2
+-line 3
++new 3
+ line 4
+ line 5
+ line 6
+@@ -14,7 +14,7 @@ line 13
+ line 14
+ line 15
+ line 16
+-line 17
++new 17
+ line 18
+ line 19
+ line 20
+");
+        assert_eq!(hunks(&old, &numbered(20, &[(5, "new"), (10, "new")]), Algorithm::Myers, 3), "\
+@@ -2,12 +2,12 @@ line 1
+ line 2
+ line 3
+ line 4
+-line 5
++new 5
+ line 6
+ line 7
+ line 8
+ line 9
+-line 10
++new 10
+ line 11
+ line 12
+ line 13
+");
+    }
+
+    #[test]
+    fn no_context() {
+        assert_eq!(hunks("a\nb\nc\nd\ne\n", "new\na\nb\nc\nd\n", Algorithm::Myers, 0), "\
+@@ -0,0 +1 @@
++new
+@@ -5 +5,0 @@ d
+-e
+");
+    }
+
+    #[test]
+    fn ends_of_files() {
+        assert_eq!(hunks("a\nb\nc", "a\nb\nc\nd", Algorithm::Myers, 3), "\
+@@ -1,3 +1,4 @@
+ a
+ b
+-c
+\\ No newline at end of file
++c
++d
+\\ No newline at end of file
+");
+        assert_eq!(hunks("", "x\ny\nz\n", Algorithm::Myers, 3), "\
+@@ -0,0 +1,3 @@
++x
++y
++z
+");
+    }
+
+    #[test]
+    fn indent_heuristic() {
+        // Without the heuristic git adds the new block after the old one
+        let old = "if b {\n    x\n}\nif a {\n    x\n}\n";
+        let new = "if b {\n    x\n}\n\nif b {\n    x\n}\nif a {\n    x\n}\n";
+        assert_eq!(hunks(old, new, Algorithm::Myers, 3), "\
+@@ -1,3 +1,7 @@
++if b {
++    x
++}
++
+ if b {
+     x
+ }
+");
+    }
+
+    #[test]
+    fn algorithms() {
+        let (old, new) = ("d\nb\na\n}\nc\n", "c\nd\na\nd\nb\n");
+        let myers = "\
+@@ -1,5 +1,5 @@
++c
+ d
+-b
+ a
+-}
+-c
++d
++b
+";
+        assert_eq!(hunks(old, new, Algorithm::Myers, 1), myers);
+        assert_eq!(hunks(old, new, Algorithm::Minimal, 1), myers);
+        assert_eq!(hunks(old, new, Algorithm::Patience, 1), "\
+@@ -1,5 +1,5 @@
+-d
+-b
+-a
+-}
+ c
++d
++a
++d
++b
+");
+        assert_eq!(hunks(old, new, Algorithm::Histogram, 1), "\
+@@ -1,5 +1,5 @@
++c
++d
++a
+ d
+ b
+-a
+-}
+-c
+");
+    }
+
+    #[test]
+    fn algorithms_agree_on_simple_changes() {
+        let (old, new) = (numbered(20, &[]), numbered(20, &[(3, "new"), (17, "new")]));
+        let myers = hunks(&old, &new, Algorithm::Myers, 3);
+        for &algorithm in [Algorithm::Minimal, Algorithm::Patience, Algorithm::Histogram].iter() {
+            assert_eq!(hunks(&old, &new, algorithm, 3), myers);
+            assert!(hunks(&old, &old, algorithm, 3).is_empty());
+        }
+    }
+
+    #[test]
+    fn algorithm_names() {
+        assert_eq!(Algorithm::from_name("default"), Some(Algorithm::Myers));
+        assert_eq!(Algorithm::from_name("Histogram"), Some(Algorithm::Histogram));
+        assert_eq!(Algorithm::from_name("fast"), None);
+    }
+}